@@ -1,7 +1,5 @@
 extern crate enet;
 
-use std::net::Ipv4Addr;
-
 use anyhow::Context;
 use enet::*;
 
@@ -18,7 +16,7 @@ fn main() -> anyhow::Result<()> {
         )
         .context("could not create host")?;
 
-    host.connect(&Address::new(Ipv4Addr::LOCALHOST, 9001), 10, 0)
+    host.connect("127.0.0.1:9001", 10, 0)
         .context("connect failed")?;
 
     let mut peer = loop {
@@ -32,16 +30,21 @@ fn main() -> anyhow::Result<()> {
         println!("[client] event: {:#?}", e);
 
         match e {
-            Event::Connect(ref p) => {
+            Event::Connect(ref p) | Event::Authenticated(ref p) | Event::Resumed(ref p) => {
                 break p.clone();
             }
             Event::Disconnect(ref p, r) => {
-                println!("connection NOT successful, peer: {:?}, reason: {}", p, r);
+                println!("connection NOT successful, peer: {:?}, reason: {:?}", p, r);
                 std::process::exit(0);
             }
             Event::Receive { .. } => {
                 anyhow::bail!("unexpected Receive-event while waiting for connection")
             }
+            Event::SessionExpired { .. }
+            | Event::Transfer { .. }
+            | Event::Resolved { .. }
+            | Event::ResolveFailed { .. }
+            | Event::ConnectFailed { .. } => continue,
         };
     };
 
@@ -1,14 +1,21 @@
 use std::{
-    ffi::CString,
-    net::{Ipv4Addr, SocketAddrV4},
+    ffi::{CStr, CString},
+    fmt, io,
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4, ToSocketAddrs},
+    option,
+    os::raw::c_char,
+    str::FromStr,
 };
 
-use enet_sys::ENetAddress;
+use enet_sys::{enet_address_get_host, enet_address_get_host_ip, ENetAddress};
 
 use crate::Error;
 
 /// An IPv4 address that can be used with the ENet API.
-#[derive(Clone, Debug, PartialEq, Eq)]
+///
+/// Addresses can be parsed from strings such as `"127.0.0.1:9000"`, without
+/// resolving hostnames. Use `Address::from_hostname` to resolve a hostname.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
 pub struct Address {
     addr: SocketAddrV4,
 }
@@ -37,6 +44,72 @@ impl Address {
         Ok(Self::from_enet_address(&addr))
     }
 
+    /// Resolves `hostname` to all of its IPv4 addresses, with the given port.
+    ///
+    /// Unlike `Address::from_hostname`, which only returns the first
+    /// candidate, this allows trying each address in turn. Fails if the
+    /// hostname has no IPv4 address.
+    pub fn resolve_all(hostname: &str, port: u16) -> io::Result<Vec<Address>> {
+        let mut addrs = Vec::new();
+
+        for addr in (hostname, port).to_socket_addrs()? {
+            if let Ok(addr) = Address::try_from(addr) {
+                if !addrs.contains(&addr) {
+                    addrs.push(addr);
+                }
+            }
+        }
+
+        if addrs.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no IPv4 address found for '{}'", hostname),
+            ));
+        }
+
+        Ok(addrs)
+    }
+
+    /// Looks up the hostname of the ip of this address, using reverse DNS.
+    ///
+    /// Returns the ip in numeric form if it has no hostname. This blocks until
+    /// the lookup completes.
+    pub fn reverse_lookup(&self) -> Result<String, Error> {
+        // large enough for any hostname, see `NI_MAXHOST`
+        let mut name = [0 as c_char; 1025];
+
+        let res = unsafe {
+            enet_address_get_host(&self.to_enet_address(), name.as_mut_ptr(), name.len())
+        };
+
+        if res != 0 {
+            return Err(Error(res));
+        }
+
+        Ok(unsafe { CStr::from_ptr(name.as_ptr()) }
+            .to_string_lossy()
+            .into_owned())
+    }
+
+    /// Formats the ip of this address in numeric form, such as `127.0.0.1`,
+    /// without the port.
+    pub fn host_ip_string(&self) -> Result<String, Error> {
+        // large enough for any IPv4 address, see `INET_ADDRSTRLEN`
+        let mut name = [0 as c_char; 16];
+
+        let res = unsafe {
+            enet_address_get_host_ip(&self.to_enet_address(), name.as_mut_ptr(), name.len())
+        };
+
+        if res != 0 {
+            return Err(Error(res));
+        }
+
+        Ok(unsafe { CStr::from_ptr(name.as_ptr()) }
+            .to_string_lossy()
+            .into_owned())
+    }
+
     /// Return the ip of this address
     pub fn ip(&self) -> &Ipv4Addr {
         self.addr.ip()
@@ -69,11 +142,128 @@ impl From<SocketAddrV4> for Address {
     }
 }
 
+impl From<(Ipv4Addr, u16)> for Address {
+    fn from((addr, port): (Ipv4Addr, u16)) -> Address {
+        Address::new(addr, port)
+    }
+}
+
+impl TryFrom<SocketAddr> for Address {
+    type Error = AddressParseError;
+
+    /// Converts an IPv4 socket address, IPv6 addresses are not supported by
+    /// ENet.
+    fn try_from(addr: SocketAddr) -> Result<Address, AddressParseError> {
+        match addr {
+            SocketAddr::V4(addr) => Ok(Address::from(addr)),
+            SocketAddr::V6(_) => Err(AddressParseError(addr.to_string())),
+        }
+    }
+}
+
+impl From<Address> for SocketAddrV4 {
+    fn from(addr: Address) -> SocketAddrV4 {
+        addr.addr
+    }
+}
+
+impl From<Address> for SocketAddr {
+    fn from(addr: Address) -> SocketAddr {
+        SocketAddr::V4(addr.addr)
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.addr.fmt(f)
+    }
+}
+
+/// An error that occurs when parsing an invalid `Address`, or converting an
+/// IPv6 address.
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+#[error("invalid IPv4 address '{}'", .0)]
+pub struct AddressParseError(String);
+
+impl FromStr for Address {
+    type Err = AddressParseError;
+
+    /// Parses `a.b.c.d:port`.
+    fn from_str(s: &str) -> Result<Address, AddressParseError> {
+        match s.parse::<SocketAddrV4>() {
+            Ok(addr) => Ok(Address::from(addr)),
+            Err(_) => Err(AddressParseError(s.to_string())),
+        }
+    }
+}
+
+impl ToSocketAddrs for Address {
+    type Iter = option::IntoIter<SocketAddr>;
+
+    fn to_socket_addrs(&self) -> io::Result<Self::Iter> {
+        Ok(Some(SocketAddr::from(self.clone())).into_iter())
+    }
+}
+
+/// A value that can be converted into an `Address`, such as an `Address`
+/// itself, a `SocketAddr` or a string like `"127.0.0.1:9000"`.
+///
+/// Unlike `ToSocketAddrs`, strings are never resolved as hostnames.
+pub trait ToAddress {
+    /// Converts this value into an `Address`.
+    fn to_address(&self) -> Result<Address, AddressParseError>;
+}
+
+impl ToAddress for Address {
+    fn to_address(&self) -> Result<Address, AddressParseError> {
+        Ok(self.clone())
+    }
+}
+
+impl ToAddress for SocketAddrV4 {
+    fn to_address(&self) -> Result<Address, AddressParseError> {
+        Ok(Address::from(*self))
+    }
+}
+
+impl ToAddress for SocketAddr {
+    fn to_address(&self) -> Result<Address, AddressParseError> {
+        Address::try_from(*self)
+    }
+}
+
+impl ToAddress for (Ipv4Addr, u16) {
+    fn to_address(&self) -> Result<Address, AddressParseError> {
+        Ok(Address::from(*self))
+    }
+}
+
+impl ToAddress for str {
+    fn to_address(&self) -> Result<Address, AddressParseError> {
+        self.parse()
+    }
+}
+
+impl ToAddress for String {
+    fn to_address(&self) -> Result<Address, AddressParseError> {
+        self.parse()
+    }
+}
+
+impl<A: ToAddress + ?Sized> ToAddress for &A {
+    fn to_address(&self) -> Result<Address, AddressParseError> {
+        (**self).to_address()
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use std::{ffi::CString, net::Ipv4Addr};
+    use std::{
+        ffi::CString,
+        net::{Ipv4Addr, SocketAddr, ToSocketAddrs},
+    };
 
-    use super::Address;
+    use super::{Address, ToAddress};
 
     #[test]
     fn test_from_valid_hostname() {
@@ -86,4 +276,42 @@ mod tests {
     fn test_from_invalid_hostname() {
         assert!(Address::from_hostname(&CString::new("").unwrap(), 0).is_err());
     }
+
+    #[test]
+    fn test_lookups() {
+        let addr = Address::new(Ipv4Addr::LOCALHOST, 9000);
+        assert_eq!(addr.host_ip_string().unwrap(), "127.0.0.1");
+        assert!(!addr.reverse_lookup().unwrap().is_empty());
+
+        let addrs = Address::resolve_all("localhost", 9000).unwrap();
+        assert!(addrs.contains(&addr));
+        assert!(Address::resolve_all("", 9000).is_err());
+    }
+
+    #[test]
+    fn test_parse_and_display() {
+        let addr: Address = "127.0.0.1:9000".parse().unwrap();
+        assert_eq!(addr, Address::new(Ipv4Addr::LOCALHOST, 9000));
+        assert_eq!(addr.to_string(), "127.0.0.1:9000");
+
+        assert!("127.0.0.1".parse::<Address>().is_err());
+        assert!("localhost:9000".parse::<Address>().is_err());
+        assert!("[::1]:9000".parse::<Address>().is_err());
+    }
+
+    #[test]
+    fn test_conversions() {
+        let addr = Address::new(Ipv4Addr::LOCALHOST, 9000);
+        let socket_addr = SocketAddr::from(addr.clone());
+
+        assert_eq!(Address::try_from(socket_addr).unwrap(), addr);
+        assert!(Address::try_from("[::1]:9000".parse::<SocketAddr>().unwrap()).is_err());
+
+        assert_eq!("127.0.0.1:9000".to_address().unwrap(), addr);
+        assert_eq!((Ipv4Addr::LOCALHOST, 9000).to_address().unwrap(), addr);
+        assert_eq!(socket_addr.to_address().unwrap(), addr);
+
+        let socket_addrs: Vec<_> = addr.to_socket_addrs().unwrap().collect();
+        assert_eq!(socket_addrs, [socket_addr]);
+    }
 }
@@ -0,0 +1,595 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    fmt,
+    net::Ipv4Addr,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+use crate::{early::EarlyPackets, Address, Event, Packet, PeerId, RejectReason};
+
+/// A connection attempt of a foreign host, handed to an `AdmissionPolicy`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConnectRequest {
+    /// The id of the `Peer` the connection was assigned to.
+    pub peer_id: PeerId,
+    /// The address the connection attempt came from.
+    pub address: Address,
+    /// The user data the foreign host passed to `Host::connect`.
+    pub data: u32,
+}
+
+/// The decision of an `AdmissionPolicy` about a `ConnectRequest`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Admission {
+    /// Accept the connection, it is handed to the application.
+    Accept,
+    /// Disconnect the peer immediately, with the contained disconnect data.
+    Reject(u32),
+    /// Decide later, using `Host::admit` or `Host::reject`, within the time
+    /// set using `Host::set_defer_timeout`.
+    ///
+    /// Packets received from the peer in the meantime are buffered, and
+    /// handed on after `Event::Connect` once it is admitted. A peer that
+    /// sends too much while deferred is disconnected with
+    /// `RejectReason::RateLimited`.
+    Defer,
+}
+
+/// Decides whether incoming connections are accepted, once set on a `Host`
+/// using `Host::set_admission_policy`.
+///
+/// The policy is consulted for every incoming connection before
+/// `Event::Connect` is emitted for it. Rejected and deferred connections do
+/// not create any events. Connections initiated using `Host::connect` are not
+/// subject to the policy.
+///
+/// This is implemented for closures taking a `&ConnectRequest`.
+pub trait AdmissionPolicy {
+    /// Decides about a connection attempt.
+    fn admit(&mut self, request: &ConnectRequest) -> Admission;
+}
+
+impl<F> AdmissionPolicy for F
+where
+    F: FnMut(&ConnectRequest) -> Admission,
+{
+    fn admit(&mut self, request: &ConnectRequest) -> Admission {
+        self(request)
+    }
+}
+
+/// An IPv4 network in CIDR notation, e.g. `10.0.0.0/8`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Cidr {
+    network: Ipv4Addr,
+    prefix_len: u8,
+}
+
+impl Cidr {
+    /// Creates a new network from an address and a prefix length. Host bits
+    /// of `address` are ignored.
+    ///
+    /// Panics if `prefix_len` is larger than 32.
+    pub fn new(address: Ipv4Addr, prefix_len: u8) -> Cidr {
+        assert!(prefix_len <= 32, "invalid prefix length: {}", prefix_len);
+
+        Cidr {
+            network: Ipv4Addr::from(u32::from(address) & Cidr::mask(prefix_len)),
+            prefix_len,
+        }
+    }
+
+    fn mask(prefix_len: u8) -> u32 {
+        u32::MAX
+            .checked_shl(32 - u32::from(prefix_len))
+            .unwrap_or(0)
+    }
+
+    /// Returns whether `address` is part of this network.
+    pub fn contains(&self, address: &Ipv4Addr) -> bool {
+        u32::from(*address) & Cidr::mask(self.prefix_len) == u32::from(self.network)
+    }
+}
+
+impl From<Ipv4Addr> for Cidr {
+    fn from(address: Ipv4Addr) -> Cidr {
+        Cidr::new(address, 32)
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.network, self.prefix_len)
+    }
+}
+
+/// An error that occurs when parsing an invalid `Cidr`.
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+#[error("invalid CIDR network '{}'", .0)]
+pub struct CidrParseError(String);
+
+impl FromStr for Cidr {
+    type Err = CidrParseError;
+
+    /// Parses `a.b.c.d/n`, or a single address `a.b.c.d`.
+    fn from_str(s: &str) -> Result<Cidr, CidrParseError> {
+        let error = || CidrParseError(s.to_string());
+
+        let (address, prefix_len) = match s.split_once('/') {
+            Some((address, prefix_len)) => (address, prefix_len.parse().map_err(|_| error())?),
+            None => (s, 32),
+        };
+
+        if prefix_len > 32 {
+            return Err(error());
+        }
+
+        Ok(Cidr::new(address.parse().map_err(|_| error())?, prefix_len))
+    }
+}
+
+/// An `AdmissionPolicy` based on allow and deny lists of networks.
+///
+/// Connections from denied networks are always rejected. If any networks are
+/// allowed, connections from all other networks are rejected as well.
+/// Rejected peers are disconnected with `RejectReason::Banned`.
+///
+/// To limit the number of concurrent connections per IP, see
+/// `Host::set_duplicate_peers`.
+#[derive(Debug, Clone, Default)]
+pub struct IpFilter {
+    allow: Vec<Cidr>,
+    deny: Vec<Cidr>,
+}
+
+impl IpFilter {
+    /// Creates a new `IpFilter` that accepts all connections.
+    pub fn new() -> IpFilter {
+        IpFilter::default()
+    }
+
+    /// Only allows connections from the given networks, and other allowed
+    /// ones.
+    pub fn allow(mut self, network: impl Into<Cidr>) -> IpFilter {
+        self.allow.push(network.into());
+        self
+    }
+
+    /// Rejects connections from the given network.
+    pub fn deny(mut self, network: impl Into<Cidr>) -> IpFilter {
+        self.deny.push(network.into());
+        self
+    }
+
+    /// Adds a network to the deny list of an `IpFilter` that is in use.
+    pub fn ban(&mut self, network: impl Into<Cidr>) {
+        self.deny.push(network.into());
+    }
+
+    /// Removes a network from the deny list.
+    pub fn unban(&mut self, network: impl Into<Cidr>) {
+        let network = network.into();
+        self.deny.retain(|denied| *denied != network);
+    }
+
+    /// Returns whether connections from `address` are allowed.
+    pub fn is_allowed(&self, address: &Ipv4Addr) -> bool {
+        if self.deny.iter().any(|network| network.contains(address)) {
+            return false;
+        }
+
+        self.allow.is_empty() || self.allow.iter().any(|network| network.contains(address))
+    }
+}
+
+impl AdmissionPolicy for IpFilter {
+    fn admit(&mut self, request: &ConnectRequest) -> Admission {
+        if self.is_allowed(request.address.ip()) {
+            Admission::Accept
+        } else {
+            Admission::Reject(RejectReason::Banned.code())
+        }
+    }
+}
+
+/// A peer whose connection was deferred by the policy.
+struct Deferred {
+    since: Instant,
+    early: EarlyPackets,
+}
+
+/// Runs an `AdmissionPolicy` on the events of a `Host`.
+pub(crate) struct AdmissionControl {
+    policy: Box<dyn AdmissionPolicy>,
+    timeout: Duration,
+    outgoing: HashSet<PeerId>,
+    deferred: HashMap<PeerId, Deferred>,
+    admitted: VecDeque<(PeerId, EarlyPackets)>,
+    replayed: VecDeque<(PeerId, u8, Packet)>,
+}
+
+impl AdmissionControl {
+    pub(crate) fn new(policy: Box<dyn AdmissionPolicy>) -> AdmissionControl {
+        AdmissionControl {
+            policy,
+            timeout: Duration::from_secs(10),
+            outgoing: HashSet::new(),
+            deferred: HashMap::new(),
+            admitted: VecDeque::new(),
+            replayed: VecDeque::new(),
+        }
+    }
+
+    /// Sets how long a peer may stay deferred.
+    pub(crate) fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    /// Returns the ids of all deferred peers that were neither admitted nor
+    /// rejected in time, and forgets them. The caller has to disconnect them.
+    pub(crate) fn expire(&mut self, now: Instant) -> Vec<PeerId> {
+        let timeout = self.timeout;
+        let mut expired = Vec::new();
+
+        self.deferred.retain(|id, deferred| {
+            let keep = now.duration_since(deferred.since) < timeout;
+            if !keep {
+                expired.push(*id);
+            }
+            keep
+        });
+
+        expired
+    }
+
+    /// Marks a peer as connected by us, so it is not subject to the policy.
+    pub(crate) fn add_outgoing(&mut self, id: PeerId) {
+        self.outgoing.insert(id);
+    }
+
+    /// Accepts a deferred peer. Returns `false` if the peer was not deferred.
+    pub(crate) fn admit(&mut self, id: PeerId) -> bool {
+        match self.deferred.remove(&id) {
+            Some(deferred) => {
+                self.admitted.push_back((id, deferred.early));
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Rejects a deferred peer. Returns `false` if the peer was not deferred,
+    /// otherwise the caller has to disconnect it.
+    pub(crate) fn reject(&mut self, id: PeerId) -> bool {
+        self.deferred.remove(&id).is_some()
+    }
+
+    /// Returns the next peer that was admitted after being deferred. The
+    /// packets it sent while deferred are returned by `pop_replayed`
+    /// afterwards.
+    pub(crate) fn pop_admitted(&mut self) -> Option<PeerId> {
+        let (id, early) = self.admitted.pop_front()?;
+
+        self.replayed
+            .extend(early.into_packets().filter_map(|packet| {
+                let replayed = Packet::new(&packet.data, packet.mode).ok()?;
+                Some((id, packet.channel_id, replayed))
+            }));
+
+        Some(id)
+    }
+
+    /// Returns the next packet that was received from an admitted peer while
+    /// it was deferred.
+    pub(crate) fn pop_replayed(&mut self) -> Option<(PeerId, u8, Packet)> {
+        self.replayed.pop_front()
+    }
+
+    /// Runs the policy for an event, returning the event that should be
+    /// handed on instead, if any. `data` is the data of the raw ENet event.
+    pub(crate) fn process_event<'a, T>(
+        &mut self,
+        event: Event<'a, T>,
+        data: u32,
+    ) -> Option<Event<'a, T>> {
+        match &event {
+            Event::Connect(peer) => {
+                let id = peer.id();
+
+                if self.outgoing.remove(&id) {
+                    return Some(event);
+                }
+
+                let request = ConnectRequest {
+                    peer_id: id,
+                    address: peer.address(),
+                    data,
+                };
+
+                match self.policy.admit(&request) {
+                    Admission::Accept => Some(event),
+                    Admission::Reject(code) => {
+                        // no event is emitted, so the slot has to be cleaned
+                        // up right away
+                        let mut peer = peer.clone();
+                        peer.free_data();
+                        peer.disconnect_now(code);
+                        None
+                    }
+                    Admission::Defer => {
+                        let deferred = Deferred {
+                            since: Instant::now(),
+                            early: EarlyPackets::default(),
+                        };
+
+                        self.deferred.insert(id, deferred);
+                        None
+                    }
+                }
+            }
+            Event::Disconnect(peer, _) => {
+                let id = peer.id();
+
+                self.outgoing.remove(&id);
+                self.admitted.retain(|(admitted, _)| *admitted != id);
+                self.replayed.retain(|(replayed, ..)| *replayed != id);
+
+                match self.deferred.remove(&id) {
+                    Some(_) => None,
+                    None => Some(event),
+                }
+            }
+            Event::Receive {
+                sender,
+                channel_id,
+                packet,
+            } => {
+                let id = sender.id();
+
+                let deferred = match self.deferred.get_mut(&id) {
+                    Some(deferred) => deferred,
+                    None => return Some(event),
+                };
+
+                if deferred.early.push(*channel_id, packet).is_err() {
+                    // no event is emitted, as for rejected peers
+                    self.deferred.remove(&id);
+
+                    let mut peer = sender.clone();
+                    peer.free_data();
+                    peer.disconnect_now(RejectReason::RateLimited.code());
+                }
+
+                None
+            }
+            Event::Authenticated(_)
+            | Event::Resumed(_)
+            | Event::SessionExpired { .. }
+            | Event::Transfer { .. }
+            | Event::Resolved { .. }
+            | Event::ResolveFailed { .. }
+            | Event::ConnectFailed { .. } => Some(event),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, net::Ipv4Addr, rc::Rc, time::Duration};
+
+    use super::{Admission, Cidr, ConnectRequest, IpFilter};
+    use crate::{
+        tests::{create_host, create_server},
+        DisconnectReason, Event, Handshake, Hello, Host, Packet, PacketMode, RejectReason,
+    };
+
+    #[test]
+    fn test_cidr() {
+        let network: Cidr = "10.1.2.3/16".parse().unwrap();
+
+        assert_eq!(network.to_string(), "10.1.0.0/16");
+        assert!(network.contains(&Ipv4Addr::new(10, 1, 200, 1)));
+        assert!(!network.contains(&Ipv4Addr::new(10, 2, 0, 1)));
+
+        assert!("0.0.0.0/0"
+            .parse::<Cidr>()
+            .unwrap()
+            .contains(&Ipv4Addr::new(1, 2, 3, 4)));
+        assert_eq!(
+            "1.2.3.4".parse::<Cidr>().unwrap(),
+            Cidr::from(Ipv4Addr::new(1, 2, 3, 4))
+        );
+        assert!("1.2.3.4/33".parse::<Cidr>().is_err());
+        assert!("1.2.3/8".parse::<Cidr>().is_err());
+    }
+
+    #[test]
+    fn test_ip_filter() {
+        let filter = IpFilter::new()
+            .allow("10.0.0.0/8".parse::<Cidr>().unwrap())
+            .deny(Ipv4Addr::new(10, 0, 0, 66));
+
+        assert!(filter.is_allowed(&Ipv4Addr::new(10, 0, 0, 1)));
+        assert!(!filter.is_allowed(&Ipv4Addr::new(10, 0, 0, 66)));
+        assert!(!filter.is_allowed(&Ipv4Addr::new(192, 168, 0, 1)));
+
+        let mut filter = IpFilter::new();
+        assert!(filter.is_allowed(&Ipv4Addr::LOCALHOST));
+        filter.ban(Ipv4Addr::LOCALHOST);
+        assert!(!filter.is_allowed(&Ipv4Addr::LOCALHOST));
+        filter.unban(Ipv4Addr::LOCALHOST);
+        assert!(filter.is_allowed(&Ipv4Addr::LOCALHOST));
+    }
+
+    #[test]
+    fn test_banned_and_deferred() {
+        let mut server: Host<()> = create_server(2);
+        let server_addr = server.address();
+        let mut client: Host<()> = create_host(None, 2);
+
+        let requests = Rc::new(RefCell::new(Vec::new()));
+        let policy_requests = requests.clone();
+
+        server.set_admission_policy(move |request: &super::ConnectRequest| {
+            policy_requests.borrow_mut().push(request.clone());
+
+            match request.data {
+                1 => Admission::Reject(RejectReason::Banned.code()),
+                _ => Admission::Defer,
+            }
+        });
+
+        client.connect(&server_addr, 1, 1).unwrap();
+        client.connect(&server_addr, 1, 2).unwrap();
+
+        let mut rejected = false;
+        let mut admit_called = false;
+        let mut admitted = false;
+
+        for _ in 0..200 {
+            if let Some(Event::Disconnect(_, reason)) = client.service(5).unwrap() {
+                assert_eq!(reason, DisconnectReason::Rejected(RejectReason::Banned));
+                rejected = true;
+            }
+
+            let deferred = requests
+                .borrow()
+                .iter()
+                .find(|request| request.data == 2)
+                .map(|request| request.peer_id);
+
+            if let Some(id) = deferred {
+                if !admit_called {
+                    assert!(server.admit(id));
+                    admit_called = true;
+                }
+            }
+
+            match server.service(5).unwrap() {
+                Some(Event::Connect(ref peer)) => {
+                    assert_eq!(Some(peer.id()), deferred);
+                    admitted = true;
+                }
+                Some(Event::Disconnect(..)) => panic!("rejected peer was reported"),
+                _ => (),
+            }
+
+            if rejected && admitted {
+                break;
+            }
+        }
+
+        assert!(rejected && admitted);
+        assert_eq!(requests.borrow().len(), 2);
+    }
+
+    #[test]
+    fn test_deferred_handshake() {
+        let mut server: Host<()> = create_server(2);
+        let server_addr = server.address();
+        let deferred = Rc::new(RefCell::new(None));
+        let policy_deferred = deferred.clone();
+
+        server.set_admission_policy(move |request: &ConnectRequest| {
+            *policy_deferred.borrow_mut() = Some(request.peer_id);
+            Admission::Defer
+        });
+        server.set_handshake(Handshake::server(1, |_, _| Ok(())));
+
+        let mut client: Host<()> = create_host(None, 2);
+        client.set_handshake(Handshake::client(Hello::new(1, "token")));
+        let id = client.connect(&server_addr, 1, 0).unwrap().id();
+
+        let (mut server_authenticated, mut client_authenticated) = (false, false);
+
+        for i in 0..200 {
+            if let Some(Event::Authenticated(_)) = client.service(5).unwrap() {
+                client_authenticated = true;
+            }
+
+            if let Some(Event::Authenticated(_)) = server.service(5).unwrap() {
+                server_authenticated = true;
+            }
+
+            // the hello arrives while the peer is deferred, and is replayed
+            if i == 20 {
+                assert!(server.admit(deferred.borrow().unwrap()));
+            }
+
+            if server_authenticated && client_authenticated {
+                break;
+            }
+        }
+
+        assert!(
+            server_authenticated && client_authenticated,
+            "handshake failed"
+        );
+        assert!(client.peer(id).is_some());
+    }
+
+    #[test]
+    fn test_defer_timeout() {
+        let mut server: Host<()> = create_server(2);
+        let server_addr = server.address();
+        server.set_admission_policy(|_: &ConnectRequest| Admission::Defer);
+        server.set_defer_timeout(Duration::from_millis(50));
+
+        let mut client: Host<()> = create_host(None, 2);
+        client.connect(&server_addr, 1, 0).unwrap();
+
+        let mut reason = None;
+
+        for _ in 0..100 {
+            if let Some(Event::Disconnect(_, r)) = client.service(5).unwrap() {
+                reason = Some(r);
+                break;
+            }
+
+            assert!(server.service(5).unwrap().is_none());
+        }
+
+        assert_eq!(
+            reason,
+            Some(DisconnectReason::Rejected(RejectReason::Timeout))
+        );
+    }
+
+    #[test]
+    fn test_defer_overflow() {
+        let mut server: Host<()> = create_server(2);
+        let server_addr = server.address();
+        server.set_admission_policy(|_: &ConnectRequest| Admission::Defer);
+
+        let mut client: Host<()> = create_host(None, 2);
+        client.connect(&server_addr, 1, 0).unwrap();
+
+        let mut reason = None;
+
+        for _ in 0..400 {
+            match client.service(1).unwrap() {
+                Some(Event::Connect(ref peer)) => {
+                    let mut peer = peer.clone();
+
+                    for _ in 0..100 {
+                        let packet = Packet::new(&[0; 1000], PacketMode::ReliableSequenced);
+                        peer.send_packet(packet.unwrap(), 0).unwrap();
+                    }
+                }
+                Some(Event::Disconnect(_, r)) => {
+                    reason = Some(r);
+                    break;
+                }
+                _ => (),
+            }
+
+            assert!(server.service(1).unwrap().is_none());
+        }
+
+        assert_eq!(
+            reason,
+            Some(DisconnectReason::Rejected(RejectReason::RateLimited))
+        );
+    }
+}
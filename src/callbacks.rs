@@ -0,0 +1,140 @@
+use std::{
+    alloc::{self, Layout},
+    os::raw::{c_int, c_void},
+    sync::OnceLock,
+};
+
+use enet_sys::{enet_initialize_with_callbacks, enet_linked_version, ENetCallbacks};
+
+/// The space reserved in front of each allocation made through the global
+/// allocator, which stores the size of the allocation. Also the alignment of
+/// all allocations, which is enough for any type ENet allocates.
+const HEADER: usize = 16;
+
+/// The handler called by ENet when an allocation fails.
+static NO_MEMORY: OnceLock<Box<dyn Fn() + Send + Sync>> = OnceLock::new();
+
+/// A function that allocates `size` bytes for ENet, returning null on
+/// failure.
+pub type MallocFn = unsafe extern "C" fn(size: usize) -> *mut c_void;
+
+/// A function that frees memory allocated by the matching `MallocFn`. Called
+/// with null pointers as well, which have to be ignored.
+pub type FreeFn = unsafe extern "C" fn(memory: *mut c_void);
+
+/// Callbacks that replace how ENet allocates memory and handles running out
+/// of it, used with `Enet::with_callbacks`.
+///
+/// By default, ENet uses the system `malloc` and `free`, and aborts the
+/// process once an allocation fails.
+#[derive(Default)]
+pub struct EnetCallbacks {
+    allocator: Option<(MallocFn, FreeFn)>,
+    no_memory: Option<Box<dyn Fn() + Send + Sync>>,
+}
+
+impl EnetCallbacks {
+    /// Creates callbacks that keep ENet's defaults.
+    pub fn new() -> EnetCallbacks {
+        EnetCallbacks::default()
+    }
+
+    /// Routes all allocations of ENet through Rust's global allocator, so
+    /// they are accounted for by a custom `#[global_allocator]`.
+    pub fn with_global_allocator(self) -> EnetCallbacks {
+        self.with_allocator(global_malloc, global_free)
+    }
+
+    /// Routes all allocations of ENet through `malloc` and `free`.
+    pub fn with_allocator(mut self, malloc: MallocFn, free: FreeFn) -> EnetCallbacks {
+        self.allocator = Some((malloc, free));
+        self
+    }
+
+    /// Calls `handler` instead of aborting once an allocation fails. ENet
+    /// then treats the allocation as failed, so for example creating a host
+    /// or a packet returns an error.
+    ///
+    /// The handler must not panic, as it is called from C code.
+    pub fn with_no_memory(mut self, handler: impl Fn() + Send + Sync + 'static) -> EnetCallbacks {
+        self.no_memory = Some(Box::new(handler));
+        self
+    }
+
+    /// Initializes ENet with these callbacks, returning the result of
+    /// `enet_initialize_with_callbacks`. Must only be called once.
+    pub(crate) fn initialize(self) -> c_int {
+        let no_memory = self.no_memory.map(|handler| {
+            // ENet is initialized at most once, so this is the only handler
+            let _ = NO_MEMORY.set(handler);
+            no_memory as unsafe extern "C" fn()
+        });
+
+        let callbacks = ENetCallbacks {
+            malloc: self.allocator.map(|(malloc, _)| malloc),
+            free: self.allocator.map(|(_, free)| free),
+            no_memory,
+        };
+
+        unsafe { enet_initialize_with_callbacks(enet_linked_version(), &callbacks) }
+    }
+}
+
+unsafe extern "C" fn no_memory() {
+    if let Some(handler) = NO_MEMORY.get() {
+        handler();
+    }
+}
+
+fn layout(size: usize) -> Option<Layout> {
+    Layout::from_size_align(size.checked_add(HEADER)?, HEADER).ok()
+}
+
+unsafe extern "C" fn global_malloc(size: usize) -> *mut c_void {
+    let Some(layout) = layout(size) else {
+        return std::ptr::null_mut();
+    };
+
+    let base = alloc::alloc(layout);
+    if base.is_null() {
+        return std::ptr::null_mut();
+    }
+
+    // `free` is not passed the size, so it is stored in front of the memory
+    base.cast::<usize>().write(size);
+    base.add(HEADER).cast()
+}
+
+unsafe extern "C" fn global_free(memory: *mut c_void) {
+    if memory.is_null() {
+        return;
+    }
+
+    let base = memory.cast::<u8>().sub(HEADER);
+    let size = base.cast::<usize>().read();
+
+    // the layout was valid when the memory was allocated
+    alloc::dealloc(base, layout(size).unwrap());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{global_free, global_malloc, HEADER};
+
+    #[test]
+    fn test_global_allocator() {
+        unsafe {
+            for size in [0, 1, 100, 4096] {
+                let memory = global_malloc(size).cast::<u8>();
+                assert!(!memory.is_null());
+                assert_eq!(memory as usize % HEADER, 0);
+
+                std::ptr::write_bytes(memory, 0xab, size);
+                global_free(memory.cast());
+            }
+
+            global_free(std::ptr::null_mut());
+            assert!(global_malloc(usize::MAX).is_null());
+        }
+    }
+}
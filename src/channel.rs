@@ -0,0 +1,118 @@
+use crate::PacketMode;
+
+/// Describes the channels used by an application, and how packets are
+/// delivered on each of them.
+///
+/// Instead of passing bare channel ids and a `PacketMode` on every send, a
+/// layout binds each channel to a default `PacketMode`. This is usually
+/// implemented for an enum using the [`channel_layout!`](crate::channel_layout)
+/// macro.
+///
+/// Channel ids are the index of the channel in `CHANNELS`, so manual
+/// implementations have to make sure `channel_id` agrees with that order.
+pub trait ChannelLayout: Copy + 'static {
+    /// All channels of this layout, ordered by their channel id.
+    const CHANNELS: &'static [Self];
+
+    /// Returns the ENet channel id of this channel.
+    fn channel_id(self) -> u8;
+
+    /// Returns the mode packets are sent with on this channel.
+    fn mode(self) -> PacketMode;
+
+    /// Returns the number of channels in this layout, to be used as the
+    /// `channel_count` of a connection.
+    fn channel_count() -> usize {
+        Self::CHANNELS.len()
+    }
+
+    /// Returns the channel with the given channel id, if it is part of this
+    /// layout.
+    fn from_channel_id(channel_id: u8) -> Option<Self> {
+        Self::CHANNELS.get(usize::from(channel_id)).copied()
+    }
+}
+
+/// Declares an enum of channels and implements `ChannelLayout` for it.
+///
+/// Channels are numbered in order of declaration, starting at `0`. Each
+/// channel is bound to a `PacketMode` variant.
+///
+/// # Examples
+/// ```
+/// use enet::{channel_layout, ChannelLayout, PacketMode};
+///
+/// channel_layout! {
+///     pub enum Channel {
+///         Chat: ReliableSequenced,
+///         Movement: UnreliableSequenced,
+///     }
+/// }
+///
+/// assert_eq!(Channel::channel_count(), 2);
+/// assert_eq!(Channel::Movement.channel_id(), 1);
+/// assert_eq!(Channel::Chat.mode(), PacketMode::ReliableSequenced);
+/// ```
+#[macro_export]
+macro_rules! channel_layout {
+    (
+        $(#[$meta:meta])*
+        $vis:vis enum $name:ident {
+            $($(#[$variant_meta:meta])* $variant:ident : $mode:ident),+ $(,)?
+        }
+    ) => {
+        $(#[$meta])*
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+        #[repr(u8)]
+        $vis enum $name {
+            $($(#[$variant_meta])* $variant),+
+        }
+
+        impl $crate::ChannelLayout for $name {
+            const CHANNELS: &'static [Self] = &[$($name::$variant),+];
+
+            fn channel_id(self) -> u8 {
+                self as u8
+            }
+
+            fn mode(self) -> $crate::PacketMode {
+                match self {
+                    $($name::$variant => $crate::PacketMode::$mode),+
+                }
+            }
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ChannelLayout;
+    use crate::PacketMode;
+
+    channel_layout! {
+        enum Channel {
+            Chat: ReliableSequenced,
+            Movement: UnreliableSequenced,
+            /// Voice data, latest wins.
+            Voice: UnreliableUnsequenced,
+        }
+    }
+
+    #[test]
+    fn test_channel_layout() {
+        assert_eq!(Channel::channel_count(), 3);
+
+        for (i, channel) in Channel::CHANNELS.iter().enumerate() {
+            assert_eq!(usize::from(channel.channel_id()), i);
+            assert_eq!(
+                Channel::from_channel_id(channel.channel_id()),
+                Some(*channel)
+            );
+        }
+
+        assert_eq!(Channel::Chat.mode(), PacketMode::ReliableSequenced);
+        assert_eq!(Channel::Movement.mode(), PacketMode::UnreliableSequenced);
+        assert_eq!(Channel::Voice.mode(), PacketMode::UnreliableUnsequenced);
+        assert_eq!(Channel::from_channel_id(3), None);
+    }
+}
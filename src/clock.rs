@@ -0,0 +1,363 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    time::{Duration, Instant},
+};
+
+use enet_sys::ENetHost;
+
+use crate::{host::peer_at, Event, Packet, PacketMode, PeerId, PeerState, SendError};
+
+const MSG_PROBE: u8 = 1;
+const MSG_REPLY: u8 = 2;
+
+/// Samples with a round trip time above this factor of the median are
+/// discarded as outliers.
+const OUTLIER_FACTOR: f64 = 1.5;
+
+/// The estimated relation between the local clock and the clock of a server,
+/// see `ClockSync`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ClockEstimate {
+    /// The server time minus the local time, in microseconds.
+    pub offset_micros: i64,
+    /// The median round trip time of the recent probes.
+    pub rtt: Duration,
+    /// The lowest round trip time of the recent probes.
+    pub min_rtt: Duration,
+    /// How much the estimate can be trusted, between `0.0` and `1.0`. This is
+    /// the fraction of the sample window filled with samples that were not
+    /// discarded as outliers.
+    pub confidence: f64,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Sample {
+    offset_micros: i64,
+    rtt_micros: u64,
+}
+
+struct PeerClock {
+    next_probe: Instant,
+    samples: VecDeque<Sample>,
+}
+
+/// Synchronizes the clocks of clients with the clock of their server, once
+/// set on a `Host` using `Host::set_clock_sync`.
+///
+/// Clients periodically send timestamped probes to each connected peer,
+/// which answers with its own time. The round trip time of each probe bounds
+/// the error of its sample, so samples with an unusually high round trip time
+/// are discarded, and the offset is averaged over the remaining samples of a
+/// sliding window. `ClockSync::server_time_now` then estimates the current
+/// time on the server's timeline.
+///
+/// Probes are sent unreliably and unsequenced on a dedicated channel. Packets
+/// on that channel are consumed by the layer, and never handed to the
+/// application. Both sides of a connection have to use the same channel.
+pub struct ClockSync {
+    channel_id: u8,
+    probing: bool,
+    interval: Duration,
+    samples: usize,
+    epoch: Instant,
+    peers: HashMap<PeerId, PeerClock>,
+}
+
+impl ClockSync {
+    /// Creates the client side of the clock sync, which probes all connected
+    /// peers and uses `channel_id` exclusively.
+    pub fn client(channel_id: u8) -> ClockSync {
+        ClockSync::new(channel_id, true)
+    }
+
+    /// Creates the server side of the clock sync, which only answers probes
+    /// and uses `channel_id` exclusively.
+    pub fn server(channel_id: u8) -> ClockSync {
+        ClockSync::new(channel_id, false)
+    }
+
+    fn new(channel_id: u8, probing: bool) -> ClockSync {
+        ClockSync {
+            channel_id,
+            probing,
+            interval: Duration::from_secs(1),
+            samples: 16,
+            epoch: Instant::now(),
+            peers: HashMap::new(),
+        }
+    }
+
+    /// Sets how often each peer is probed, defaults to 1 second. Only used by
+    /// clients.
+    pub fn with_interval(mut self, interval: Duration) -> ClockSync {
+        self.interval = interval;
+        self
+    }
+
+    /// Sets how many of the most recent samples the estimate is based on,
+    /// defaults to 16. Only used by clients.
+    pub fn with_samples(mut self, samples: usize) -> ClockSync {
+        self.samples = samples.max(1);
+        self
+    }
+
+    /// Returns the time on the local timeline, which starts when this
+    /// `ClockSync` is created.
+    pub fn local_time(&self) -> Duration {
+        self.epoch.elapsed()
+    }
+
+    /// Returns the estimated current time on the server's timeline.
+    ///
+    /// On a server, this is its local time. On a client, this is `None` until
+    /// the first probe was answered.
+    pub fn server_time_now(&self) -> Option<Duration> {
+        if !self.probing {
+            return Some(self.local_time());
+        }
+
+        let estimate = self.estimate()?;
+        let local = self.local_time().as_micros() as i64;
+
+        Some(Duration::from_micros(
+            (local + estimate.offset_micros).max(0) as u64,
+        ))
+    }
+
+    /// Returns the estimate of the peer with the highest confidence, which
+    /// is the server for a client connected to a single server.
+    pub fn estimate(&self) -> Option<ClockEstimate> {
+        self.peers
+            .keys()
+            .filter_map(|&id| self.peer_estimate(id))
+            .max_by(|a, b| a.confidence.total_cmp(&b.confidence))
+    }
+
+    /// Returns the estimate of the clock of the peer `id`, if any of its
+    /// probes were answered.
+    pub fn peer_estimate(&self, id: PeerId) -> Option<ClockEstimate> {
+        let samples = &self.peers.get(&id)?.samples;
+
+        let mut rtts: Vec<u64> = samples.iter().map(|s| s.rtt_micros).collect();
+        rtts.sort_unstable();
+
+        let median = *rtts.get(rtts.len() / 2)?;
+        let threshold = (median as f64 * OUTLIER_FACTOR) as u64;
+
+        let accepted: Vec<&Sample> = samples
+            .iter()
+            .filter(|s| s.rtt_micros <= threshold)
+            .collect();
+        let sum: i64 = accepted.iter().map(|s| s.offset_micros).sum();
+
+        Some(ClockEstimate {
+            offset_micros: sum / accepted.len() as i64,
+            rtt: Duration::from_micros(median),
+            min_rtt: Duration::from_micros(rtts[0]),
+            confidence: accepted.len() as f64 / self.samples as f64,
+        })
+    }
+
+    /// Returns when the next probe is due, if any peer is probed.
+    pub(crate) fn next_due(&self) -> Option<Instant> {
+        self.peers.values().map(|clock| clock.next_probe).min()
+    }
+
+    /// Sends a probe to each peer whose probe is due.
+    pub(crate) fn pump<T>(&mut self, host: *mut ENetHost) {
+        let now = Instant::now();
+        let timestamp = self.micros_at(now);
+
+        for (&id, clock) in self.peers.iter_mut() {
+            if clock.next_probe > now {
+                continue;
+            }
+
+            clock.next_probe = now + self.interval;
+
+            let mut peer = peer_at::<T>(host, id);
+            if peer.state() != PeerState::Connected {
+                continue;
+            }
+
+            let mut message = vec![MSG_PROBE];
+            message.extend_from_slice(&timestamp.to_be_bytes());
+
+            // a lost probe is no different from a dropped datagram
+            let _ = Packet::new(&message, PacketMode::UnreliableUnsequenced)
+                .map_err(SendError::from)
+                .and_then(|packet| peer.try_send_packet(packet, self.channel_id));
+        }
+    }
+
+    /// Drops the clock of the peer `id`, once its connection ended.
+    pub(crate) fn remove_peer(&mut self, id: PeerId) {
+        self.peers.remove(&id);
+    }
+
+    /// Runs the clock sync for an event, returning the event that should be
+    /// handed to the application instead, if any.
+    pub(crate) fn process_event<'a, T>(&mut self, event: Event<'a, T>) -> Option<Event<'a, T>> {
+        match &event {
+            Event::Connect(peer) | Event::Authenticated(peer) | Event::Resumed(peer)
+                if self.probing =>
+            {
+                self.peers.insert(
+                    peer.id(),
+                    PeerClock {
+                        next_probe: Instant::now(),
+                        samples: VecDeque::new(),
+                    },
+                );
+
+                Some(event)
+            }
+            Event::Receive {
+                sender,
+                channel_id,
+                packet,
+            } if *channel_id == self.channel_id => {
+                let received = self.micros_at(Instant::now());
+
+                match packet.data().split_first() {
+                    Some((&MSG_PROBE, timestamp)) if timestamp.len() == 8 => {
+                        let mut message = vec![MSG_REPLY];
+                        message.extend_from_slice(timestamp);
+                        message.extend_from_slice(&received.to_be_bytes());
+
+                        // the client just misses a sample if this fails
+                        let _ = Packet::new(&message, PacketMode::UnreliableUnsequenced)
+                            .map_err(SendError::from)
+                            .and_then(|packet| sender.clone().try_send_packet(packet, *channel_id));
+                    }
+                    Some((&MSG_REPLY, rest)) if rest.len() == 16 => {
+                        let sent = u64::from_be_bytes(rest[..8].try_into().unwrap());
+                        let server = u64::from_be_bytes(rest[8..].try_into().unwrap());
+
+                        if let Some(clock) = self.peers.get_mut(&sender.id()) {
+                            if sent <= received {
+                                // assume the probe took as long as the reply
+                                let midpoint = sent + (received - sent) / 2;
+
+                                clock.samples.push_back(Sample {
+                                    offset_micros: server as i64 - midpoint as i64,
+                                    rtt_micros: received - sent,
+                                });
+
+                                if clock.samples.len() > self.samples {
+                                    clock.samples.pop_front();
+                                }
+                            }
+                        }
+                    }
+                    _ => (),
+                }
+
+                None
+            }
+            _ => Some(event),
+        }
+    }
+
+    fn micros_at(&self, instant: Instant) -> u64 {
+        instant.duration_since(self.epoch).as_micros() as u64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use super::{ClockSync, PeerClock, Sample, OUTLIER_FACTOR};
+    use crate::{
+        tests::{create_host, create_server},
+        Host, PeerId,
+    };
+
+    #[test]
+    fn test_outliers() {
+        let mut clock = ClockSync::client(1).with_samples(8);
+        let samples = [(1000, 200), (1010, 220), (990, 180), (5000, 4000)]
+            .into_iter()
+            .map(|(offset_micros, rtt_micros)| Sample {
+                offset_micros,
+                rtt_micros,
+            })
+            .collect();
+
+        clock.peers.insert(
+            PeerId(0),
+            PeerClock {
+                next_probe: Instant::now(),
+                samples,
+            },
+        );
+
+        let estimate = clock.peer_estimate(PeerId(0)).unwrap();
+        assert_eq!(estimate.offset_micros, 1000);
+        assert_eq!(estimate.min_rtt, Duration::from_micros(180));
+        assert_eq!(estimate.rtt, Duration::from_micros(220));
+        assert_eq!(estimate.confidence, 3.0 / 8.0);
+    }
+
+    #[test]
+    fn test_sync() {
+        let mut server: Host<()> = create_server(1);
+        server.set_clock_sync(ClockSync::server(1));
+        let address = server.address();
+
+        // let the server's timeline run ahead of the client's
+        thread::sleep(Duration::from_millis(50));
+
+        let mut client: Host<()> = create_host(None, 1);
+        client.set_clock_sync(
+            ClockSync::client(1)
+                .with_interval(Duration::from_millis(5))
+                .with_samples(8),
+        );
+        client.connect(&address, 2, 0).unwrap();
+
+        assert_eq!(client.clock_sync().unwrap().server_time_now(), None);
+
+        for _ in 0..200 {
+            server.service(1).unwrap();
+            client.service(1).unwrap();
+
+            let clock = client.clock_sync().unwrap();
+            if clock.estimate().is_some_and(|e| e.confidence >= 0.5) {
+                break;
+            }
+        }
+
+        let clock = client.clock_sync().unwrap();
+        let estimate = clock.estimate().expect("no probe was answered");
+        assert!(estimate.confidence >= 0.5);
+
+        // each accepted sample is off by at most half of its round trip time,
+        // which is at most `OUTLIER_FACTOR` times the median
+        let max_error = estimate.rtt.mul_f64(OUTLIER_FACTOR) / 2 + Duration::from_millis(1);
+        let offset = Duration::from_micros(estimate.offset_micros.max(0) as u64);
+        assert!(
+            offset + max_error >= Duration::from_millis(50),
+            "offset: {:?}, max error: {:?}",
+            offset,
+            max_error
+        );
+
+        let start = Instant::now();
+        let expected = server.clock_sync().unwrap().server_time_now().unwrap();
+        let estimated = clock.server_time_now().unwrap();
+        let max_error = max_error + start.elapsed();
+
+        let error = expected.abs_diff(estimated);
+        assert!(
+            error <= max_error,
+            "error: {:?}, max error: {:?}",
+            error,
+            max_error
+        );
+    }
+}
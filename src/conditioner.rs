@@ -0,0 +1,704 @@
+use std::{
+    cell::{Cell, RefCell},
+    cmp::Ordering,
+    collections::{BinaryHeap, HashMap, VecDeque},
+    io,
+    net::{Ipv4Addr, SocketAddrV4, UdpSocket},
+    os::raw::c_int,
+    rc::Rc,
+    time::{Duration, Instant},
+};
+
+use enet_sys::{
+    _ENetPeerState_ENET_PEER_STATE_CONNECTED, enet_peer_send, enet_socket_get_address, ENetAddress,
+    ENetHost, ENetPeer,
+};
+
+use crate::{rng::SplitMix64, Packet};
+
+const NUDGE_MAGIC: &[u8; 4] = b"ENRN";
+
+thread_local! {
+    /// The conditioner of the host that is currently being serviced on this
+    /// thread, used by the intercept callback.
+    static ACTIVE: Cell<*mut NetworkConditioner> = const { Cell::new(std::ptr::null_mut()) };
+
+    /// The queues of outgoing packets of the hosts on this thread that
+    /// condition their outgoing traffic. `Host` is not `Send`, so its peers
+    /// are always used on the thread it was created on.
+    static OUTGOING: RefCell<HashMap<*mut ENetHost, Rc<RefCell<Outgoing>>>> =
+        RefCell::new(HashMap::new());
+}
+
+/// The traffic of a `Host` that a `NetworkConditioner` applies to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TrafficDirection {
+    /// Only the datagrams the host receives.
+    Incoming,
+    /// Only the packets the host sends.
+    Outgoing,
+    /// The datagrams the host receives and the packets it sends, which are
+    /// conditioned independently.
+    Both,
+}
+
+/// Simulates a lossy network for the traffic received or sent by a `Host`.
+///
+/// Once attached using `Host::set_network_conditioner`, each received
+/// datagram can be dropped, delayed, duplicated or reordered before ENet
+/// processes it, so reliable packets are resent as over a real network. Only
+/// incoming traffic is conditioned by default, see `with_direction`.
+///
+/// Outgoing traffic is conditioned per packet instead, before ENet queues it,
+/// as ENet sends its datagrams directly from its socket. Dropped packets are
+/// never handed to ENet, delayed ones once they are due. Reliable packets are
+/// only delayed, since ENet would resend them if they were lost, and sequenced
+/// packets keep their order on their channel, so only unsequenced packets are
+/// reordered. Packets that are still held back when the connection ends are
+/// lost. ENet's own control traffic, e.g. acknowledgements and pings, is not
+/// conditioned on the way out.
+///
+/// All decisions are made using a random number generator seeded with the
+/// given seed, so the same sequence of datagrams is always treated the same
+/// way. Delayed incoming datagrams are re-injected by sending a small wake-up
+/// datagram from a local socket to the host, so the host has to be reachable
+/// on loopback.
+pub struct NetworkConditioner {
+    direction: TrafficDirection,
+    conditions: Conditions,
+
+    rng: SplitMix64,
+    next_seq: u64,
+    delayed: BinaryHeap<Delayed<Datagram>>,
+    released: VecDeque<Datagram>,
+    nudge: Option<Nudge>,
+    outgoing: Option<(*mut ENetHost, Rc<RefCell<Outgoing>>)>,
+}
+
+impl NetworkConditioner {
+    /// Creates a new `NetworkConditioner` that does not affect any traffic
+    /// yet, using `seed` for all random decisions.
+    pub fn new(seed: u64) -> NetworkConditioner {
+        NetworkConditioner {
+            direction: TrafficDirection::Incoming,
+            conditions: Conditions {
+                loss: 0.0,
+                latency: Duration::ZERO,
+                jitter: Duration::ZERO,
+                duplication: 0.0,
+                reordering: 0.0,
+                reorder_delay: Duration::ZERO,
+            },
+            rng: SplitMix64::new(seed),
+            next_seq: 0,
+            delayed: BinaryHeap::new(),
+            released: VecDeque::new(),
+            nudge: None,
+            outgoing: None,
+        }
+    }
+
+    /// Sets which traffic is conditioned, defaults to
+    /// `TrafficDirection::Incoming`.
+    pub fn with_direction(mut self, direction: TrafficDirection) -> NetworkConditioner {
+        self.direction = direction;
+        self
+    }
+
+    /// Drops each datagram with the given probability, between `0.0` and
+    /// `1.0`.
+    pub fn with_loss(mut self, probability: f64) -> NetworkConditioner {
+        self.conditions.loss = probability;
+        self
+    }
+
+    /// Delays each datagram by `latency`.
+    pub fn with_latency(mut self, latency: Duration) -> NetworkConditioner {
+        self.conditions.latency = latency;
+        self
+    }
+
+    /// Delays each datagram by an additional, uniformly distributed amount
+    /// between zero and `jitter`.
+    pub fn with_jitter(mut self, jitter: Duration) -> NetworkConditioner {
+        self.conditions.jitter = jitter;
+        self
+    }
+
+    /// Delivers each datagram twice with the given probability. The duplicate
+    /// is delayed independently.
+    pub fn with_duplication(mut self, probability: f64) -> NetworkConditioner {
+        self.conditions.duplication = probability;
+        self
+    }
+
+    /// Holds back each datagram by an additional `delay` with the given
+    /// probability, so that it is overtaken by later datagrams.
+    pub fn with_reordering(mut self, probability: f64, delay: Duration) -> NetworkConditioner {
+        self.conditions.reordering = probability;
+        self.conditions.reorder_delay = delay;
+        self
+    }
+
+    /// Creates the socket used to re-inject delayed datagrams, and starts
+    /// holding back the packets sent by `host` if outgoing traffic is
+    /// conditioned.
+    pub(crate) fn attach(&mut self, host: *mut ENetHost) -> io::Result<()> {
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0))?;
+        socket.set_nonblocking(true)?;
+
+        let port = socket.local_addr()?.port();
+
+        let mut message = NUDGE_MAGIC.to_vec();
+        message.extend_from_slice(&self.rng.next_u64().to_be_bytes());
+
+        self.nudge = Some(Nudge {
+            socket,
+            address: ENetAddress {
+                host: u32::from_ne_bytes(Ipv4Addr::LOCALHOST.octets()),
+                port,
+            },
+            message,
+        });
+
+        if self.direction != TrafficDirection::Incoming {
+            let outgoing = Rc::new(RefCell::new(Outgoing {
+                conditions: self.conditions,
+                rng: SplitMix64::new(self.rng.next_u64()),
+                next_seq: 0,
+                queued: BinaryHeap::new(),
+                last_due: HashMap::new(),
+            }));
+
+            OUTGOING.with(|queues| queues.borrow_mut().insert(host, outgoing.clone()));
+            self.outgoing = Some((host, outgoing));
+        }
+
+        Ok(())
+    }
+
+    fn conditions_incoming(&self) -> bool {
+        self.direction != TrafficDirection::Outgoing
+    }
+
+    /// Returns when the next delayed datagram or packet is due, if any.
+    pub(crate) fn next_due(&self) -> Option<Instant> {
+        let incoming = self.delayed.peek().map(|delayed| delayed.due);
+        let outgoing = self
+            .outgoing
+            .as_ref()
+            .and_then(|(_, outgoing)| outgoing.borrow().queued.peek().map(|delayed| delayed.due));
+
+        incoming.into_iter().chain(outgoing).min()
+    }
+
+    /// Runs `f`, which services or flushes `host`, with this conditioner
+    /// active. All delayed datagrams and packets that are due are released
+    /// before.
+    pub(crate) fn condition<R>(&mut self, host: *mut ENetHost, f: impl FnOnce() -> R) -> R {
+        self.release_due(host);
+
+        NetworkConditioner::with_active(self, f)
+    }
+
+    /// Re-injects all incoming datagrams that are due into `host`, and hands
+    /// all outgoing packets that are due to ENet.
+    fn release_due(&mut self, host: *mut ENetHost) {
+        let now = Instant::now();
+
+        if let Some((_, outgoing)) = &self.outgoing {
+            outgoing.borrow_mut().release_due(host, now);
+        }
+
+        let nudge = match &self.nudge {
+            Some(nudge) => nudge,
+            None => return,
+        };
+
+        if self.delayed.peek().is_none_or(|delayed| delayed.due > now) {
+            return;
+        }
+
+        let mut address = ENetAddress { host: 0, port: 0 };
+        if unsafe { enet_socket_get_address((*host).socket, &mut address) } != 0 {
+            return;
+        }
+
+        let ip = match Ipv4Addr::from(address.host.to_ne_bytes()) {
+            ip if ip.is_unspecified() => Ipv4Addr::LOCALHOST,
+            ip => ip,
+        };
+        let target = SocketAddrV4::new(ip, address.port);
+
+        while let Some(delayed) = pop_due(&mut self.delayed, now) {
+            // a datagram whose wake-up could not be sent is lost
+            if nudge.socket.send_to(&nudge.message, target).is_ok() {
+                self.released.push_back(delayed.item);
+            }
+        }
+    }
+
+    /// Runs `f` with this conditioner active for the intercept callback.
+    pub(crate) fn with_active<R>(conditioner: *mut NetworkConditioner, f: impl FnOnce() -> R) -> R {
+        let previous = ACTIVE.with(|active| active.replace(conditioner));
+        let result = f();
+        ACTIVE.with(|active| active.set(previous));
+        result
+    }
+
+    /// Decides the fate of a received datagram. Returns its delay, or `None` if
+    /// it is dropped, and the delay of a duplicate, if any.
+    fn roll(&mut self) -> (Option<Duration>, Option<Duration>) {
+        self.conditions.roll(&mut self.rng)
+    }
+
+    fn schedule(&mut self, due: Instant, datagram: Datagram) {
+        self.delayed.push(Delayed {
+            due,
+            seq: self.next_seq,
+            item: datagram,
+        });
+        self.next_seq += 1;
+    }
+
+    /// Handles the datagram that `host` just received, returning the result of
+    /// the intercept callback.
+    unsafe fn intercept(&mut self, host: &mut ENetHost) -> c_int {
+        let data = std::slice::from_raw_parts(host.receivedData, host.receivedDataLength);
+
+        if let Some(nudge) = &self.nudge {
+            let from = &host.receivedAddress;
+
+            if from.host == nudge.address.host
+                && from.port == nudge.address.port
+                && data == nudge.message.as_slice()
+            {
+                return match self.released.pop_front() {
+                    Some(datagram) => {
+                        // the datagram was received into this buffer before, so it fits
+                        std::ptr::copy_nonoverlapping(
+                            datagram.data.as_ptr(),
+                            host.receivedData,
+                            datagram.data.len(),
+                        );
+                        host.receivedDataLength = datagram.data.len();
+                        host.receivedAddress = datagram.address;
+                        0
+                    }
+                    None => 1,
+                };
+            }
+        }
+
+        if !self.conditions_incoming() {
+            return 0;
+        }
+
+        let now = Instant::now();
+        let (delay, duplicate) = self.roll();
+
+        if let Some(duplicate) = duplicate {
+            let datagram = Datagram::copy(host.receivedAddress, data);
+            self.schedule(now + duplicate, datagram);
+        }
+
+        match delay {
+            None => 1,
+            Some(delay) if delay.is_zero() => 0,
+            Some(delay) => {
+                let datagram = Datagram::copy(host.receivedAddress, data);
+                self.schedule(now + delay, datagram);
+                1
+            }
+        }
+    }
+}
+
+impl Drop for NetworkConditioner {
+    fn drop(&mut self) {
+        if let Some((host, outgoing)) = self.outgoing.take() {
+            // a conditioner that replaced this one already registered itself
+            OUTGOING.with(|queues| {
+                let mut queues = queues.borrow_mut();
+
+                if queues
+                    .get(&host)
+                    .is_some_and(|queued| Rc::ptr_eq(queued, &outgoing))
+                {
+                    queues.remove(&host);
+                }
+            });
+        }
+    }
+}
+
+/// The parameters of a `NetworkConditioner`.
+#[derive(Debug, Clone, Copy)]
+struct Conditions {
+    loss: f64,
+    latency: Duration,
+    jitter: Duration,
+    duplication: f64,
+    reordering: f64,
+    reorder_delay: Duration,
+}
+
+impl Conditions {
+    /// Returns the delay of a datagram, or `None` if it is dropped, and the
+    /// delay of a duplicate, if any.
+    fn roll(&self, rng: &mut SplitMix64) -> (Option<Duration>, Option<Duration>) {
+        if rng.chance(self.loss) {
+            return (None, None);
+        }
+
+        let delay = self.delay(rng);
+        let duplicate = if rng.chance(self.duplication) {
+            Some(self.delay(rng))
+        } else {
+            None
+        };
+
+        (Some(delay), duplicate)
+    }
+
+    fn delay(&self, rng: &mut SplitMix64) -> Duration {
+        let mut delay = self.latency + self.jitter.mul_f64(rng.next_f64());
+
+        if rng.chance(self.reordering) {
+            delay += self.reorder_delay;
+        }
+
+        delay
+    }
+}
+
+/// The packets held back by the `NetworkConditioner` of a host that
+/// conditions its outgoing traffic.
+struct Outgoing {
+    conditions: Conditions,
+    rng: SplitMix64,
+    next_seq: u64,
+    queued: BinaryHeap<Delayed<QueuedPacket>>,
+    /// When the last sequenced packet on each channel of each peer is due.
+    last_due: HashMap<(u16, u8), Instant>,
+}
+
+impl Outgoing {
+    /// Holds back `packet`, which is sent to `peer` on `channel_id`. Returns
+    /// the packet if it should be sent right away.
+    fn push(&mut self, peer: &ENetPeer, channel_id: u8, packet: Packet) -> Option<Packet> {
+        let now = Instant::now();
+        let mode = packet.mode();
+
+        let (delay, duplicate) = if mode.is_reliable() {
+            // ENet resends lost reliable packets, and discards duplicates
+            (Some(self.conditions.delay(&mut self.rng)), None)
+        } else {
+            self.conditions.roll(&mut self.rng)
+        };
+
+        let key = (peer.incomingPeerID, channel_id);
+        let due_at = |last_due: &mut HashMap<(u16, u8), Instant>, delay: Duration| {
+            if !mode.is_sequenced() {
+                return now + delay;
+            }
+
+            // sequenced packets are numbered when ENet queues them
+            let due = last_due
+                .get(&key)
+                .map_or(now + delay, |last| (*last).max(now + delay));
+            last_due.insert(key, due);
+            due
+        };
+
+        if let Some(duplicate) = duplicate {
+            if let Ok(copy) = Packet::new(packet.data(), mode) {
+                let due = due_at(&mut self.last_due, duplicate);
+                self.schedule(peer, channel_id, due, copy);
+            }
+        }
+
+        let due = due_at(&mut self.last_due, delay?);
+
+        if due <= now {
+            return Some(packet);
+        }
+
+        self.schedule(peer, channel_id, due, packet);
+        None
+    }
+
+    fn schedule(&mut self, peer: &ENetPeer, channel_id: u8, due: Instant, packet: Packet) {
+        self.queued.push(Delayed {
+            due,
+            seq: self.next_seq,
+            item: QueuedPacket {
+                peer_id: peer.incomingPeerID,
+                connect_id: peer.connectID,
+                channel_id,
+                packet,
+            },
+        });
+        self.next_seq += 1;
+    }
+
+    /// Hands all packets that are due to ENet.
+    fn release_due(&mut self, host: *mut ENetHost, now: Instant) {
+        while let Some(delayed) = pop_due(&mut self.queued, now) {
+            let queued = delayed.item;
+
+            unsafe {
+                let peer = (*host).peers.add(usize::from(queued.peer_id));
+
+                // the connection ended in the meantime
+                if (*peer).state != _ENetPeerState_ENET_PEER_STATE_CONNECTED
+                    || (*peer).connectID != queued.connect_id
+                {
+                    continue;
+                }
+
+                if enet_peer_send(peer, queued.channel_id, queued.packet.as_ptr()) == 0 {
+                    // ENet owns the packet now
+                    queued.packet.into_inner();
+                }
+            }
+        }
+    }
+}
+
+/// Holds back `packet`, which is about to be sent to `peer` on `channel_id`,
+/// if the host of `peer` conditions its outgoing traffic. Returns the packet
+/// if it should be sent right away.
+pub(crate) unsafe fn condition_outgoing(
+    peer: *mut ENetPeer,
+    channel_id: u8,
+    packet: Packet,
+) -> Option<Packet> {
+    let host = (*peer).host;
+    let outgoing = OUTGOING.with(|queues| queues.borrow().get(&host).cloned());
+
+    let outgoing = match outgoing {
+        Some(outgoing) => outgoing,
+        None => return Some(packet),
+    };
+
+    // let ENet report packets it would refuse right away
+    if (*peer).state != _ENetPeerState_ENET_PEER_STATE_CONNECTED
+        || usize::from(channel_id) >= (*peer).channelCount
+        || packet.data().len() > (*host).maximumPacketSize
+    {
+        return Some(packet);
+    }
+
+    let mut outgoing = outgoing.borrow_mut();
+    // packets that are due go first, so none of them is overtaken
+    outgoing.release_due(host, Instant::now());
+    outgoing.push(&*peer, channel_id, packet)
+}
+
+/// Returns whether the host of `peer` conditions its outgoing traffic.
+pub(crate) unsafe fn conditions_outgoing(peer: *mut ENetPeer) -> bool {
+    OUTGOING.with(|queues| queues.borrow().contains_key(&(*peer).host))
+}
+
+/// Conditions the datagram `host` just received if a `NetworkConditioner` is
+/// active, returning the result of the intercept callback.
+pub(crate) unsafe fn intercept(host: *mut ENetHost) -> c_int {
+    let conditioner = ACTIVE.with(Cell::get);
+
+    if conditioner.is_null() {
+        return 0;
+    }
+
+    (*conditioner).intercept(&mut *host)
+}
+
+fn pop_due<I>(delayed: &mut BinaryHeap<Delayed<I>>, now: Instant) -> Option<Delayed<I>> {
+    match delayed.peek() {
+        Some(next) if next.due <= now => delayed.pop(),
+        _ => None,
+    }
+}
+
+struct Nudge {
+    socket: UdpSocket,
+    address: ENetAddress,
+    message: Vec<u8>,
+}
+
+struct Datagram {
+    address: ENetAddress,
+    data: Vec<u8>,
+}
+
+impl Datagram {
+    fn copy(address: ENetAddress, data: &[u8]) -> Datagram {
+        Datagram {
+            address,
+            data: data.to_vec(),
+        }
+    }
+}
+
+/// A packet held back for a connection, identified by its peer and connect
+/// id.
+struct QueuedPacket {
+    peer_id: u16,
+    connect_id: u32,
+    channel_id: u8,
+    packet: Packet,
+}
+
+/// A delayed datagram or packet, ordered so that the earliest one is the
+/// greatest.
+struct Delayed<I> {
+    due: Instant,
+    seq: u64,
+    item: I,
+}
+
+impl<I> PartialEq for Delayed<I> {
+    fn eq(&self, other: &Delayed<I>) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl<I> Eq for Delayed<I> {}
+
+impl<I> PartialOrd for Delayed<I> {
+    fn partial_cmp(&self, other: &Delayed<I>) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<I> Ord for Delayed<I> {
+    fn cmp(&self, other: &Delayed<I>) -> Ordering {
+        (other.due, other.seq).cmp(&(self.due, self.seq))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{NetworkConditioner, TrafficDirection};
+    use crate::{
+        tests::{connect_pair, create_host, create_server},
+        Event, Host, Packet, PacketMode,
+    };
+
+    #[test]
+    fn test_seeded_decisions() {
+        let fates = |seed| {
+            let mut conditioner = NetworkConditioner::new(seed)
+                .with_loss(0.3)
+                .with_jitter(Duration::from_millis(10))
+                .with_duplication(0.1);
+
+            (0..100).map(|_| conditioner.roll()).collect::<Vec<_>>()
+        };
+
+        assert_eq!(fates(7), fates(7));
+        assert_ne!(fates(7), fates(8));
+
+        let dropped = fates(7).iter().filter(|(delay, _)| delay.is_none()).count();
+        assert!((15..=45).contains(&dropped), "dropped {}", dropped);
+    }
+
+    fn with_conditioner(mut host: Host<()>, seed: u64, direction: TrafficDirection) -> Host<()> {
+        let conditioner = NetworkConditioner::new(seed)
+            .with_direction(direction)
+            .with_loss(0.2)
+            .with_latency(Duration::from_millis(5))
+            .with_jitter(Duration::from_millis(10))
+            .with_duplication(0.1)
+            .with_reordering(0.1, Duration::from_millis(20));
+        host.set_network_conditioner(conditioner).unwrap();
+
+        host
+    }
+
+    #[test]
+    fn test_reliable_over_lossy_network() {
+        for direction in [
+            TrafficDirection::Incoming,
+            TrafficDirection::Outgoing,
+            TrafficDirection::Both,
+        ] {
+            let server = with_conditioner(create_server(1), 1, direction);
+            let client = with_conditioner(create_host(None, 1), 2, direction);
+
+            assert_eq!(
+                exchange(server, client),
+                (0..20).collect::<Vec<_>>(),
+                "{:?}",
+                direction
+            );
+        }
+    }
+
+    #[test]
+    fn test_outgoing_loss() {
+        let mut server: Host<()> = create_server(1);
+        let mut client: Host<()> = create_host(None, 1);
+        let (_, server_id) = connect_pair(&mut server, &mut client, 1);
+
+        let conditioner = NetworkConditioner::new(3)
+            .with_direction(TrafficDirection::Outgoing)
+            .with_loss(1.0);
+        client.set_network_conditioner(conditioner).unwrap();
+
+        // lost unreliable packets are never sent, reliable ones would be resent
+        let mut peer = client.peer(server_id).unwrap();
+        for mode in [
+            PacketMode::UnreliableUnsequenced,
+            PacketMode::ReliableSequenced,
+        ] {
+            let packet = Packet::new(&[u8::from(mode.is_reliable())], mode).unwrap();
+            peer.send_packet(packet, 0).unwrap();
+        }
+
+        let mut received = Vec::new();
+
+        for _ in 0..50 {
+            client.service(1).unwrap();
+
+            if let Some(Event::Receive { ref packet, .. }) = server.service(1).unwrap() {
+                received.push(packet.data()[0]);
+            }
+        }
+
+        assert_eq!(received, vec![1]);
+    }
+
+    /// Sends 20 reliable packets from `client` to `server`, returning those
+    /// received in order.
+    fn exchange(mut server: Host<()>, mut client: Host<()>) -> Vec<u8> {
+        let server_addr = server.address();
+
+        client.connect(&server_addr, 1, 0).unwrap();
+
+        let mut received = Vec::new();
+
+        for _ in 0..2000 {
+            if let Some(Event::Connect(ref peer)) = client.service(1).unwrap() {
+                let mut peer = peer.clone();
+
+                for i in 0..20u8 {
+                    let packet = Packet::new(&[i], PacketMode::ReliableSequenced).unwrap();
+                    peer.send_packet(packet, 0).unwrap();
+                }
+            }
+
+            if let Some(Event::Receive { ref packet, .. }) = server.service(1).unwrap() {
+                received.push(packet.data()[0]);
+
+                if received.len() == 20 {
+                    break;
+                }
+            }
+        }
+
+        received
+    }
+}
@@ -0,0 +1,187 @@
+use crate::{
+    socket::{SocketError, SocketOption},
+    Address, AddressParseError, BandwidthLimit, ChannelLimit, ToAddress,
+};
+
+/// The configuration of a `Host`, used with `Enet::create_host_with_config`.
+///
+/// Unlike `Enet::create_host`, this allows setting socket options before the
+/// socket of the host is bound, which some options such as
+/// `SocketOption::ReuseAddress` require.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HostConfig {
+    pub(crate) address: Option<Result<Address, AddressParseError>>,
+    pub(crate) max_peer_count: usize,
+    pub(crate) max_channel_count: ChannelLimit,
+    pub(crate) incoming_bandwidth: BandwidthLimit,
+    pub(crate) outgoing_bandwidth: BandwidthLimit,
+    pub(crate) socket_options: Vec<SocketOption>,
+}
+
+impl HostConfig {
+    /// Creates a configuration for a client-only host with up to 32 peers,
+    /// the maximum number of channels and unlimited bandwidth.
+    pub fn new() -> HostConfig {
+        HostConfig {
+            address: None,
+            max_peer_count: 32,
+            max_channel_count: ChannelLimit::Maximum,
+            incoming_bandwidth: BandwidthLimit::Unlimited,
+            outgoing_bandwidth: BandwidthLimit::Unlimited,
+            socket_options: Vec::new(),
+        }
+    }
+
+    /// Sets the address to listen on, such as an `Address` or a string like
+    /// `"0.0.0.0:9000"`. An invalid address is reported by
+    /// `Enet::create_host_with_config`.
+    pub fn with_address(mut self, address: impl ToAddress) -> HostConfig {
+        self.address = Some(address.to_address());
+        self
+    }
+
+    /// Sets the maximum number of peers, defaults to 32.
+    pub fn with_max_peer_count(mut self, max_peer_count: usize) -> HostConfig {
+        self.max_peer_count = max_peer_count;
+        self
+    }
+
+    /// Sets the maximum number of channels per peer, defaults to
+    /// `ChannelLimit::Maximum`.
+    pub fn with_max_channel_count(mut self, max_channel_count: ChannelLimit) -> HostConfig {
+        self.max_channel_count = max_channel_count;
+        self
+    }
+
+    /// Sets the incoming and outgoing bandwidth limits, defaults to
+    /// unlimited.
+    pub fn with_bandwidth_limits(
+        mut self,
+        incoming_bandwidth: BandwidthLimit,
+        outgoing_bandwidth: BandwidthLimit,
+    ) -> HostConfig {
+        self.incoming_bandwidth = incoming_bandwidth;
+        self.outgoing_bandwidth = outgoing_bandwidth;
+        self
+    }
+
+    /// Adds an option to set on the socket of the host before it is bound.
+    /// Options are set in the order they are added, after ENet's defaults.
+    pub fn with_socket_option(mut self, option: SocketOption) -> HostConfig {
+        self.socket_options.push(option);
+        self
+    }
+}
+
+impl Default for HostConfig {
+    fn default() -> HostConfig {
+        HostConfig::new()
+    }
+}
+
+/// An error that can occur when creating a `Host` from a `HostConfig`.
+#[derive(thiserror::Error, Debug)]
+pub enum CreateHostError {
+    /// The address to listen on could not be converted into an `Address`.
+    #[error("invalid address")]
+    InvalidAddress(#[from] AddressParseError),
+    /// ENet failed to create the host.
+    #[error("failed to create host")]
+    Create,
+    /// The socket to use is not bound to an IPv4 address.
+    #[error("the socket is not bound to an IPv4 address")]
+    UnsupportedAddress,
+    /// A socket option could not be set, or the socket could not be bound.
+    #[error("failed to set up the socket of the host")]
+    Socket(#[from] SocketError),
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, UdpSocket};
+
+    use super::{CreateHostError, HostConfig};
+    use crate::{
+        socket::{SocketOption, SocketOptionKind},
+        tests::ENET,
+        Address, Event, Host,
+    };
+
+    #[test]
+    fn test_socket_options() {
+        let config = HostConfig::new()
+            .with_address("127.0.0.1:0")
+            .with_max_peer_count(4)
+            .with_socket_option(SocketOption::ReuseAddress(true))
+            .with_socket_option(SocketOption::ReceiveBuffer(1 << 20));
+
+        let mut host: Host<()> = ENET.create_host_with_config(&config).unwrap();
+        assert_eq!(host.address().ip(), &Ipv4Addr::LOCALHOST);
+        assert_ne!(host.address().port(), 0);
+        assert_eq!(host.peer_count(), 4);
+
+        host.set_socket_option(SocketOption::Ttl(9)).unwrap();
+        assert_eq!(
+            host.socket_option(SocketOptionKind::Ttl).unwrap(),
+            SocketOption::Ttl(9)
+        );
+
+        #[cfg(unix)]
+        {
+            host.set_socket_option(SocketOption::dscp(46)).unwrap();
+            assert_eq!(
+                host.socket_option(SocketOptionKind::Tos).unwrap(),
+                SocketOption::Tos(46 << 2)
+            );
+            assert_eq!(
+                host.socket_option(SocketOptionKind::ReuseAddress).unwrap(),
+                SocketOption::ReuseAddress(true)
+            );
+
+            match host.socket_option(SocketOptionKind::ReceiveBuffer).unwrap() {
+                SocketOption::ReceiveBuffer(size) => assert!(size >= 1 << 20),
+                option => panic!("unexpected option: {:?}", option),
+            }
+        }
+    }
+
+    #[test]
+    fn test_invalid_address() {
+        let config = HostConfig::new().with_address("localhost:9000");
+        assert!(matches!(
+            ENET.create_host_with_config::<()>(&config),
+            Err(CreateHostError::InvalidAddress(_))
+        ));
+    }
+
+    #[test]
+    fn test_from_udp_socket() {
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let address = Address::from(match socket.local_addr().unwrap() {
+            std::net::SocketAddr::V4(address) => address,
+            address => panic!("unexpected address: {}", address),
+        });
+
+        let mut server: Host<()> =
+            Host::from_udp_socket(&ENET, socket, &HostConfig::new()).unwrap();
+        assert_eq!(server.address(), address);
+
+        let mut client: Host<()> = ENET.create_host_with_config(&HostConfig::new()).unwrap();
+        client.connect(&address, 1, 0).unwrap();
+
+        let mut connected = false;
+        for _ in 0..100 {
+            if let Some(Event::Connect(_)) = server.service(5).unwrap() {
+                connected = true;
+                break;
+            }
+            client.service(5).unwrap();
+        }
+        assert!(connected);
+
+        let socket = UdpSocket::bind("[::1]:0");
+        if let Ok(socket) = socket {
+            assert!(Host::<()>::from_udp_socket(&ENET, socket, &HostConfig::new()).is_err());
+        }
+    }
+}
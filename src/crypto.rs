@@ -0,0 +1,552 @@
+//! Transparent encryption of packet payloads, available with the `crypto`
+//! feature.
+//!
+//! After connecting, both sides exchange ephemeral X25519 public keys as their
+//! first reliable message. The shared secret is used to derive one
+//! ChaCha20-Poly1305 key per direction, and from then on all packet payloads
+//! are encrypted and authenticated.
+//!
+//! Each encrypted packet carries an explicit 64-bit counter that is used as the
+//! nonce, so packets can be decrypted in any order, as happens with unreliable
+//! and unsequenced delivery. Replayed packets are detected using a sliding
+//! window over the received counters.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    time::{Duration, Instant},
+};
+
+use chacha20poly1305::{
+    aead::{Aead, KeyInit, Payload},
+    ChaCha20Poly1305, Key, Nonce,
+};
+use hkdf::Hkdf;
+use rand_core::OsRng;
+use sha2::Sha256;
+use x25519_dalek::{EphemeralSecret, PublicKey};
+
+use crate::{
+    early::EarlyPackets, Event, Packet, PacketMode, Peer, PeerId, RejectReason, SendError,
+};
+
+const MAGIC: &[u8; 4] = b"ENRC";
+const MSG_KEY: u8 = 1;
+const KEY_INFO: &[u8] = b"enet-rs crypto v1";
+
+const COUNTER_LEN: usize = 8;
+const TAG_LEN: usize = 16;
+
+/// The number of counters below the highest received one that are still
+/// accepted. Has to be a multiple of 64.
+const REPLAY_WINDOW: u64 = 4096;
+
+/// Encrypts all traffic of a `Host`, once set using `Host::set_encryption`.
+///
+/// `Event::Connect` is delayed until the key exchange with the peer has
+/// completed, and `Event::Receive` only contains decrypted packets. Packets
+/// that fail to decrypt are dropped. Packets that overtake the key of a peer
+/// are buffered, and a peer that sends too much before its key is
+/// disconnected. `Peer::send_packet` encrypts
+/// transparently, so application code does not have to change. Both sides of
+/// a connection have to enable encryption.
+///
+/// The key exchange is not authenticated, so it protects against passive
+/// eavesdroppers, but not against an active man-in-the-middle.
+#[derive(Debug)]
+pub struct Encryption {
+    channel_id: u8,
+    timeout: Duration,
+    pending: HashMap<PeerId, Instant>,
+    decrypted: VecDeque<(PeerId, u8, Packet)>,
+}
+
+impl Default for Encryption {
+    fn default() -> Encryption {
+        Encryption::new()
+    }
+}
+
+impl Encryption {
+    /// Creates a new `Encryption`, with the key exchange on channel `0`.
+    pub fn new() -> Encryption {
+        Encryption {
+            channel_id: 0,
+            timeout: Duration::from_secs(5),
+            pending: HashMap::new(),
+            decrypted: VecDeque::new(),
+        }
+    }
+
+    /// Sets the channel the key exchange is performed on, defaults to `0`.
+    pub fn with_channel(mut self, channel_id: u8) -> Encryption {
+        self.channel_id = channel_id;
+        self
+    }
+
+    /// Sets how long a peer may take to complete the key exchange after
+    /// connecting, defaults to 5 seconds.
+    pub fn with_timeout(mut self, timeout: Duration) -> Encryption {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Marks `peer` as connecting to an encrypting host, so nothing can be
+    /// sent to it before its key exchange has completed.
+    pub(crate) fn await_key_exchange<T>(peer: &mut Peer<'_, T>) {
+        peer.peer_data_mut().crypto = Some(PeerCrypto::Awaiting);
+    }
+
+    /// Returns the ids of all peers whose key exchange timed out.
+    pub(crate) fn expire(&mut self, now: Instant) -> Vec<PeerId> {
+        let timeout = self.timeout;
+        let mut expired = Vec::new();
+
+        self.pending.retain(|id, since| {
+            let keep = now.duration_since(*since) < timeout;
+            if !keep {
+                expired.push(*id);
+            }
+            keep
+        });
+
+        expired
+    }
+
+    /// Returns the next packet that was received during the key exchange and
+    /// decrypted once it completed.
+    pub(crate) fn pop_decrypted(&mut self) -> Option<(PeerId, u8, Packet)> {
+        self.decrypted.pop_front()
+    }
+
+    /// Runs the encryption layer for an event, returning the event that should
+    /// be handed on instead, if any.
+    pub(crate) fn process_event<'a, T>(&mut self, event: Event<'a, T>) -> Option<Event<'a, T>> {
+        match &event {
+            Event::Connect(peer) => {
+                let mut peer = peer.clone();
+
+                let secret = EphemeralSecret::random_from_rng(OsRng);
+                let public = PublicKey::from(&secret);
+
+                let sent = Packet::new(&key_message(&public), PacketMode::ReliableSequenced)
+                    .and_then(|packet| peer.send_packet_raw(packet, self.channel_id));
+
+                if sent.is_err() {
+                    peer.disconnect(RejectReason::Malformed.code());
+                    return None;
+                }
+
+                peer.peer_data_mut().crypto = Some(PeerCrypto::Pending {
+                    secret,
+                    public,
+                    early: EarlyPackets::default(),
+                });
+                self.pending.insert(peer.id(), Instant::now());
+
+                None
+            }
+            Event::Disconnect(peer, _) => {
+                let id = peer.id();
+
+                self.pending.remove(&id);
+                self.decrypted.retain(|(peer_id, ..)| *peer_id != id);
+
+                Some(event)
+            }
+            Event::Receive {
+                sender,
+                channel_id,
+                packet,
+            } => {
+                let mut peer = sender.clone();
+
+                match peer.crypto_mut()? {
+                    PeerCrypto::Awaiting => None,
+                    PeerCrypto::Established(session) => {
+                        let packet = session.open(packet.data(), packet.mode(), *channel_id)?;
+
+                        Some(Event::Receive {
+                            sender: peer,
+                            channel_id: *channel_id,
+                            packet,
+                        })
+                    }
+                    PeerCrypto::Pending { early, .. } => {
+                        let remote = match decode_key_message(packet.data()) {
+                            Some(remote) if *channel_id == self.channel_id => remote,
+                            _ => {
+                                // might have overtaken the key on another channel
+                                if early.push(*channel_id, packet).is_err() {
+                                    self.pending.remove(&peer.id());
+                                    peer.disconnect(RejectReason::RateLimited.code());
+                                }
+
+                                return None;
+                            }
+                        };
+
+                        self.establish(peer, remote)
+                    }
+                }
+            }
+            Event::Authenticated(_)
+            | Event::Resumed(_)
+            | Event::SessionExpired { .. }
+            | Event::Transfer { .. }
+            | Event::Resolved { .. }
+            | Event::ResolveFailed { .. }
+            | Event::ConnectFailed { .. } => Some(event),
+        }
+    }
+
+    fn establish<'a, T>(
+        &mut self,
+        mut peer: Peer<'a, T>,
+        remote: PublicKey,
+    ) -> Option<Event<'a, T>> {
+        let id = peer.id();
+        self.pending.remove(&id);
+
+        let (secret, public, early) = match peer.peer_data_mut().crypto.take() {
+            Some(PeerCrypto::Pending {
+                secret,
+                public,
+                early,
+            }) => (secret, public, early),
+            _ => unreachable!("key exchange is not pending"),
+        };
+
+        let mut session = match Session::new(secret, &public, &remote) {
+            Some(session) => session,
+            None => {
+                peer.disconnect(RejectReason::Malformed.code());
+                return None;
+            }
+        };
+
+        for packet in early.into_packets() {
+            if let Some(decrypted) = session.open(&packet.data, packet.mode, packet.channel_id) {
+                self.decrypted.push_back((id, packet.channel_id, decrypted));
+            }
+        }
+
+        peer.peer_data_mut().crypto = Some(PeerCrypto::Established(session));
+
+        Some(Event::Connect(peer))
+    }
+}
+
+fn key_message(public: &PublicKey) -> Vec<u8> {
+    let mut data = MAGIC.to_vec();
+    data.push(MSG_KEY);
+    data.extend_from_slice(public.as_bytes());
+    data
+}
+
+fn decode_key_message(data: &[u8]) -> Option<PublicKey> {
+    let key: [u8; 32] = data
+        .strip_prefix(MAGIC)?
+        .strip_prefix(&[MSG_KEY])?
+        .try_into()
+        .ok()?;
+
+    Some(PublicKey::from(key))
+}
+
+/// The encryption state of a single peer.
+pub(crate) enum PeerCrypto {
+    /// The peer is connecting, the key exchange starts once it is connected.
+    Awaiting,
+    /// Our public key was sent, waiting for the peer's key.
+    Pending {
+        secret: EphemeralSecret,
+        public: PublicKey,
+        early: EarlyPackets,
+    },
+    /// Keys have been exchanged, packets are encrypted.
+    Established(Session),
+}
+
+/// The keys and counters of an established connection.
+pub(crate) struct Session {
+    sealer: ChaCha20Poly1305,
+    opener: ChaCha20Poly1305,
+    next_counter: u64,
+    replay: ReplayWindow,
+}
+
+impl Session {
+    /// Derives a session from our secret and both public keys. Returns `None`
+    /// if the remote key is not acceptable.
+    fn new(secret: EphemeralSecret, public: &PublicKey, remote: &PublicKey) -> Option<Session> {
+        // a reflected key would result in both directions using the same key
+        if public.as_bytes() == remote.as_bytes() {
+            return None;
+        }
+
+        let shared = secret.diffie_hellman(remote);
+
+        if !shared.was_contributory() {
+            return None;
+        }
+
+        let we_are_low = public.as_bytes() < remote.as_bytes();
+        let (low, high) = if we_are_low {
+            (public, remote)
+        } else {
+            (remote, public)
+        };
+
+        let mut info = KEY_INFO.to_vec();
+        info.extend_from_slice(low.as_bytes());
+        info.extend_from_slice(high.as_bytes());
+
+        let mut keys = [0u8; 64];
+        Hkdf::<Sha256>::new(None, shared.as_bytes())
+            .expand(&info, &mut keys)
+            .ok()?;
+
+        // the first key is used by the side with the lower public key to send
+        let (low_key, high_key) = keys.split_at(32);
+        let (seal_key, open_key) = if we_are_low {
+            (low_key, high_key)
+        } else {
+            (high_key, low_key)
+        };
+
+        Some(Session {
+            sealer: ChaCha20Poly1305::new(Key::from_slice(seal_key)),
+            opener: ChaCha20Poly1305::new(Key::from_slice(open_key)),
+            next_counter: 0,
+            replay: ReplayWindow::new(),
+        })
+    }
+
+    /// Encrypts `packet`, which is going to be sent on `channel_id`.
+    pub(crate) fn seal(&mut self, packet: &Packet, channel_id: u8) -> Result<Packet, SendError> {
+        let counter = self.next_counter;
+        self.next_counter = counter.checked_add(1).ok_or(SendError::NonceExhausted)?;
+
+        let payload = Payload {
+            msg: packet.data(),
+            aad: &[channel_id],
+        };
+
+        let ciphertext = self
+            .sealer
+            .encrypt(&nonce(counter), payload)
+            .map_err(|_| SendError::Encrypt)?;
+
+        let mut data = Vec::with_capacity(COUNTER_LEN + ciphertext.len());
+        data.extend_from_slice(&counter.to_be_bytes());
+        data.extend_from_slice(&ciphertext);
+
+        Ok(Packet::new(&data, packet.mode())?)
+    }
+
+    /// Decrypts a packet received on `channel_id`. Returns `None` if it was
+    /// tampered with or replayed.
+    pub(crate) fn open(&mut self, data: &[u8], mode: PacketMode, channel_id: u8) -> Option<Packet> {
+        if data.len() < COUNTER_LEN + TAG_LEN {
+            return None;
+        }
+
+        let (counter, ciphertext) = data.split_at(COUNTER_LEN);
+        let counter = u64::from_be_bytes(counter.try_into().unwrap());
+
+        if !self.replay.is_fresh(counter) {
+            return None;
+        }
+
+        let payload = Payload {
+            msg: ciphertext,
+            aad: &[channel_id],
+        };
+
+        let plaintext = self.opener.decrypt(&nonce(counter), payload).ok()?;
+
+        self.replay.mark(counter);
+
+        Packet::new(&plaintext, mode).ok()
+    }
+}
+
+fn nonce(counter: u64) -> Nonce {
+    let mut nonce = Nonce::default();
+    nonce[4..].copy_from_slice(&counter.to_be_bytes());
+    nonce
+}
+
+/// Tracks which of the last `REPLAY_WINDOW` counters have been received.
+struct ReplayWindow {
+    /// One more than the highest received counter.
+    top: u64,
+    seen: Box<[u64; (REPLAY_WINDOW / 64) as usize]>,
+}
+
+impl ReplayWindow {
+    fn new() -> ReplayWindow {
+        ReplayWindow {
+            top: 0,
+            seen: Box::new([0; (REPLAY_WINDOW / 64) as usize]),
+        }
+    }
+
+    fn bit(counter: u64) -> (usize, u64) {
+        let index = counter % REPLAY_WINDOW;
+        ((index / 64) as usize, 1 << (index % 64))
+    }
+
+    fn is_fresh(&self, counter: u64) -> bool {
+        if counter >= self.top {
+            return true;
+        }
+
+        if self.top - counter > REPLAY_WINDOW {
+            return false;
+        }
+
+        let (word, mask) = ReplayWindow::bit(counter);
+        self.seen[word] & mask == 0
+    }
+
+    fn mark(&mut self, counter: u64) {
+        if counter >= self.top {
+            // forget the counters that are slid out of the window
+            if counter - self.top >= REPLAY_WINDOW {
+                self.seen.fill(0);
+            } else {
+                for c in self.top..=counter {
+                    let (word, mask) = ReplayWindow::bit(c);
+                    self.seen[word] &= !mask;
+                }
+            }
+
+            self.top = counter + 1;
+        }
+
+        let (word, mask) = ReplayWindow::bit(counter);
+        self.seen[word] |= mask;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand_core::OsRng;
+    use x25519_dalek::{EphemeralSecret, PublicKey};
+
+    use super::{ReplayWindow, Session, REPLAY_WINDOW};
+    use crate::{
+        tests::{create_host, create_server},
+        Encryption, Event, Packet, PacketMode, SendError,
+    };
+
+    fn session_pair() -> (Session, Session) {
+        let (a, b) = (
+            EphemeralSecret::random_from_rng(OsRng),
+            EphemeralSecret::random_from_rng(OsRng),
+        );
+        let (a_public, b_public) = (PublicKey::from(&a), PublicKey::from(&b));
+
+        (
+            Session::new(a, &a_public, &b_public).unwrap(),
+            Session::new(b, &b_public, &a_public).unwrap(),
+        )
+    }
+
+    fn seal(session: &mut Session, data: &[u8], channel_id: u8) -> Vec<u8> {
+        let packet = Packet::new(data, PacketMode::UnreliableUnsequenced).unwrap();
+        session.seal(&packet, channel_id).unwrap().data().to_vec()
+    }
+
+    #[test]
+    fn test_session_out_of_order() {
+        let (mut a, mut b) = session_pair();
+
+        let first = seal(&mut a, b"first", 0);
+        let second = seal(&mut a, b"second", 0);
+        assert!(!first.windows(5).any(|w| w == b"first"));
+
+        let mode = PacketMode::UnreliableUnsequenced;
+        assert_eq!(b.open(&second, mode, 0).unwrap().data(), b"second");
+        assert_eq!(b.open(&first, mode, 0).unwrap().data(), b"first");
+
+        // replays are rejected
+        assert!(b.open(&first, mode, 0).is_none());
+
+        // so are packets moved to another channel, or tampered with
+        let mut third = seal(&mut a, b"third", 0);
+        assert!(b.open(&third, mode, 1).is_none());
+        *third.last_mut().unwrap() ^= 1;
+        assert!(b.open(&third, mode, 0).is_none());
+
+        // the other direction uses a different key
+        let back = seal(&mut b, b"back", 0);
+        assert!(b.open(&back, mode, 0).is_none());
+        assert_eq!(a.open(&back, mode, 0).unwrap().data(), b"back");
+    }
+
+    #[test]
+    fn test_session_nonce_exhausted() {
+        let (mut a, _) = session_pair();
+        let packet = Packet::new(b"last", PacketMode::UnreliableUnsequenced).unwrap();
+
+        a.next_counter = u64::MAX - 1;
+        assert!(a.seal(&packet, 0).is_ok());
+        assert!(matches!(a.seal(&packet, 0), Err(SendError::NonceExhausted)));
+    }
+
+    #[test]
+    fn test_replay_window() {
+        let mut window = ReplayWindow::new();
+
+        window.mark(5);
+        assert!(window.is_fresh(0));
+        assert!(!window.is_fresh(5));
+
+        window.mark(5 + REPLAY_WINDOW);
+        assert!(!window.is_fresh(5));
+        assert!(window.is_fresh(6));
+        assert!(!window.is_fresh(5 + REPLAY_WINDOW));
+    }
+
+    #[test]
+    fn test_encrypted_connection() {
+        let mut server = create_server::<()>(1);
+        let server_addr = server.address();
+        server.set_encryption(Encryption::new());
+
+        let mut client = create_host::<()>(None, 1);
+        client.set_encryption(Encryption::new());
+
+        // nothing may be sent in plaintext before the key exchange
+        let mut peer = client.connect(&server_addr, 2, 0).unwrap();
+        let packet = Packet::new(b"early", PacketMode::ReliableSequenced).unwrap();
+        assert!(matches!(
+            peer.try_send_packet(packet, 1),
+            Err(SendError::KeyExchangePending)
+        ));
+
+        let mut received = None;
+
+        for _ in 0..200 {
+            if let Some(Event::Connect(ref peer)) = client.service(5).unwrap() {
+                let mut peer = peer.clone();
+                let packet = Packet::new(b"harro", PacketMode::ReliableSequenced).unwrap();
+                peer.send_packet(packet, 1).unwrap();
+            }
+
+            if let Some(Event::Receive {
+                channel_id,
+                ref packet,
+                ..
+            }) = server.service(5).unwrap()
+            {
+                received = Some((channel_id, packet.data().to_vec()));
+                break;
+            }
+        }
+
+        assert_eq!(received, Some((1, b"harro".to_vec())));
+    }
+}
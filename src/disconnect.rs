@@ -0,0 +1,349 @@
+use enet_sys::{
+    _ENetPeerState, _ENetPeerState_ENET_PEER_STATE_ACKNOWLEDGING_DISCONNECT,
+    _ENetPeerState_ENET_PEER_STATE_DISCONNECTED, _ENetPeerState_ENET_PEER_STATE_DISCONNECTING,
+    _ENetPeerState_ENET_PEER_STATE_DISCONNECT_LATER, _ENetPeerState_ENET_PEER_STATE_ZOMBIE,
+    ENetHost, ENetOutgoingCommand, ENetPeer,
+};
+
+use crate::{PeerId, RejectReason};
+
+/// Disconnect data that reports to peers that there is no room for them.
+const SERVER_FULL_CODE: u32 = 0xFFFF_FFFF;
+
+/// Application-defined disconnect codes, sent as the data of a disconnection.
+///
+/// `u32` implements this trait, so raw codes can always be used.
+///
+/// Codes `>= 0xFFFE_0000` are reserved by this crate, e.g. for
+/// `RejectReason`s.
+pub trait DisconnectCode: Sized {
+    /// Returns the data to send when disconnecting with this code.
+    fn to_code(&self) -> u32;
+
+    /// Parses received disconnect data, returning `None` if it does not
+    /// represent a valid code.
+    fn from_code(code: u32) -> Option<Self>;
+}
+
+impl DisconnectCode for u32 {
+    fn to_code(&self) -> u32 {
+        *self
+    }
+
+    fn from_code(code: u32) -> Option<u32> {
+        Some(code)
+    }
+}
+
+/// Describes why a `Peer` was disconnected.
+///
+/// ENet itself only reports an opaque `u32` for disconnections. Whether a
+/// disconnection was caused by a timeout is inferred from the state of the
+/// peer before the disconnection: if the remote host did not request the
+/// disconnection and nothing was received from it for at least the peer's
+/// minimum timeout, it is reported as `Timeout`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DisconnectReason {
+    /// The connection timed out, or the connection attempt failed.
+    Timeout,
+    /// The remote host requested the disconnection, with the contained data.
+    RemoteRequested(u32),
+    /// The disconnection was requested locally, using one of the `disconnect`
+    /// methods of `Peer`.
+    LocalRequested,
+    /// The remote host rejected the connection.
+    Rejected(RejectReason),
+    /// The remote host did not have room for another connection.
+    ///
+    /// This crate never sends this on its own: ENet silently ignores
+    /// connection attempts once all peers of a host are in use, so they time
+    /// out instead. An application that limits its connections below the
+    /// peer count has to send `DisconnectReason::SERVER_FULL_CODE` itself, e.g.
+    /// from an `AdmissionPolicy` using `Admission::Reject`.
+    ServerFull,
+}
+
+impl DisconnectReason {
+    /// The disconnect data to send when a peer is refused because the server is
+    /// full. It is reported as `DisconnectReason::ServerFull` on the other
+    /// side. It is only ever sent by the application, see `ServerFull`.
+    pub const SERVER_FULL_CODE: u32 = SERVER_FULL_CODE;
+
+    /// Returns the reason for disconnect data sent by the remote host.
+    pub fn from_remote_code(code: u32) -> DisconnectReason {
+        if code == SERVER_FULL_CODE {
+            return DisconnectReason::ServerFull;
+        }
+
+        match RejectReason::from_code(code) {
+            Some(reason) => DisconnectReason::Rejected(reason),
+            None => DisconnectReason::RemoteRequested(code),
+        }
+    }
+
+    /// Returns whether this disconnection was caused by a timeout.
+    pub fn is_timeout(&self) -> bool {
+        matches!(self, DisconnectReason::Timeout)
+    }
+
+    /// Parses the data of a `RemoteRequested` disconnection into an
+    /// application-defined code.
+    ///
+    /// Returns `None` for other reasons, or if the data is not a valid `C`.
+    pub fn code<C: DisconnectCode>(&self) -> Option<C> {
+        match self {
+            DisconnectReason::RemoteRequested(code) => C::from_code(*code),
+            _ => None,
+        }
+    }
+}
+
+/// Snapshots of the peers that may disconnect during a call to ENet.
+///
+/// Only peers that were added are captured, so hosts with many free slots
+/// don't pay for them on every call. Peers are dropped once ENet reset them.
+#[derive(Debug, Default)]
+pub(crate) struct PeerSnapshots {
+    active: Vec<PeerId>,
+    snapshots: Vec<(PeerId, PeerSnapshot)>,
+}
+
+impl PeerSnapshots {
+    /// Starts capturing the peer `id`, which connected or is connecting.
+    pub(crate) fn add(&mut self, id: PeerId) {
+        if !self.active.contains(&id) {
+            self.active.push(id);
+        }
+    }
+
+    /// Takes snapshots of the active peers of `host`.
+    pub(crate) fn capture(&mut self, host: *const ENetHost) {
+        let peers = unsafe { std::slice::from_raw_parts((*host).peers, (*host).peerCount) };
+
+        self.active
+            .retain(|id| peers[id.0].state != _ENetPeerState_ENET_PEER_STATE_DISCONNECTED);
+
+        self.snapshots.clear();
+        self.snapshots.extend(
+            self.active
+                .iter()
+                .map(|&id| (id, PeerSnapshot::capture(&peers[id.0]))),
+        );
+    }
+
+    /// Returns the snapshot of the peer `id` taken by the last capture.
+    pub(crate) fn get(&self, id: PeerId) -> Option<&PeerSnapshot> {
+        self.snapshots
+            .iter()
+            .find(|(peer_id, _)| *peer_id == id)
+            .map(|(_, snapshot)| snapshot)
+    }
+}
+
+/// The state of a peer before a call to ENet, used to classify disconnections.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct PeerSnapshot {
+    state: _ENetPeerState,
+    /// When the oldest unacknowledged reliable command was sent, `0` if there
+    /// is none.
+    earliest_timeout: u32,
+    timeout_minimum: u32,
+    timeout_maximum: u32,
+}
+
+impl PeerSnapshot {
+    fn capture(peer: &ENetPeer) -> PeerSnapshot {
+        // ENet only notes when the oldest command was sent once it timed out,
+        // which may happen in the very call that times the peer out. Sent
+        // commands are kept in the order they were sent.
+        let sentinel = &peer.sentReliableCommands.sentinel;
+        let earliest_timeout = match peer.earliestTimeout {
+            0 if std::ptr::eq(sentinel.next, sentinel) => 0,
+            0 => unsafe { (*sentinel.next.cast::<ENetOutgoingCommand>()).sentTime },
+            earliest_timeout => earliest_timeout,
+        };
+
+        PeerSnapshot {
+            state: peer.state,
+            earliest_timeout,
+            timeout_minimum: peer.timeoutMinimum,
+            timeout_maximum: peer.timeoutMaximum,
+        }
+    }
+
+    /// Returns whether ENet times the peer out at `service_time`, because its
+    /// reliable commands have not been acknowledged for too long.
+    fn timed_out(&self, service_time: u32) -> bool {
+        let unacknowledged = service_time.wrapping_sub(self.earliest_timeout);
+
+        // ENet only waits for `timeout_maximum` once `timeout_minimum` passed
+        // if the command was not resent often enough, so either means a
+        // timeout
+        self.earliest_timeout != 0
+            && unacknowledged >= self.timeout_minimum.min(self.timeout_maximum)
+    }
+
+    /// Classifies a disconnection with `data`, which happened at
+    /// `service_time`.
+    #[allow(non_upper_case_globals)]
+    pub(crate) fn disconnect_reason(&self, data: u32, service_time: u32) -> DisconnectReason {
+        match self.state {
+            _ENetPeerState_ENET_PEER_STATE_ZOMBIE
+            | _ENetPeerState_ENET_PEER_STATE_ACKNOWLEDGING_DISCONNECT => {
+                DisconnectReason::from_remote_code(data)
+            }
+            _ if data != 0 => DisconnectReason::from_remote_code(data),
+            _ENetPeerState_ENET_PEER_STATE_DISCONNECTING
+            | _ENetPeerState_ENET_PEER_STATE_DISCONNECT_LATER => DisconnectReason::LocalRequested,
+            _ if self.timed_out(service_time) => DisconnectReason::Timeout,
+            _ => DisconnectReason::RemoteRequested(0),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use enet_sys::{
+        _ENetPeerState, _ENetPeerState_ENET_PEER_STATE_ACKNOWLEDGING_DISCONNECT,
+        _ENetPeerState_ENET_PEER_STATE_CONNECTED, _ENetPeerState_ENET_PEER_STATE_DISCONNECTING,
+        _ENetPeerState_ENET_PEER_STATE_DISCONNECT_LATER, _ENetPeerState_ENET_PEER_STATE_ZOMBIE,
+    };
+
+    use super::{DisconnectReason, PeerSnapshot};
+    use crate::RejectReason;
+
+    fn snapshot(state: _ENetPeerState, earliest_timeout: u32) -> PeerSnapshot {
+        PeerSnapshot {
+            state,
+            earliest_timeout,
+            timeout_minimum: 5000,
+            timeout_maximum: 30000,
+        }
+    }
+
+    #[derive(Debug, PartialEq)]
+    enum Kick {
+        Afk,
+        Cheating,
+    }
+
+    impl super::DisconnectCode for Kick {
+        fn to_code(&self) -> u32 {
+            match self {
+                Kick::Afk => 1,
+                Kick::Cheating => 2,
+            }
+        }
+
+        fn from_code(code: u32) -> Option<Kick> {
+            match code {
+                1 => Some(Kick::Afk),
+                2 => Some(Kick::Cheating),
+                _ => None,
+            }
+        }
+    }
+
+    #[test]
+    fn test_from_remote_code() {
+        use super::DisconnectCode;
+
+        let reason = DisconnectReason::from_remote_code(Kick::Cheating.to_code());
+        assert_eq!(reason, DisconnectReason::RemoteRequested(2));
+        assert_eq!(reason.code(), Some(Kick::Cheating));
+        assert_eq!(DisconnectReason::from_remote_code(7).code::<Kick>(), None);
+
+        assert_eq!(
+            DisconnectReason::from_remote_code(RejectReason::Unauthorized.code()),
+            DisconnectReason::Rejected(RejectReason::Unauthorized)
+        );
+        assert_eq!(
+            DisconnectReason::from_remote_code(DisconnectReason::SERVER_FULL_CODE),
+            DisconnectReason::ServerFull
+        );
+    }
+
+    #[test]
+    fn test_disconnect_reason() {
+        // the remote host requested the disconnection, even without data
+        for state in [
+            _ENetPeerState_ENET_PEER_STATE_ZOMBIE,
+            _ENetPeerState_ENET_PEER_STATE_ACKNOWLEDGING_DISCONNECT,
+        ] {
+            let snapshot = snapshot(state, 0);
+            assert_eq!(
+                snapshot.disconnect_reason(0, 10_000),
+                DisconnectReason::RemoteRequested(0)
+            );
+            assert_eq!(
+                snapshot.disconnect_reason(7, 10_000),
+                DisconnectReason::RemoteRequested(7)
+            );
+        }
+
+        // data can only come from the remote host
+        let connected = snapshot(_ENetPeerState_ENET_PEER_STATE_CONNECTED, 0);
+        assert_eq!(
+            connected.disconnect_reason(7, 10_000),
+            DisconnectReason::RemoteRequested(7)
+        );
+        assert_eq!(
+            connected.disconnect_reason(RejectReason::Banned.code(), 10_000),
+            DisconnectReason::Rejected(RejectReason::Banned)
+        );
+
+        for state in [
+            _ENetPeerState_ENET_PEER_STATE_DISCONNECTING,
+            _ENetPeerState_ENET_PEER_STATE_DISCONNECT_LATER,
+        ] {
+            assert_eq!(
+                snapshot(state, 0).disconnect_reason(0, 10_000),
+                DisconnectReason::LocalRequested
+            );
+        }
+
+        let unacknowledged = snapshot(_ENetPeerState_ENET_PEER_STATE_CONNECTED, 1000);
+        assert_eq!(
+            unacknowledged.disconnect_reason(0, 6000),
+            DisconnectReason::Timeout
+        );
+        assert_eq!(
+            snapshot(_ENetPeerState_ENET_PEER_STATE_CONNECTED, u32::MAX - 1000)
+                .disconnect_reason(0, 4000),
+            DisconnectReason::Timeout
+        );
+
+        let short_maximum = PeerSnapshot {
+            timeout_maximum: 2000,
+            ..unacknowledged
+        };
+        assert_eq!(
+            short_maximum.disconnect_reason(0, 3000),
+            DisconnectReason::Timeout
+        );
+
+        // a remote disconnection with data 0, captured while still connected
+        assert_eq!(
+            unacknowledged.disconnect_reason(0, 5999),
+            DisconnectReason::RemoteRequested(0)
+        );
+        // nothing was left unacknowledged, however long ago the peer was heard
+        // from
+        assert_eq!(
+            connected.disconnect_reason(0, 100_000),
+            DisconnectReason::RemoteRequested(0)
+        );
+    }
+
+    #[test]
+    fn test_receiving_peer_times_out() {
+        // the peer keeps sending unreliable packets, so it was heard from just
+        // now, but it stopped acknowledging reliable commands long ago
+        let snapshot = snapshot(_ENetPeerState_ENET_PEER_STATE_CONNECTED, 10_000);
+
+        assert_eq!(
+            snapshot.disconnect_reason(0, 15_000),
+            DisconnectReason::Timeout
+        );
+    }
+}
@@ -0,0 +1,325 @@
+use std::{
+    cell::Cell,
+    net::Ipv4Addr,
+    os::raw::c_int,
+    time::{Duration, Instant},
+};
+
+use enet_sys::{enet_socket_send, ENetBuffer, ENetHost};
+use rand_core::{OsRng, RngCore};
+
+use crate::{
+    socket::{Readiness, SocketError, SocketOption, SocketType},
+    Address, Enet,
+};
+
+const PROBE_MAGIC: &[u8; 4] = b"ENDQ";
+const REPLY_MAGIC: &[u8; 4] = b"ENDR";
+
+/// The length of the header of probes and replies: the magic and a nonce.
+const HEADER_LEN: usize = 12;
+
+/// The length of a probe, padded so servers can reply with up to
+/// `Discovery::MAX_INFO_LEN` bytes of info.
+const PROBE_LEN: usize = HEADER_LEN + Discovery::MAX_INFO_LEN;
+
+thread_local! {
+    /// The discovery of the host that is currently being serviced on this
+    /// thread, used by the intercept callback.
+    static ACTIVE: Cell<*const Discovery> = const { Cell::new(std::ptr::null()) };
+}
+
+/// Answers LAN discovery probes sent using `Enet::discover`, once set on a
+/// `Host` using `Host::set_discovery`.
+///
+/// Probes are received on the port of the host itself, so no additional
+/// socket is needed, and answered with a user-defined info blob, such as the
+/// name of the server and its number of players. Probes are only answered
+/// while the host is serviced.
+///
+/// To receive broadcast probes, the host has to be bound to
+/// `Ipv4Addr::UNSPECIFIED` or to the broadcast address of the network, not to
+/// the address of a single interface.
+///
+/// Probes are not authenticated, and their source address can be spoofed, so
+/// an attacker could use the host to reflect traffic at a victim. To not
+/// amplify such traffic, the info is limited to `Discovery::MAX_INFO_LEN`
+/// bytes, which `Enet::discover` pads its probes to, so a reply is never
+/// larger than the probe.
+///
+/// The magic of a probe is also a valid ENet header, so only datagrams of the
+/// exact length of a probe with zeroed padding are taken as probes. Any other
+/// datagram is left to ENet.
+pub struct Discovery {
+    info: Vec<u8>,
+}
+
+/// The info of a `Discovery` is longer than `Discovery::MAX_INFO_LEN` bytes,
+/// contains its length.
+#[derive(thiserror::Error, Debug, Clone, Copy, PartialEq, Eq)]
+#[error("discovery info too long: {} bytes", .0)]
+pub struct DiscoveryInfoError(pub usize);
+
+impl Discovery {
+    /// The maximum length of the info in bytes.
+    pub const MAX_INFO_LEN: usize = 512;
+
+    /// Creates a new `Discovery` that answers probes with `info`.
+    ///
+    /// Fails if `info` is longer than `Discovery::MAX_INFO_LEN` bytes.
+    pub fn new(info: impl Into<Vec<u8>>) -> Result<Discovery, DiscoveryInfoError> {
+        Ok(Discovery {
+            info: Discovery::checked_info(info.into())?,
+        })
+    }
+
+    fn checked_info(info: Vec<u8>) -> Result<Vec<u8>, DiscoveryInfoError> {
+        if info.len() > Discovery::MAX_INFO_LEN {
+            return Err(DiscoveryInfoError(info.len()));
+        }
+
+        Ok(info)
+    }
+
+    /// Returns the info probes are answered with.
+    pub fn info(&self) -> &[u8] {
+        &self.info
+    }
+
+    /// Replaces the info probes are answered with.
+    ///
+    /// Fails if `info` is longer than `Discovery::MAX_INFO_LEN` bytes, the
+    /// previous info is kept then.
+    pub fn set_info(&mut self, info: impl Into<Vec<u8>>) -> Result<(), DiscoveryInfoError> {
+        self.info = Discovery::checked_info(info.into())?;
+        Ok(())
+    }
+
+    /// Runs `f` with `discovery` active for the intercept callback.
+    pub(crate) fn with_active<R>(discovery: Option<&Discovery>, f: impl FnOnce() -> R) -> R {
+        let discovery = discovery.map_or(std::ptr::null(), |discovery| discovery as *const _);
+
+        let previous = ACTIVE.with(|active| active.replace(discovery));
+        let result = f();
+        ACTIVE.with(|active| active.set(previous));
+        result
+    }
+}
+
+/// Answers the datagram `host` just received if it is a discovery probe and
+/// a `Discovery` is active, returning the result of the intercept callback.
+pub(crate) unsafe fn intercept(host: *mut ENetHost) -> c_int {
+    let discovery = ACTIVE.with(Cell::get);
+
+    if discovery.is_null() {
+        return 0;
+    }
+
+    let host = &mut *host;
+    let data = std::slice::from_raw_parts(host.receivedData, host.receivedDataLength);
+
+    // an ENet datagram may start with the magic as well, see `Discovery`
+    if data.len() != PROBE_LEN
+        || !data.starts_with(PROBE_MAGIC)
+        || data[HEADER_LEN..].iter().any(|&byte| byte != 0)
+    {
+        return 0;
+    }
+
+    let info = &(*discovery).info;
+
+    let mut reply = REPLY_MAGIC.to_vec();
+    reply.extend_from_slice(&data[PROBE_MAGIC.len()..HEADER_LEN]);
+    reply.extend_from_slice(info);
+
+    let buffer = ENetBuffer {
+        data: reply.as_ptr() as *mut _,
+        dataLength: reply.len(),
+    };
+
+    // the client just misses this server if the reply is lost
+    enet_socket_send(host.socket, &host.receivedAddress, &buffer, 1);
+
+    1
+}
+
+impl Enet {
+    /// Discovers servers on the local network that listen on `port` and have
+    /// a `Discovery` set, by broadcasting a probe and collecting the replies
+    /// for `timeout`.
+    ///
+    /// Returns the address of each server that replied, together with its
+    /// info.
+    pub fn discover(
+        &self,
+        port: u16,
+        timeout: Duration,
+    ) -> Result<Vec<(Address, Vec<u8>)>, SocketError> {
+        self.discover_at(&Address::new(Ipv4Addr::BROADCAST, port), timeout)
+    }
+
+    /// Like `Enet::discover`, but sends the probe to `address`, which can be
+    /// the broadcast address of a specific network, such as
+    /// `192.168.1.255`, or the address of a single server.
+    pub fn discover_at(
+        &self,
+        address: &Address,
+        timeout: Duration,
+    ) -> Result<Vec<(Address, Vec<u8>)>, SocketError> {
+        let deadline = Instant::now() + timeout;
+
+        let mut socket = self.create_socket(SocketType::Datagram)?;
+        socket.set_option(SocketOption::Broadcast(true))?;
+        socket.bind(&Address::new(Ipv4Addr::UNSPECIFIED, 0))?;
+
+        // unpredictable, so replies to this probe can not be spoofed
+        let nonce = OsRng.next_u64();
+
+        let mut probe = PROBE_MAGIC.to_vec();
+        probe.extend_from_slice(&nonce.to_be_bytes());
+        probe.resize(PROBE_LEN, 0);
+        socket.send_to(&probe, address)?;
+
+        let mut servers: Vec<(Address, Vec<u8>)> = Vec::new();
+        let mut buffer = vec![0; HEADER_LEN + Discovery::MAX_INFO_LEN];
+
+        let receive = Readiness {
+            send: false,
+            receive: true,
+        };
+
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                break;
+            }
+
+            let timeout_ms = remaining.as_millis().try_into().unwrap_or(u32::MAX);
+            if !socket.wait(receive, timeout_ms)?.receive {
+                continue;
+            }
+
+            let (len, from) = match socket.receive(&mut buffer) {
+                Ok(Some(received)) => received,
+                // replies that do not fit are ignored, see `Discovery`
+                Ok(None) | Err(SocketError::Truncated) => continue,
+                Err(err) => return Err(err),
+            };
+
+            let data = &buffer[..len];
+            if len < HEADER_LEN
+                || !data.starts_with(REPLY_MAGIC)
+                || data[4..HEADER_LEN] != probe[4..HEADER_LEN]
+            {
+                continue;
+            }
+
+            // a server reachable in several ways may answer more than once
+            if !servers.iter().any(|(address, _)| *address == from) {
+                servers.push((from, data[HEADER_LEN..].to_vec()));
+            }
+        }
+
+        Ok(servers)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::Ipv4Addr,
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use super::{Discovery, DiscoveryInfoError, HEADER_LEN, PROBE_LEN, PROBE_MAGIC};
+    use crate::{
+        socket::{Readiness, Socket, SocketType},
+        tests::{create_host, ENET},
+        Address, Host,
+    };
+
+    fn create_server() -> Host<()> {
+        create_host(Some(&Address::new(Ipv4Addr::UNSPECIFIED, 0)), 1)
+    }
+
+    #[test]
+    fn test_discover() {
+        let mut server = create_server();
+        server.set_discovery(Discovery::new(b"lobby 1".to_vec()).unwrap());
+
+        let port = server.address().port();
+        let broadcast = Address::new(Ipv4Addr::new(127, 255, 255, 255), port);
+
+        let client = thread::spawn(move || {
+            ENET.discover_at(&broadcast, Duration::from_millis(300))
+                .unwrap()
+        });
+
+        while !client.is_finished() {
+            server.service(10).unwrap();
+        }
+
+        let servers = client.join().unwrap();
+        assert_eq!(servers.len(), 1);
+
+        let (address, info) = &servers[0];
+        assert_eq!(address.port(), port);
+        assert_eq!(info, b"lobby 1");
+    }
+
+    #[test]
+    fn test_malformed_probe() {
+        let mut server = create_server();
+        server.set_discovery(Discovery::new(vec![1; 100]).unwrap());
+        let address = Address::new(Ipv4Addr::LOCALHOST, server.address().port());
+
+        let mut socket = ENET.create_socket(SocketType::Datagram).unwrap();
+        socket.bind(&Address::new(Ipv4Addr::LOCALHOST, 0)).unwrap();
+
+        let receive = Readiness {
+            send: false,
+            receive: true,
+        };
+
+        let mut replied = |socket: &mut Socket| {
+            let deadline = Instant::now() + Duration::from_millis(100);
+            while Instant::now() < deadline {
+                server.service(5).unwrap();
+            }
+
+            socket.wait(receive, 0).unwrap().receive
+        };
+
+        let mut probe = PROBE_MAGIC.to_vec();
+        probe.resize(PROBE_LEN, 0);
+
+        // a probe smaller than the reply would amplify spoofed traffic
+        socket
+            .send_to(&probe[..HEADER_LEN + 100], &address)
+            .unwrap();
+        assert!(!replied(&mut socket));
+
+        // ENet datagrams that start with the magic are not probes
+        let mut datagram = probe.clone();
+        datagram[PROBE_LEN - 1] = 1;
+        socket.send_to(&datagram, &address).unwrap();
+        assert!(!replied(&mut socket));
+
+        socket.send_to(&probe, &address).unwrap();
+        assert!(replied(&mut socket));
+    }
+
+    #[test]
+    fn test_info_too_long() {
+        let too_long = vec![0; Discovery::MAX_INFO_LEN + 1];
+        assert_eq!(
+            Discovery::new(too_long.clone()).err(),
+            Some(DiscoveryInfoError(Discovery::MAX_INFO_LEN + 1))
+        );
+
+        let mut discovery = Discovery::new(b"lobby 1".to_vec()).unwrap();
+        assert!(discovery.set_info(too_long).is_err());
+        assert_eq!(discovery.info(), b"lobby 1");
+    }
+}
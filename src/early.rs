@@ -0,0 +1,87 @@
+use std::mem;
+
+use crate::{Packet, PacketMode};
+
+/// The maximum number of bytes that are buffered per peer, including a fixed
+/// overhead per packet.
+const MAX_EARLY_BYTES: usize = 64 * 1024;
+
+/// Packets that were received from a peer before a layer was ready to hand
+/// them on, e.g. because they overtook a handshake message on another
+/// channel.
+///
+/// Such peers are not admitted or authenticated yet, so the buffer is capped
+/// in bytes. Once the cap is reached, `push` fails and the peer has to be
+/// disconnected, as dropping its reliable packets would silently lose data.
+#[derive(Debug, Default)]
+pub(crate) struct EarlyPackets {
+    packets: Vec<EarlyPacket>,
+    size: usize,
+}
+
+/// A packet in `EarlyPackets`.
+#[derive(Debug)]
+pub(crate) struct EarlyPacket {
+    pub(crate) channel_id: u8,
+    pub(crate) mode: PacketMode,
+    pub(crate) data: Vec<u8>,
+}
+
+/// The buffer of a peer is full, and the peer has to be disconnected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Overflow;
+
+impl EarlyPackets {
+    /// Buffers a copy of `packet`, which was received on `channel_id`.
+    pub(crate) fn push(&mut self, channel_id: u8, packet: &Packet) -> Result<(), Overflow> {
+        let size = self.size + mem::size_of::<EarlyPacket>() + packet.data().len();
+
+        if size > MAX_EARLY_BYTES {
+            return Err(Overflow);
+        }
+
+        self.size = size;
+        self.packets.push(EarlyPacket {
+            channel_id,
+            mode: packet.mode(),
+            data: packet.data().to_vec(),
+        });
+
+        Ok(())
+    }
+
+    /// Returns the buffered packets in the order they were received.
+    pub(crate) fn into_packets(self) -> impl Iterator<Item = EarlyPacket> {
+        self.packets.into_iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{EarlyPackets, Overflow, MAX_EARLY_BYTES};
+    use crate::{Packet, PacketMode};
+
+    #[test]
+    fn test_byte_cap() {
+        let mut early = EarlyPackets::default();
+        let packet = Packet::new(&[7; 1000], PacketMode::ReliableSequenced).unwrap();
+
+        let mut pushed = 0;
+        while early.push(1, &packet).is_ok() {
+            pushed += 1;
+        }
+
+        assert!(pushed < MAX_EARLY_BYTES / 1000);
+        assert!(pushed > MAX_EARLY_BYTES / 2000);
+        assert_eq!(early.push(1, &packet), Err(Overflow));
+
+        // tiny packets are not free
+        let mut early = EarlyPackets::default();
+        let tiny = Packet::new(&[1], PacketMode::ReliableSequenced).unwrap();
+        assert!((0..MAX_EARLY_BYTES).any(|_| early.push(0, &tiny).is_err()));
+
+        let packets: Vec<_> = early.into_packets().collect();
+        assert!(packets.len() < MAX_EARLY_BYTES / 8);
+        assert!(packets.iter().all(|packet| packet.data == [1]));
+    }
+}
@@ -3,7 +3,10 @@ use enet_sys::{
     _ENetEventType_ENET_EVENT_TYPE_NONE, _ENetEventType_ENET_EVENT_TYPE_RECEIVE,
 };
 
-use crate::{Packet, Peer};
+use crate::{
+    Address, ChannelLayout, DisconnectReason, Packet, Peer, ResolveId, SessionId, TransferEvent,
+    TransferId,
+};
 
 /// This enum represents an event that can occur when servicing an `EnetHost`.
 ///
@@ -13,12 +16,21 @@ pub enum Event<'a, T> {
     /// This variant represents the connection of a peer, contained in the only
     /// field.
     Connect(Peer<'a, T>),
+    /// This variant represents a peer that completed the `Handshake` set on
+    /// the `Host`. It replaces `Connect` when a handshake is used.
+    Authenticated(Peer<'a, T>),
+    /// This variant represents a client that resumed its session over a new
+    /// connection, see `Sessions`. It replaces `Connect` or `Authenticated`
+    /// for that connection. On servers, the `Peer` already holds the data of
+    /// the session.
+    Resumed(Peer<'a, T>),
     /// This variant represents the disconnection of a peer, either because it
     /// was requested or due to a timeout.
     ///
     /// The disconnected peer is contained in the first field, while the second
-    /// field contains the user-specified data for this disconnection.
-    Disconnect(Peer<'a, T>, u32),
+    /// field describes why the peer was disconnected. For disconnections
+    /// requested by the remote host, it contains the user-specified data.
+    Disconnect(Peer<'a, T>, DisconnectReason),
     /// This variants repersents a packet that was received.
     Receive {
         /// The `Peer` that sent the packet.
@@ -28,6 +40,51 @@ pub enum Event<'a, T> {
         /// The `Packet` that was received.
         packet: Packet,
     },
+    /// This variant represents a session whose client timed out and did not
+    /// resume it in time, see `Sessions`.
+    SessionExpired {
+        /// The session that expired.
+        session: SessionId,
+        /// The data of the `Peer` the session belonged to.
+        data: Option<T>,
+    },
+    /// This variant represents an update of a transfer, see `Transfers`.
+    Transfer {
+        /// The `Peer` the transfer is sent to or received from.
+        peer: Peer<'a, T>,
+        /// The transfer that was updated.
+        transfer: TransferId,
+        /// What happened to the transfer.
+        event: TransferEvent,
+    },
+    /// This variant represents a hostname passed to `Host::connect_hostname`
+    /// that was resolved. The connection to `peer` is being established, and
+    /// completes with `Event::Connect` as usual.
+    Resolved {
+        /// The resolution that completed.
+        resolve: ResolveId,
+        /// The `Peer` that connects to the resolved address.
+        peer: Peer<'a, T>,
+    },
+    /// This variant represents a hostname passed to `Host::connect_hostname`
+    /// that could not be resolved.
+    ResolveFailed {
+        /// The resolution that failed.
+        resolve: ResolveId,
+        /// The hostname that was resolved.
+        hostname: String,
+    },
+    /// This variant represents a hostname passed to `Host::connect_hostname`
+    /// that was resolved, but whose connection could not be initiated because
+    /// no peer was available.
+    ConnectFailed {
+        /// The resolution whose connection failed.
+        resolve: ResolveId,
+        /// The hostname that was resolved.
+        hostname: String,
+        /// The address the hostname resolved to.
+        address: Address,
+    },
 }
 
 impl<'a, T> Event<'a, T> {
@@ -38,9 +95,10 @@ impl<'a, T> Event<'a, T> {
             _ENetEventType_ENET_EVENT_TYPE_CONNECT => {
                 Some(Event::Connect(Peer::new(event_sys.peer)))
             }
-            _ENetEventType_ENET_EVENT_TYPE_DISCONNECT => {
-                Some(Event::Disconnect(Peer::new(event_sys.peer), event_sys.data))
-            }
+            _ENetEventType_ENET_EVENT_TYPE_DISCONNECT => Some(Event::Disconnect(
+                Peer::new(event_sys.peer),
+                DisconnectReason::from_remote_code(event_sys.data),
+            )),
             _ENetEventType_ENET_EVENT_TYPE_RECEIVE => Some(Event::Receive {
                 sender: Peer::new(event_sys.peer),
                 channel_id: event_sys.channelID,
@@ -49,6 +107,17 @@ impl<'a, T> Event<'a, T> {
             _ => panic!("unrecognized event type: {}", event_sys.type_),
         }
     }
+
+    /// Returns the channel of `L` an `Event::Receive` was received on.
+    ///
+    /// Returns `None` for other events, or if the channel id is not part of
+    /// the layout.
+    pub fn channel<L: ChannelLayout>(&self) -> Option<L> {
+        match self {
+            Event::Receive { channel_id, .. } => L::from_channel_id(*channel_id),
+            _ => None,
+        }
+    }
 }
 
 impl<'a, T> Drop for Event<'a, T> {
@@ -58,7 +127,7 @@ impl<'a, T> Drop for Event<'a, T> {
         // It looks like the Peer *might* live longer, but not shorter, so it should be
         // safe to destroy the associated data (if any) here.
         if let Event::Disconnect(peer, _) = self {
-            peer.set_data(None)
+            peer.free_data()
         }
     }
 }
@@ -0,0 +1,523 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    time::{Duration, Instant},
+};
+
+use crate::{early::EarlyPackets, Event, Packet, PacketMode, Peer, PeerId, SendError};
+
+const MAGIC: &[u8; 4] = b"ENRH";
+const MSG_HELLO: u8 = 1;
+const MSG_ACCEPT: u8 = 2;
+
+const REJECT_CODE_BASE: u32 = 0xFFFF_FF00;
+const REJECT_CODE_CUSTOM_BASE: u32 = 0xFFFE_0000;
+
+/// The first message a client sends to a server that requires a handshake.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Hello {
+    /// The version of the application protocol the client speaks.
+    pub protocol_version: u32,
+    /// Opaque authentication data, e.g. a login token.
+    pub token: Vec<u8>,
+}
+
+impl Hello {
+    /// Creates a new `Hello` from a protocol version and an authentication
+    /// token.
+    pub fn new(protocol_version: u32, token: impl Into<Vec<u8>>) -> Hello {
+        Hello {
+            protocol_version,
+            token: token.into(),
+        }
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(MAGIC.len() + 5 + self.token.len());
+        data.extend_from_slice(MAGIC);
+        data.push(MSG_HELLO);
+        data.extend_from_slice(&self.protocol_version.to_be_bytes());
+        data.extend_from_slice(&self.token);
+        data
+    }
+
+    fn decode(data: &[u8]) -> Option<Hello> {
+        let rest = data.strip_prefix(MAGIC)?.strip_prefix(&[MSG_HELLO])?;
+
+        if rest.len() < 4 {
+            return None;
+        }
+
+        let (version, token) = rest.split_at(4);
+
+        Some(Hello {
+            protocol_version: u32::from_be_bytes(version.try_into().unwrap()),
+            token: token.to_vec(),
+        })
+    }
+}
+
+/// The reason a server rejected the handshake of a client.
+///
+/// Rejected clients are disconnected, with `RejectReason::code()` as the
+/// disconnect data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RejectReason {
+    /// The client did not send a valid `Hello` as its first message.
+    Malformed,
+    /// The client speaks a different protocol version than the server.
+    VersionMismatch,
+    /// The validator did not accept the client's token.
+    Unauthorized,
+    /// The handshake was not completed in time.
+    Timeout,
+    /// The client's address is not allowed to connect.
+    Banned,
+    /// The client exceeded its rate limit.
+    RateLimited,
+    /// An application-specific reason.
+    Custom(u16),
+}
+
+impl RejectReason {
+    /// Returns the disconnect data that is sent when rejecting with this
+    /// reason.
+    pub fn code(self) -> u32 {
+        match self {
+            RejectReason::Malformed => REJECT_CODE_BASE + 1,
+            RejectReason::VersionMismatch => REJECT_CODE_BASE + 2,
+            RejectReason::Unauthorized => REJECT_CODE_BASE + 3,
+            RejectReason::Timeout => REJECT_CODE_BASE + 4,
+            RejectReason::Banned => REJECT_CODE_BASE + 5,
+            RejectReason::RateLimited => REJECT_CODE_BASE + 6,
+            RejectReason::Custom(c) => REJECT_CODE_CUSTOM_BASE + u32::from(c),
+        }
+    }
+
+    /// Returns the `RejectReason` corresponding to some disconnect data, if
+    /// any.
+    pub fn from_code(code: u32) -> Option<RejectReason> {
+        match code {
+            c if c == REJECT_CODE_BASE + 1 => Some(RejectReason::Malformed),
+            c if c == REJECT_CODE_BASE + 2 => Some(RejectReason::VersionMismatch),
+            c if c == REJECT_CODE_BASE + 3 => Some(RejectReason::Unauthorized),
+            c if c == REJECT_CODE_BASE + 4 => Some(RejectReason::Timeout),
+            c if c == REJECT_CODE_BASE + 5 => Some(RejectReason::Banned),
+            c if c == REJECT_CODE_BASE + 6 => Some(RejectReason::RateLimited),
+            c if (REJECT_CODE_CUSTOM_BASE..=REJECT_CODE_CUSTOM_BASE + 0xFFFF).contains(&c) => {
+                Some(RejectReason::Custom((c - REJECT_CODE_CUSTOM_BASE) as u16))
+            }
+            _ => None,
+        }
+    }
+}
+
+type Validator<T> = Box<dyn FnMut(&mut Peer<'_, T>, &Hello) -> Result<(), RejectReason>>;
+
+enum Role<T> {
+    Client(Hello),
+    Server {
+        protocol_version: u32,
+        validator: Validator<T>,
+    },
+}
+
+#[derive(Debug)]
+enum PeerHandshake {
+    /// Waiting for the handshake to complete. On clients, `early` holds the
+    /// packets that overtook the accept message on another channel.
+    Pending {
+        since: Instant,
+        early: EarlyPackets,
+    },
+    Authenticated,
+    Rejected,
+}
+
+/// An opt-in handshake that has to be completed before a connection is handed
+/// to the application.
+///
+/// Once set on a `Host` using `Host::set_handshake`, `Event::Connect` is no
+/// longer emitted. Instead, the client sends a `Hello` as its first reliable
+/// message after connecting, which the server validates. Only if the server
+/// accepts the `Hello`, both sides emit `Event::Authenticated`.
+///
+/// Clients that fail the handshake are disconnected with a `RejectReason`
+/// code. Servers do not emit any events for clients that never completed the
+/// handshake, while clients receive the `Event::Disconnect` containing the
+/// reason code.
+///
+/// Servers reject clients that send anything before their `Hello`, so clients
+/// should wait for `Event::Authenticated` before sending. Packets a client
+/// receives before the server's accept message are held back, and handed on
+/// after `Event::Authenticated`.
+pub struct Handshake<T> {
+    role: Role<T>,
+    channel_id: u8,
+    timeout: Duration,
+    peers: HashMap<PeerId, PeerHandshake>,
+    replayed: VecDeque<(PeerId, u8, Packet)>,
+}
+
+impl<T> Handshake<T> {
+    /// Creates the client side of a handshake, which sends `hello` to each
+    /// connected server.
+    pub fn client(hello: Hello) -> Handshake<T> {
+        Handshake::new(Role::Client(hello))
+    }
+
+    /// Creates the server side of a handshake.
+    ///
+    /// Clients have to speak `protocol_version`, otherwise they are rejected
+    /// with `RejectReason::VersionMismatch`. Then, `validator` decides whether
+    /// to accept the client. It can also be used to set up the data of the
+    /// new `Peer`.
+    pub fn server<V>(protocol_version: u32, validator: V) -> Handshake<T>
+    where
+        V: FnMut(&mut Peer<'_, T>, &Hello) -> Result<(), RejectReason> + 'static,
+    {
+        Handshake::new(Role::Server {
+            protocol_version,
+            validator: Box::new(validator),
+        })
+    }
+
+    fn new(role: Role<T>) -> Handshake<T> {
+        Handshake {
+            role,
+            channel_id: 0,
+            timeout: Duration::from_secs(5),
+            peers: HashMap::new(),
+            replayed: VecDeque::new(),
+        }
+    }
+
+    /// Sets the channel the handshake is performed on, defaults to `0`.
+    pub fn with_channel(mut self, channel_id: u8) -> Handshake<T> {
+        self.channel_id = channel_id;
+        self
+    }
+
+    /// Sets how long a peer may take to complete the handshake after
+    /// connecting, defaults to 5 seconds.
+    pub fn with_timeout(mut self, timeout: Duration) -> Handshake<T> {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Returns the ids of all peers whose handshake timed out, and marks them
+    /// as rejected.
+    pub(crate) fn expire(&mut self, now: Instant) -> Vec<PeerId> {
+        let mut expired = Vec::new();
+
+        for (id, state) in self.peers.iter_mut() {
+            if let PeerHandshake::Pending { since, .. } = state {
+                if now.duration_since(*since) >= self.timeout {
+                    *state = PeerHandshake::Rejected;
+                    expired.push(*id);
+                }
+            }
+        }
+
+        expired
+    }
+
+    /// Returns the next packet that a client received from a server before
+    /// its handshake completed, once it did.
+    pub(crate) fn pop_replayed(&mut self) -> Option<(PeerId, u8, Packet)> {
+        self.replayed.pop_front()
+    }
+
+    /// Runs the handshake for an event, returning the event that should be
+    /// handed to the application instead, if any.
+    pub(crate) fn process_event<'a>(&mut self, event: Event<'a, T>) -> Option<Event<'a, T>> {
+        match &event {
+            Event::Connect(peer) => {
+                let mut peer = peer.clone();
+
+                if let Role::Client(hello) = &self.role {
+                    let sent = Packet::new(&hello.encode(), PacketMode::ReliableSequenced)
+                        .map_err(SendError::from)
+                        .and_then(|packet| peer.try_send_packet(packet, self.channel_id));
+
+                    if sent.is_err() {
+                        peer.disconnect(RejectReason::Malformed.code());
+                    }
+                }
+
+                let pending = PeerHandshake::Pending {
+                    since: Instant::now(),
+                    early: EarlyPackets::default(),
+                };
+                self.peers.insert(peer.id(), pending);
+
+                None
+            }
+            Event::Disconnect(peer, _) => {
+                let id = peer.id();
+                let state = self.peers.remove(&id);
+                self.replayed.retain(|(replayed, ..)| *replayed != id);
+
+                match (&self.role, state) {
+                    (Role::Client(_), _) | (_, Some(PeerHandshake::Authenticated)) => Some(event),
+                    _ => None,
+                }
+            }
+            Event::Receive {
+                sender,
+                channel_id,
+                packet,
+            } => {
+                let id = sender.id();
+
+                match self.peers.get(&id) {
+                    Some(PeerHandshake::Authenticated) => return Some(event),
+                    Some(PeerHandshake::Pending { .. }) => (),
+                    Some(PeerHandshake::Rejected) | None => return None,
+                }
+
+                let mut peer = sender.clone();
+
+                let result = match &mut self.role {
+                    Role::Client(_) => {
+                        if *channel_id == self.channel_id && is_accept(packet.data()) {
+                            Ok(())
+                        } else {
+                            // might have overtaken the accept message on another channel
+                            if let Some(PeerHandshake::Pending { early, .. }) =
+                                self.peers.get_mut(&id)
+                            {
+                                if early.push(*channel_id, packet).is_err() {
+                                    self.peers.insert(id, PeerHandshake::Rejected);
+                                    peer.disconnect(RejectReason::RateLimited.code());
+                                }
+                            }
+
+                            return None;
+                        }
+                    }
+                    Role::Server {
+                        protocol_version,
+                        validator,
+                    } => match Hello::decode(packet.data()) {
+                        Some(_) if *channel_id != self.channel_id => Err(RejectReason::Malformed),
+                        Some(hello) if hello.protocol_version != *protocol_version => {
+                            Err(RejectReason::VersionMismatch)
+                        }
+                        Some(hello) => validator(&mut peer, &hello).and_then(|()| {
+                            Packet::new(&accept_message(), PacketMode::ReliableSequenced)
+                                .map_err(SendError::from)
+                                .and_then(|packet| peer.try_send_packet(packet, self.channel_id))
+                                .map_err(|_| RejectReason::Malformed)
+                        }),
+                        None => Err(RejectReason::Malformed),
+                    },
+                };
+
+                match result {
+                    Ok(()) => {
+                        let state = self.peers.insert(id, PeerHandshake::Authenticated);
+
+                        if let Some(PeerHandshake::Pending { early, .. }) = state {
+                            self.replayed
+                                .extend(early.into_packets().filter_map(|packet| {
+                                    // an allocation failure loses the packet
+                                    let replayed = Packet::new(&packet.data, packet.mode).ok()?;
+                                    Some((id, packet.channel_id, replayed))
+                                }));
+                        }
+
+                        Some(Event::Authenticated(peer))
+                    }
+                    Err(reason) => {
+                        self.peers.insert(id, PeerHandshake::Rejected);
+                        peer.disconnect(reason.code());
+                        None
+                    }
+                }
+            }
+            Event::Authenticated(_)
+            | Event::Resumed(_)
+            | Event::SessionExpired { .. }
+            | Event::Transfer { .. }
+            | Event::Resolved { .. }
+            | Event::ResolveFailed { .. }
+            | Event::ConnectFailed { .. } => Some(event),
+        }
+    }
+}
+
+fn accept_message() -> Vec<u8> {
+    let mut data = MAGIC.to_vec();
+    data.push(MSG_ACCEPT);
+    data
+}
+
+fn is_accept(data: &[u8]) -> bool {
+    data.strip_prefix(MAGIC) == Some(&[MSG_ACCEPT])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Handshake, Hello, RejectReason};
+    use crate::{
+        tests::{create_host, create_server},
+        DisconnectReason, Event, Host, Packet, PacketMode, Peer,
+    };
+
+    #[derive(Debug, PartialEq)]
+    enum Seen {
+        Connect,
+        Authenticated,
+        Disconnect(DisconnectReason),
+    }
+
+    fn seen(event: Option<Event<'_, String>>) -> Option<Seen> {
+        match event? {
+            Event::Connect(_) => Some(Seen::Connect),
+            Event::Authenticated(_) => Some(Seen::Authenticated),
+            Event::Disconnect(_, reason) => Some(Seen::Disconnect(reason)),
+            Event::Receive { .. }
+            | Event::Resumed(_)
+            | Event::SessionExpired { .. }
+            | Event::Transfer { .. }
+            | Event::Resolved { .. }
+            | Event::ResolveFailed { .. }
+            | Event::ConnectFailed { .. } => None,
+        }
+    }
+
+    fn run(server: &mut Host<String>, client: &mut Host<String>) -> (Vec<Seen>, Vec<Seen>) {
+        let (mut server_seen, mut client_seen) = (Vec::new(), Vec::new());
+
+        for _ in 0..100 {
+            server_seen.extend(seen(server.service(5).unwrap()));
+            client_seen.extend(seen(client.service(5).unwrap()));
+
+            if client_seen.iter().any(|s| *s != Seen::Connect) {
+                break;
+            }
+        }
+
+        (server_seen, client_seen)
+    }
+
+    fn validator(peer: &mut crate::Peer<'_, String>, hello: &Hello) -> Result<(), RejectReason> {
+        if hello.token == b"secret" {
+            peer.set_data(Some("felix".to_string()));
+            Ok(())
+        } else {
+            Err(RejectReason::Unauthorized)
+        }
+    }
+
+    #[test]
+    fn test_handshake_accepted() {
+        let mut server: Host<String> = create_server(4);
+        let address = server.address();
+        server.set_handshake(Handshake::server(3, validator));
+
+        let mut client: Host<String> = create_host(None, 4);
+        client.set_handshake(Handshake::client(Hello::new(3, "secret")));
+        client.connect(&address, 1, 0).unwrap();
+
+        let (mut server_seen, client_seen) = run(&mut server, &mut client);
+        server_seen.extend(seen(server.service(5).unwrap()));
+
+        assert_eq!(client_seen, vec![Seen::Authenticated]);
+        assert_eq!(server_seen, vec![Seen::Authenticated]);
+        assert!(server
+            .peers()
+            .any(|peer| peer.data() == Some(&"felix".to_string())));
+    }
+
+    #[test]
+    fn test_handshake_rejected() {
+        let mut server: Host<String> = create_server(4);
+        let address = server.address();
+        server.set_handshake(Handshake::server(3, validator));
+
+        for (hello, reason) in [
+            (Hello::new(2, "secret"), RejectReason::VersionMismatch),
+            (Hello::new(3, "guess"), RejectReason::Unauthorized),
+        ] {
+            let mut client: Host<String> = create_host(None, 4);
+            client.set_handshake(Handshake::client(hello));
+            client.connect(&address, 1, 0).unwrap();
+
+            let (server_seen, client_seen) = run(&mut server, &mut client);
+
+            assert_eq!(
+                client_seen,
+                vec![Seen::Disconnect(DisconnectReason::Rejected(reason))]
+            );
+            assert!(server_seen.is_empty());
+        }
+    }
+
+    #[test]
+    fn test_early_packets_replayed() {
+        let mut server: Host<String> = create_server(4);
+        let address = server.address();
+        server.set_handshake(Handshake::server(
+            3,
+            |peer: &mut Peer<'_, String>, _: &Hello| {
+                // sent before the accept message, so it always overtakes it
+                let packet = Packet::new(b"welcome", PacketMode::ReliableSequenced).unwrap();
+                peer.send_packet(packet, 1).unwrap();
+                Ok(())
+            },
+        ));
+
+        let mut client: Host<String> = create_host(None, 4);
+        client.set_handshake(Handshake::client(Hello::new(3, "secret")));
+        client.connect(&address, 2, 0).unwrap();
+
+        let mut client_seen = Vec::new();
+
+        for _ in 0..100 {
+            if let Some(Event::Authenticated(peer)) = &server.service(5).unwrap() {
+                let packet = Packet::new(b"hello", PacketMode::ReliableSequenced).unwrap();
+                peer.clone().send_packet(packet, 1).unwrap();
+            }
+
+            match &client.service(5).unwrap() {
+                Some(Event::Authenticated(_)) => client_seen.push((0, b"accept".to_vec())),
+                Some(Event::Receive {
+                    channel_id, packet, ..
+                }) => client_seen.push((*channel_id, packet.data().to_vec())),
+                _ => (),
+            }
+
+            if client_seen.len() == 3 {
+                break;
+            }
+        }
+
+        assert_eq!(
+            client_seen,
+            vec![
+                (0, b"accept".to_vec()),
+                (1, b"welcome".to_vec()),
+                (1, b"hello".to_vec()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_reject_reason_codes() {
+        for reason in [
+            RejectReason::Malformed,
+            RejectReason::VersionMismatch,
+            RejectReason::Unauthorized,
+            RejectReason::Timeout,
+            RejectReason::Banned,
+            RejectReason::RateLimited,
+            RejectReason::Custom(0),
+            RejectReason::Custom(u16::MAX),
+        ] {
+            assert_eq!(RejectReason::from_code(reason.code()), Some(reason));
+        }
+
+        assert_eq!(RejectReason::from_code(0), None);
+    }
+}
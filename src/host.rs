@@ -1,12 +1,39 @@
-use std::{marker::PhantomData, mem::MaybeUninit, sync::Arc};
+use std::{
+    hash::Hash,
+    io::{self, Read, Write},
+    marker::PhantomData,
+    mem::MaybeUninit,
+    net::UdpSocket,
+    os::raw::c_int,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use enet_sys::{
     enet_host_bandwidth_limit, enet_host_channel_limit, enet_host_check_events, enet_host_connect,
-    enet_host_destroy, enet_host_flush, enet_host_service, ENetHost, ENetPeer,
-    ENET_PROTOCOL_MAXIMUM_CHANNEL_COUNT,
+    enet_host_create, enet_host_destroy, enet_host_flush, enet_host_service, enet_socket_destroy,
+    ENetEvent, ENetHost, ENetPeer, ENetSocket, ENET_HOST_RECEIVE_BUFFER_SIZE,
+    ENET_HOST_SEND_BUFFER_SIZE, ENET_PROTOCOL_MAXIMUM_CHANNEL_COUNT,
 };
 
-use crate::{Address, EnetKeepAlive, Error, Event, Peer};
+#[cfg(feature = "crypto")]
+use crate::Encryption;
+#[cfg(feature = "serde")]
+use crate::Rpc;
+use crate::{
+    admission::AdmissionControl,
+    conditioner,
+    disconnect::PeerSnapshots,
+    discovery,
+    resolve::{PendingConnect, Resolver},
+    room::RoomRegistry,
+    socket::{self, SocketError, SocketOption, SocketOptionKind},
+    Address, AddressParseError, AdmissionPolicy, ChannelLayout, ClockSync, CreateHostError,
+    DisconnectCode, Discovery, Enet, EnetKeepAlive, Error, Event, Handshake, HostConfig,
+    NetworkConditioner, Packet, Peer, PeerId, PeerState, RateLimiter, RejectReason, ResolveId,
+    RoomError, Rooms, SendTransferError, SessionError, SessionId, Sessions, SnapshotError,
+    Snapshots, ToAddress, TransferId, TransferProgress, Transfers,
+};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 /// Represents a bandwidth limit or unlimited.
@@ -23,19 +50,19 @@ pub enum ChannelLimit {
     /// Maximum limit on the number of channels
     Maximum,
     /// Channel limit
-    Limited(enet_sys::size_t),
+    Limited(usize),
 }
 
 impl ChannelLimit {
-    pub(in crate) fn to_enet_val(self) -> enet_sys::size_t {
+    pub(in crate) fn to_enet_val(self) -> usize {
         match self {
             ChannelLimit::Maximum => 0,
             ChannelLimit::Limited(l) => l,
         }
     }
 
-    fn from_enet_val(enet_val: enet_sys::size_t) -> ChannelLimit {
-        const MAX_COUNT: enet_sys::size_t = ENET_PROTOCOL_MAXIMUM_CHANNEL_COUNT as enet_sys::size_t;
+    fn from_enet_val(enet_val: usize) -> ChannelLimit {
+        const MAX_COUNT: usize = ENET_PROTOCOL_MAXIMUM_CHANNEL_COUNT as usize;
         match enet_val {
             MAX_COUNT => ChannelLimit::Maximum,
             0 => panic!("ChannelLimit::from_enet_usize: got 0"),
@@ -53,6 +80,34 @@ impl BandwidthLimit {
     }
 }
 
+/// An error that can occur when initiating a connection using `Host::connect`.
+#[derive(thiserror::Error, Debug)]
+pub enum ConnectError {
+    /// The address could not be converted into an `Address`.
+    #[error("invalid address")]
+    InvalidAddress(#[from] AddressParseError),
+    /// ENet failed to initiate the connection, because no peer was available.
+    #[error("failed to initiate the connection")]
+    Enet(#[from] Error),
+}
+
+/// The outcome of `Host::shutdown`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct ShutdownReport {
+    /// The peers that completed the disconnection before the deadline.
+    pub disconnected: Vec<PeerId>,
+    /// The peers that had to be reset, including those that were still
+    /// connecting. They will time out on their side.
+    pub reset: Vec<PeerId>,
+}
+
+impl ShutdownReport {
+    /// Returns whether all peers disconnected cleanly.
+    pub fn is_clean(&self) -> bool {
+        self.reset.is_empty()
+    }
+}
+
 /// A `Host` represents one endpoint of an ENet connection. Created through
 /// `Enet`.
 ///
@@ -60,6 +115,22 @@ impl BandwidthLimit {
 /// transmission.
 pub struct Host<T> {
     inner: *mut ENetHost,
+    rate_limiter: Option<RateLimiter>,
+    admission: Option<AdmissionControl>,
+    handshake: Option<Handshake<T>>,
+    sessions: Option<Sessions<T>>,
+    transfers: Option<Transfers>,
+    snapshots: Option<Snapshots>,
+    clock_sync: Option<ClockSync>,
+    rooms: Option<Box<dyn RoomRegistry>>,
+    discovery: Option<Discovery>,
+    resolver: Resolver,
+    #[cfg(feature = "serde")]
+    rpc: Option<Rpc<T>>,
+    #[cfg(feature = "crypto")]
+    encryption: Option<Encryption>,
+    peer_snapshots: PeerSnapshots,
+    conditioner: Option<Box<NetworkConditioner>>,
 
     _keep_alive: Arc<EnetKeepAlive>,
     _peer_data: PhantomData<*const T>,
@@ -71,18 +142,104 @@ impl<T> Host<T> {
 
         Host {
             inner,
+            rate_limiter: None,
+            admission: None,
+            handshake: None,
+            sessions: None,
+            transfers: None,
+            snapshots: None,
+            clock_sync: None,
+            rooms: None,
+            discovery: None,
+            resolver: Resolver::default(),
+            #[cfg(feature = "serde")]
+            rpc: None,
+            #[cfg(feature = "crypto")]
+            encryption: None,
+            peer_snapshots: PeerSnapshots::default(),
+            conditioner: None,
             _keep_alive,
             _peer_data: PhantomData,
         }
     }
 
+    /// Creates a `Host` that uses an existing, bound UDP socket, for example
+    /// one passed in by socket activation, instead of creating its own.
+    ///
+    /// The address of `config` is ignored, the host keeps the address the
+    /// socket is bound to. The socket is made non-blocking, and the socket
+    /// options of `config` are set after ENet's defaults. Fails if the socket
+    /// is not bound to an IPv4 address, which is all ENet supports.
+    pub fn from_udp_socket(
+        enet: &Enet,
+        socket: UdpSocket,
+        config: &HostConfig,
+    ) -> Result<Host<T>, CreateHostError> {
+        if !socket.local_addr().is_ok_and(|address| address.is_ipv4()) {
+            return Err(CreateHostError::UnsupportedAddress);
+        }
+
+        let inner = unsafe {
+            enet_host_create(
+                std::ptr::null(),
+                config.max_peer_count,
+                config.max_channel_count.to_enet_val(),
+                config.incoming_bandwidth.to_enet_u32(),
+                config.outgoing_bandwidth.to_enet_u32(),
+            )
+        };
+
+        if inner.is_null() {
+            return Err(CreateHostError::Create);
+        }
+
+        #[cfg(unix)]
+        let raw = std::os::fd::IntoRawFd::into_raw_fd(socket);
+        #[cfg(windows)]
+        let raw = std::os::windows::io::IntoRawSocket::into_raw_socket(socket) as ENetSocket;
+
+        // from here on, the host owns the socket and closes it on drop
+        let mut host = Host::new(enet.keep_alive.clone(), inner);
+        host.replace_socket(raw)?;
+
+        for &option in &config.socket_options {
+            host.set_socket_option(option)?;
+        }
+
+        Ok(host)
+    }
+
+    /// Replaces the socket ENet created for this host with `socket`, applying
+    /// the options ENet sets on its own sockets.
+    fn replace_socket(&mut self, socket: ENetSocket) -> Result<(), SocketError> {
+        unsafe {
+            enet_socket_destroy((*self.inner).socket);
+            (*self.inner).socket = socket;
+        }
+
+        self.set_socket_option(SocketOption::NonBlocking(true))?;
+        self.set_socket_option(SocketOption::Broadcast(true))?;
+        self.set_socket_option(SocketOption::ReceiveBuffer(ENET_HOST_RECEIVE_BUFFER_SIZE))?;
+        self.set_socket_option(SocketOption::SendBuffer(ENET_HOST_SEND_BUFFER_SIZE))?;
+
+        let address = socket::local_address(socket)?;
+        unsafe {
+            (*self.inner).address = address.to_enet_address();
+        }
+
+        Ok(())
+    }
+
     /// Sends any queued packets on the host specified to its designated peers.
     ///
     /// This function need only be used in circumstances where one wishes to
     /// send queued packets earlier than in a call to `Host::service()`.
     pub fn flush(&mut self) {
-        unsafe {
-            enet_host_flush(self.inner);
+        let inner = self.inner;
+
+        match self.conditioner.as_deref_mut() {
+            Some(conditioner) => conditioner.condition(inner, || unsafe { enet_host_flush(inner) }),
+            None => unsafe { enet_host_flush(inner) },
         }
     }
 
@@ -123,63 +280,770 @@ impl<T> Host<T> {
         unsafe { (*self.inner).outgoingBandwidth }
     }
 
-    /// Returns the internet address of this `Host`.
+    /// Returns the internet address this `Host` is bound to.
+    ///
+    /// The address is queried from the socket, so a host created with port 0
+    /// reports the port assigned by the operating system.
     pub fn address(&self) -> Address {
-        Address::from_enet_address(&unsafe { (*self.inner).address })
+        let inner = unsafe { &*self.inner };
+
+        socket::local_address(inner.socket)
+            .unwrap_or_else(|_| Address::from_enet_address(&inner.address))
+    }
+
+    /// Sets an option on the socket of this host.
+    pub fn set_socket_option(&mut self, option: SocketOption) -> Result<(), SocketError> {
+        socket::set_option(unsafe { (*self.inner).socket }, option)
+    }
+
+    /// Reads the current value of an option of the socket of this host, see
+    /// `Socket::option`.
+    ///
+    /// [`Socket::option`]: crate::socket::Socket::option
+    pub fn socket_option(&self, kind: SocketOptionKind) -> Result<SocketOption, SocketError> {
+        socket::get_option(unsafe { (*self.inner).socket }, kind)
+    }
+
+    /// Sets the options of `config` on the socket of this host, which must not
+    /// be bound yet, and binds it to the address of `config`, if any.
+    pub(crate) fn configure_socket(&mut self, config: &HostConfig) -> Result<(), SocketError> {
+        let socket = unsafe { (*self.inner).socket };
+
+        for &option in &config.socket_options {
+            socket::set_option(socket, option)?;
+        }
+
+        if let Some(Ok(address)) = &config.address {
+            socket::bind(socket, address)?;
+
+            let bound = socket::local_address(socket).unwrap_or_else(|_| address.clone());
+            unsafe {
+                (*self.inner).address = bound.to_enet_address();
+            }
+        }
+
+        Ok(())
     }
 
     /// Returns the number of peers allocated for this `Host`.
-    pub fn peer_count(&self) -> enet_sys::size_t {
+    pub fn peer_count(&self) -> usize {
         unsafe { (*self.inner).peerCount }
     }
 
     /// Returns an iterator over all peers connected to this `Host`.
     pub fn peers(&'_ mut self) -> impl Iterator<Item = Peer<'_, T>> {
-        // this should only fail on 32-bit platfroms when `size_t` is 64-bit, which
-        // should be super rare
-        let peer_count = unsafe { (*self.inner).peerCount.try_into().expect("too many peers") };
+        let peer_count = unsafe { (*self.inner).peerCount };
 
         let raw_peers = unsafe { std::slice::from_raw_parts_mut((*self.inner).peers, peer_count) };
 
         raw_peers.iter_mut().map(|rp| Peer::new(rp))
     }
 
+    /// Returns the peer with the given id, if it exists.
+    pub fn peer(&'_ mut self, id: PeerId) -> Option<Peer<'_, T>> {
+        if id.0 < self.peer_count() {
+            Some(Peer::new(unsafe { (*self.inner).peers.add(id.0) }))
+        } else {
+            None
+        }
+    }
+
+    /// Sets the maximum number of peers that may connect from the same IP
+    /// address.
+    ///
+    /// This is enforced by ENet before a connection attempt occupies a peer
+    /// slot. Defaults to the maximum peer count.
+    pub fn set_duplicate_peers(&mut self, limit: usize) {
+        unsafe {
+            (*self.inner).duplicatePeers = limit;
+        }
+    }
+
+    /// Returns the maximum number of peers that may connect from the same IP
+    /// address.
+    pub fn duplicate_peers(&self) -> usize {
+        unsafe { (*self.inner).duplicatePeers }
+    }
+
+    /// Sets the `RateLimiter` that limits the traffic received from each
+    /// peer.
+    pub fn set_rate_limiter(&mut self, rate_limiter: RateLimiter) {
+        self.rate_limiter = Some(rate_limiter);
+    }
+
+    /// Sets the `AdmissionPolicy` that decides about incoming connections.
+    ///
+    /// Should be set before any connections are made.
+    pub fn set_admission_policy<P: AdmissionPolicy + 'static>(&mut self, policy: P) {
+        self.admission = Some(AdmissionControl::new(Box::new(policy)));
+    }
+
+    /// Sets how long a connection deferred by the `AdmissionPolicy` may wait
+    /// for `Host::admit` or `Host::reject`, defaults to 10 seconds. Deferred
+    /// peers are disconnected with `RejectReason::Timeout` afterwards.
+    ///
+    /// Has to be called after `Host::set_admission_policy`, which resets it.
+    pub fn set_defer_timeout(&mut self, timeout: Duration) {
+        if let Some(admission) = &mut self.admission {
+            admission.set_timeout(timeout);
+        }
+    }
+
+    /// Accepts a connection that was deferred by the `AdmissionPolicy`.
+    /// `Event::Connect` will be emitted for it by the next call to
+    /// `Host::service`, followed by the packets it sent in the meantime.
+    ///
+    /// Returns `false` if the peer's connection was not deferred.
+    pub fn admit(&mut self, id: PeerId) -> bool {
+        self.admission
+            .as_mut()
+            .is_some_and(|admission| admission.admit(id))
+    }
+
+    /// Rejects a connection that was deferred by the `AdmissionPolicy`,
+    /// disconnecting the peer immediately with `user_data`.
+    ///
+    /// Returns `false` if the peer's connection was not deferred.
+    pub fn reject(&mut self, id: PeerId, user_data: u32) -> bool {
+        let rejected = self
+            .admission
+            .as_mut()
+            .is_some_and(|admission| admission.reject(id));
+
+        if rejected {
+            self.drop_deferred(id, user_data);
+        }
+
+        rejected
+    }
+
+    /// Disconnects a deferred peer immediately. No event is emitted for it,
+    /// so its data is freed right away.
+    fn drop_deferred(&mut self, id: PeerId, user_data: u32) {
+        let mut peer = self.raw_peer(id);
+        peer.free_data();
+        peer.disconnect_now(user_data);
+    }
+
+    /// Sets the `Handshake` peers have to complete before they are handed to
+    /// the application.
+    ///
+    /// Should be set before any connections are made.
+    pub fn set_handshake(&mut self, handshake: Handshake<T>) {
+        self.handshake = Some(handshake);
+    }
+
+    /// Sets the `Sessions` layer that lets clients resume their session after
+    /// a brief loss of connection.
+    ///
+    /// Should be set before any connections are made.
+    pub fn set_sessions(&mut self, sessions: Sessions<T>) {
+        self.sessions = Some(sessions);
+    }
+
+    /// Sends a packet to the client of `session`, see `Sessions`.
+    ///
+    /// While the client is away, reliable packets are queued until it resumes
+    /// the session, and unreliable packets are dropped. Fails if the session
+    /// does not exist, or too many packets are queued.
+    pub fn send_to_session(
+        &mut self,
+        session: SessionId,
+        channel_id: u8,
+        packet: Packet,
+    ) -> Result<(), SessionError> {
+        match &mut self.sessions {
+            Some(sessions) => sessions.send(self.inner, session, channel_id, packet),
+            None => Err(SessionError::NotEnabled),
+        }
+    }
+
+    /// Sets the `Transfers` layer that streams large amounts of data over a
+    /// dedicated channel.
+    pub fn set_transfers(&mut self, transfers: Transfers) {
+        self.transfers = Some(transfers);
+    }
+
+    /// Offers a transfer of `size` bytes read from `source` to the peer `id`,
+    /// see `Transfers`.
+    ///
+    /// Fails if no `Transfers` layer is set, or the offer can not be sent.
+    pub fn send_transfer<R: Read + 'static>(
+        &mut self,
+        id: PeerId,
+        source: R,
+        size: u64,
+    ) -> Result<TransferId, SendTransferError> {
+        if id.0 >= self.peer_count() {
+            return Err(SendTransferError::UnknownPeer);
+        }
+
+        let mut peer = self.raw_peer(id);
+
+        match &mut self.transfers {
+            Some(transfers) => transfers.start(&mut peer, Box::new(source), size),
+            None => Err(SendTransferError::NotEnabled),
+        }
+    }
+
+    /// Accepts an offered transfer, writing the received data to `sink`.
+    ///
+    /// Returns `false` if the transfer was not offered, or already accepted.
+    pub fn accept_transfer<W: Write + 'static>(&mut self, transfer: TransferId, sink: W) -> bool {
+        let inner = self.inner;
+
+        self.transfers
+            .as_mut()
+            .is_some_and(|transfers| transfers.accept::<T>(inner, transfer, Box::new(sink)))
+    }
+
+    /// Cancels or declines a transfer. The other side gets a
+    /// `TransferError::Cancelled`.
+    ///
+    /// Returns `false` if the transfer does not exist.
+    pub fn cancel_transfer(&mut self, transfer: TransferId) -> bool {
+        let inner = self.inner;
+
+        self.transfers
+            .as_mut()
+            .is_some_and(|transfers| transfers.cancel::<T>(inner, transfer))
+    }
+
+    /// Returns the progress of a transfer, if it exists.
+    pub fn transfer_progress(&self, transfer: TransferId) -> Option<TransferProgress> {
+        self.transfers.as_ref()?.progress(transfer)
+    }
+
+    /// Sets the `Snapshots` layer that sends snapshots as deltas against the
+    /// last acknowledged one.
+    pub fn set_snapshots(&mut self, snapshots: Snapshots) {
+        self.snapshots = Some(snapshots);
+    }
+
+    /// Sends a snapshot of `state` to the peer `id`, see `Snapshots`.
+    ///
+    /// Fails if no `Snapshots` layer is set, or the snapshot can not be sent.
+    pub fn send_snapshot(&mut self, id: PeerId, state: &[u8]) -> Result<(), SnapshotError> {
+        if id.0 >= self.peer_count() {
+            return Err(SnapshotError::UnknownPeer);
+        }
+
+        let mut peer = self.raw_peer(id);
+
+        match &mut self.snapshots {
+            Some(snapshots) => snapshots.send(&mut peer, state),
+            None => Err(SnapshotError::NotEnabled),
+        }
+    }
+
+    /// Sets the `ClockSync` layer that synchronizes the clocks of clients
+    /// with their server.
+    pub fn set_clock_sync(&mut self, clock_sync: ClockSync) {
+        self.clock_sync = Some(clock_sync);
+    }
+
+    /// Returns the `ClockSync` layer of this host, if one is set.
+    pub fn clock_sync(&self) -> Option<&ClockSync> {
+        self.clock_sync.as_ref()
+    }
+
+    /// Sets the `Rooms` registry used by `Host::broadcast_room`, replacing any
+    /// previous one.
+    pub fn set_rooms<R: Eq + Hash + Clone + 'static>(&mut self, rooms: Rooms<R>) {
+        self.rooms = Some(Box::new(rooms));
+    }
+
+    /// Returns the `Rooms` registry of this host, if one with rooms of type
+    /// `R` is set.
+    pub fn rooms<R: 'static>(&self) -> Option<&Rooms<R>> {
+        self.rooms.as_ref()?.as_any().downcast_ref()
+    }
+
+    /// Returns the `Rooms` registry of this host mutably, if one with rooms of
+    /// type `R` is set.
+    pub fn rooms_mut<R: 'static>(&mut self) -> Option<&mut Rooms<R>> {
+        self.rooms.as_mut()?.as_any_mut().downcast_mut()
+    }
+
+    /// Sets the `Discovery` that answers LAN discovery probes on the port of
+    /// this host, replacing any previous one.
+    pub fn set_discovery(&mut self, discovery: Discovery) {
+        self.discovery = Some(discovery);
+
+        unsafe {
+            (*self.inner).intercept = Some(intercept);
+        }
+    }
+
+    /// Returns the `Discovery` of this host, if one is set, for example to
+    /// update its info.
+    pub fn discovery_mut(&mut self) -> Option<&mut Discovery> {
+        self.discovery.as_mut()
+    }
+
+    /// Queues `packet` to be sent to all connected members of `room`, see
+    /// `Rooms`. Returns the number of peers it was queued for.
+    ///
+    /// The packet is shared between all recipients instead of being copied,
+    /// unless they use encryption. Fails if no `Rooms` registry with rooms of
+    /// type `R` is set.
+    pub fn broadcast_room<R: Eq + Hash + Clone + 'static>(
+        &mut self,
+        room: &R,
+        packet: Packet,
+        channel_id: u8,
+    ) -> Result<usize, RoomError> {
+        let members: Vec<PeerId> = match self.rooms::<R>() {
+            Some(rooms) => rooms.members(room).collect(),
+            None => return Err(RoomError::NotEnabled),
+        };
+
+        let mut sent = 0;
+
+        for id in members {
+            let mut peer = self.raw_peer(id);
+
+            if peer.state() == PeerState::Connected && peer.send_shared(&packet, channel_id).is_ok()
+            {
+                sent += 1;
+            }
+        }
+
+        // the recipients own the packet now, ENet destroys it once it is sent
+        if packet.is_referenced() {
+            packet.into_inner();
+        }
+
+        Ok(sent)
+    }
+
+    /// Sets the `Rpc` layer that answers requests from peers and resolves
+    /// the responses to `Peer::call`.
+    ///
+    /// Should be set before any connections are made.
+    #[cfg(feature = "serde")]
+    pub fn set_rpc(&mut self, rpc: Rpc<T>) {
+        self.rpc = Some(rpc);
+    }
+
+    /// Enables encryption of all traffic of this `Host`.
+    ///
+    /// Should be set before any connections are made. See `Encryption` for
+    /// more information.
+    #[cfg(feature = "crypto")]
+    pub fn set_encryption(&mut self, encryption: Encryption) {
+        self.encryption = Some(encryption);
+    }
+
+    /// Attaches a `NetworkConditioner` that simulates a lossy network for the
+    /// traffic of this host, replacing any previous one.
+    ///
+    /// Fails if the socket used to re-inject delayed datagrams can not be
+    /// created.
+    pub fn set_network_conditioner(
+        &mut self,
+        mut conditioner: NetworkConditioner,
+    ) -> Result<(), io::Error> {
+        conditioner.attach(self.inner)?;
+
+        self.conditioner = Some(Box::new(conditioner));
+
+        unsafe {
+            (*self.inner).intercept = Some(intercept);
+        }
+
+        Ok(())
+    }
+
     /// Maintains this host and delivers an event if available.
     ///
     /// This should be called regularly for ENet to work properly with good
     /// performance.
     pub fn service(&'_ mut self, timeout_ms: u32) -> Result<Option<Event<'_, T>>, Error> {
-        // ENetEvent is Copy (aka has no Drop impl), so we don't have to make sure we
-        // `mem::forget` it later on
-        let mut sys_event = MaybeUninit::uninit();
+        let deadline = Instant::now() + Duration::from_millis(timeout_ms.into());
+        let mut timeout_ms = timeout_ms;
+
+        loop {
+            self.expire_pending();
+            self.pump();
+
+            if let Some(event) = self.next_queued_event() {
+                return Ok(Some(event));
+            }
+
+            // ENetEvent is Copy (aka has no Drop impl), so we don't have to make sure we
+            // `mem::forget` it later on
+            let mut sys_event = MaybeUninit::uninit();
+
+            self.peer_snapshots.capture(self.inner);
 
-        let res = unsafe { enet_host_service(self.inner, sys_event.as_mut_ptr(), timeout_ms) };
+            // wake up in time to re-inject delayed datagrams, send clock probes
+            // and connect to resolved hostnames
+            let due = [
+                self.conditioner.as_ref().and_then(|c| c.next_due()),
+                self.clock_sync.as_ref().and_then(|c| c.next_due()),
+                self.resolver.next_due(),
+            ];
+            let wait_ms = match due.into_iter().flatten().min() {
+                Some(due) => timeout_ms.min(millis_until(due)),
+                None => timeout_ms,
+            };
 
-        match res {
-            r if r > 0 => Ok(Event::from_sys_event(unsafe { &sys_event.assume_init() })),
-            0 => Ok(None),
-            r if r < 0 => Err(Error(r)),
-            _ => panic!("unreachable"),
+            let res = self.raw_service(sys_event.as_mut_ptr(), wait_ms);
+
+            let sys_event = match res {
+                r if r > 0 => Some(unsafe { sys_event.assume_init() }),
+                0 if wait_ms == timeout_ms => return Ok(None),
+                0 => None,
+                r if r < 0 => return Err(Error(r)),
+                _ => panic!("unreachable"),
+            };
+
+            if let Some(sys_event) = sys_event {
+                if let Some(event) = self.process_sys_event(&sys_event) {
+                    return Ok(Some(event));
+                }
+            }
+
+            // the event was consumed internally, keep waiting for the remaining time
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            timeout_ms = remaining.as_millis().try_into().unwrap_or(u32::MAX);
         }
 
         // TODO: check `total*` fields on `inner`, these need to be reset from
         // time to time.
     }
 
+    /// Calls `enet_host_service`, with the `NetworkConditioner` and the
+    /// `Discovery` active if there are any.
+    fn raw_service(&mut self, sys_event: *mut ENetEvent, timeout_ms: u32) -> c_int {
+        let inner = self.inner;
+
+        Discovery::with_active(self.discovery.as_ref(), || {
+            match self.conditioner.as_deref_mut() {
+                Some(conditioner) => conditioner.condition(inner, || unsafe {
+                    enet_host_service(inner, sys_event, timeout_ms)
+                }),
+                None => unsafe { enet_host_service(inner, sys_event, timeout_ms) },
+            }
+        })
+    }
+
     /// Checks for any queued events on this `Host` and dispatches one if
     /// available
     pub fn check_events(&'_ mut self) -> Result<Option<Event<'_, T>>, Error> {
-        // ENetEvent is Copy (aka has no Drop impl), so we don't have to make sure we
-        // `mem::forget` it later on
-        let mut sys_event = MaybeUninit::uninit();
+        loop {
+            self.pump();
+
+            if let Some(event) = self.next_queued_event() {
+                return Ok(Some(event));
+            }
+
+            // ENetEvent is Copy (aka has no Drop impl), so we don't have to make sure we
+            // `mem::forget` it later on
+            let mut sys_event = MaybeUninit::uninit();
+
+            self.peer_snapshots.capture(self.inner);
+
+            let res = unsafe { enet_host_check_events(self.inner, sys_event.as_mut_ptr()) };
+
+            let sys_event = match res {
+                r if r > 0 => unsafe { sys_event.assume_init() },
+                0 => return Ok(None),
+                r if r < 0 => return Err(Error(r)),
+                _ => panic!("unreachable"),
+            };
+
+            if let Some(event) = self.process_sys_event(&sys_event) {
+                return Ok(Some(event));
+            }
+        }
+    }
+
+    /// Converts a raw event, and runs it through all enabled layers. Returns
+    /// `None` if the event was consumed internally.
+    ///
+    /// The returned event is not bound to the lifetime of `self`, so callers
+    /// have to make sure it does not outlive the borrow they hand out.
+    fn process_sys_event<'a>(&mut self, sys_event: &ENetEvent) -> Option<Event<'a, T>> {
+        let mut event = Event::from_sys_event(sys_event)?;
+
+        if let Event::Disconnect(peer, reason) = &mut event {
+            if let Some(snapshot) = self.peer_snapshots.get(peer.id()) {
+                let service_time = unsafe { (*self.inner).serviceTime };
+                *reason = snapshot.disconnect_reason(sys_event.data, service_time);
+            }
+
+            // before any layer can hold back the event
+            self.remove_peer(peer.id());
+        }
+
+        if let Event::Connect(peer) = &event {
+            self.peer_snapshots.add(peer.id());
+        }
+
+        // before admission can hold back the event and the key exchange starts
+        #[cfg(feature = "crypto")]
+        if let (Event::Connect(peer), Some(_)) = (&event, &self.encryption) {
+            Encryption::await_key_exchange(&mut peer.clone());
+        }
+
+        let event = match &mut self.rate_limiter {
+            Some(rate_limiter) => rate_limiter.process_event(event)?,
+            None => event,
+        };
+
+        let event = match &mut self.admission {
+            Some(admission) => admission.process_event(event, sys_event.data)?,
+            None => event,
+        };
+
+        self.process_admitted_event(event)
+    }
+
+    /// Runs an event through the layers that operate on admitted peers.
+    fn process_admitted_event<'a>(&mut self, event: Event<'a, T>) -> Option<Event<'a, T>> {
+        #[cfg(feature = "crypto")]
+        let event = match &mut self.encryption {
+            Some(encryption) => encryption.process_event(event)?,
+            None => event,
+        };
+
+        self.process_event(event)
+    }
+
+    fn raw_peer<'a>(&self, id: PeerId) -> Peer<'a, T> {
+        peer_at(self.inner, id)
+    }
+
+    /// Returns the next event that was queued by one of the layers, if any.
+    fn next_queued_event<'a>(&mut self) -> Option<Event<'a, T>> {
+        while let Some(id) = self.admission.as_mut().and_then(|a| a.pop_admitted()) {
+            let event = Event::Connect(self.raw_peer(id));
+
+            if let Some(event) = self.process_admitted_event(event) {
+                return Some(event);
+            }
+        }
+
+        while let Some((id, channel_id, packet)) =
+            self.admission.as_mut().and_then(|a| a.pop_replayed())
+        {
+            let event = Event::Receive {
+                sender: self.raw_peer(id),
+                channel_id,
+                packet,
+            };
+
+            if let Some(event) = self.process_admitted_event(event) {
+                return Some(event);
+            }
+        }
+
+        while let Some((id, channel_id, packet)) =
+            self.handshake.as_mut().and_then(|h| h.pop_replayed())
+        {
+            let event = Event::Receive {
+                sender: self.raw_peer(id),
+                channel_id,
+                packet,
+            };
+
+            if let Some(event) = self.process_event(event) {
+                return Some(event);
+            }
+        }
+
+        #[cfg(feature = "crypto")]
+        if let Some(event) = self.next_decrypted_event() {
+            return Some(event);
+        }
+
+        let inner = self.inner;
+        if let Some(event) = self.sessions.as_mut().and_then(|s| s.pop_event(inner)) {
+            return Some(event);
+        }
+
+        if let Some((pending, address)) = self.resolver.pop_completed() {
+            return Some(self.resolved_event(pending, address));
+        }
+
+        let (transfer, event) = self.transfers.as_mut()?.pop_event()?;
+
+        Some(Event::Transfer {
+            peer: self.raw_peer(transfer.peer()),
+            transfer,
+            event,
+        })
+    }
+
+    /// Initiates the connection of a completed `Host::connect_hostname` call,
+    /// returning the event reporting its outcome.
+    fn resolved_event<'a>(
+        &mut self,
+        pending: PendingConnect,
+        address: Option<Address>,
+    ) -> Event<'a, T> {
+        let address = match address {
+            Some(address) => address,
+            None => {
+                return Event::ResolveFailed {
+                    resolve: pending.id,
+                    hostname: pending.hostname,
+                }
+            }
+        };
+
+        let id = self
+            .connect_address(&address, pending.channel_count, pending.user_data)
+            .map(|peer| peer.id());
+
+        match id {
+            Ok(id) => Event::Resolved {
+                resolve: pending.id,
+                peer: self.raw_peer(id),
+            },
+            Err(_) => Event::ConnectFailed {
+                resolve: pending.id,
+                hostname: pending.hostname,
+                address,
+            },
+        }
+    }
+
+    /// Runs an event through the layers that operate on decrypted events.
+    fn process_event<'a>(&mut self, event: Event<'a, T>) -> Option<Event<'a, T>> {
+        let event = match &mut self.handshake {
+            Some(handshake) => handshake.process_event(event)?,
+            None => event,
+        };
+
+        let event = match &mut self.sessions {
+            Some(sessions) => sessions.process_event(event)?,
+            None => event,
+        };
+
+        let event = match &mut self.transfers {
+            Some(transfers) => transfers.process_event(event)?,
+            None => event,
+        };
+
+        let event = match &mut self.snapshots {
+            Some(snapshots) => snapshots.process_event(event)?,
+            None => event,
+        };
+
+        let event = match &mut self.clock_sync {
+            Some(clock_sync) => clock_sync.process_event(event)?,
+            None => event,
+        };
+
+        #[cfg(feature = "serde")]
+        let event = match &mut self.rpc {
+            Some(rpc) => rpc.process_event(event)?,
+            None => event,
+        };
+
+        Some(event)
+    }
+
+    /// Drops the state all layers keep for the peer `id`, once its connection
+    /// ended. Runs before any layer can hold back `Event::Disconnect`, and
+    /// for connections that end without one.
+    fn remove_peer(&mut self, id: PeerId) {
+        if let Some(rooms) = &mut self.rooms {
+            rooms.remove_peer(id);
+        }
+
+        if let Some(transfers) = &mut self.transfers {
+            transfers.remove_peer(id);
+        }
+
+        if let Some(snapshots) = &mut self.snapshots {
+            snapshots.remove_peer(id);
+        }
+
+        if let Some(clock_sync) = &mut self.clock_sync {
+            clock_sync.remove_peer(id);
+        }
+
+        #[cfg(feature = "serde")]
+        if let Some(rpc) = &mut self.rpc {
+            rpc.remove_peer(id);
+        }
+    }
+
+    /// Sends the chunks of all outgoing transfers that fit their windows, and
+    /// the clock probes that are due.
+    fn pump(&mut self) {
+        let inner = self.inner;
+
+        if let Some(transfers) = &mut self.transfers {
+            transfers.pump::<T>(inner);
+        }
+
+        if let Some(clock_sync) = &mut self.clock_sync {
+            clock_sync.pump::<T>(inner);
+        }
+    }
 
-        let res = unsafe { enet_host_check_events(self.inner, sys_event.as_mut_ptr()) };
+    /// Returns the next packet that was decrypted after its peer completed
+    /// the key exchange, if any.
+    #[cfg(feature = "crypto")]
+    fn next_decrypted_event<'a>(&mut self) -> Option<Event<'a, T>> {
+        loop {
+            let (id, channel_id, packet) = self.encryption.as_mut()?.pop_decrypted()?;
 
-        match res {
-            r if r > 0 => Ok(Event::from_sys_event(unsafe { &sys_event.assume_init() })),
-            0 => Ok(None),
-            r if r < 0 => Err(Error(r)),
-            _ => panic!("unreachable"),
+            let event = Event::Receive {
+                sender: self.raw_peer(id),
+                channel_id,
+                packet,
+            };
+
+            if let Some(event) = self.process_event(event) {
+                return Some(event);
+            }
+        }
+    }
+
+    /// Disconnects all peers that were not admitted, or did not complete the
+    /// key exchange, handshake or session setup in time, and ends expired
+    /// sessions and calls.
+    fn expire_pending(&mut self) {
+        let now = Instant::now();
+        let mut expired = Vec::new();
+
+        let deferred = self
+            .admission
+            .as_mut()
+            .map_or_else(Vec::new, |admission| admission.expire(now));
+
+        for id in deferred {
+            self.drop_deferred(id, RejectReason::Timeout.code());
+        }
+
+        #[cfg(feature = "crypto")]
+        if let Some(encryption) = &mut self.encryption {
+            expired.extend(encryption.expire(now));
+        }
+
+        if let Some(handshake) = &mut self.handshake {
+            expired.extend(handshake.expire(now));
+        }
+
+        if let Some(sessions) = &mut self.sessions {
+            expired.extend(sessions.expire(now));
+        }
+
+        #[cfg(feature = "serde")]
+        if let Some(rpc) = &mut self.rpc {
+            rpc.expire(now);
+        }
+
+        for id in expired {
+            if let Some(mut peer) = self.peer(id) {
+                peer.disconnect(RejectReason::Timeout.code());
+            }
         }
     }
 
@@ -188,12 +1052,26 @@ impl<T> Host<T> {
     /// The connection will not be done until a `Event::Connected` for this peer
     /// was received.
     ///
+    /// `address` can be anything that converts into an `Address`, such as
+    /// `"127.0.0.1:9000"`.
+    ///
     /// `channel_count` specifies how many channels to allocate for this peer.
     /// `user_data` is a user-specified value that can be chosen arbitrarily.
     pub fn connect(
+        &mut self,
+        address: impl ToAddress,
+        channel_count: usize,
+        user_data: u32,
+    ) -> Result<Peer<'_, T>, ConnectError> {
+        let address = address.to_address()?;
+        Ok(self.connect_address(&address, channel_count, user_data)?)
+    }
+
+    /// Initiates a connection to an `Address`, see `Host::connect`.
+    pub(crate) fn connect_address(
         &mut self,
         address: &Address,
-        channel_count: enet_sys::size_t,
+        channel_count: usize,
         user_data: u32,
     ) -> Result<Peer<'_, T>, Error> {
         let res: *mut ENetPeer = unsafe {
@@ -209,15 +1087,274 @@ impl<T> Host<T> {
             return Err(Error(0));
         }
 
-        Ok(Peer::new(res))
+        let peer = Peer::new(res);
+        self.peer_snapshots.add(peer.id());
+
+        #[cfg(feature = "crypto")]
+        if self.encryption.is_some() {
+            Encryption::await_key_exchange(&mut peer.clone());
+        }
+
+        if let Some(admission) = &mut self.admission {
+            admission.add_outgoing(peer.id());
+        }
+
+        Ok(peer)
+    }
+
+    /// Initiates a connection to a foreign host, allocating all channels of
+    /// the `ChannelLayout` `L`.
+    ///
+    /// See `Host::connect` for more information.
+    pub fn connect_with_layout<L: ChannelLayout>(
+        &mut self,
+        address: impl ToAddress,
+        user_data: u32,
+    ) -> Result<Peer<'_, T>, ConnectError> {
+        self.connect(address, L::channel_count(), user_data)
+    }
+
+    /// Initiates a connection to a foreign host by its hostname, without
+    /// blocking on the system resolver.
+    ///
+    /// The hostname is resolved on a worker thread. Once resolved, the
+    /// connection is initiated as by `Host::connect`, and an
+    /// `Event::Resolved` containing the connecting peer is emitted. If the
+    /// hostname can not be resolved, an `Event::ResolveFailed` is emitted
+    /// instead, and if the connection can not be initiated, an
+    /// `Event::ConnectFailed`. All of these events carry the returned
+    /// `ResolveId`.
+    pub fn connect_hostname(
+        &mut self,
+        hostname: &str,
+        port: u16,
+        channel_count: usize,
+        user_data: u32,
+    ) -> ResolveId {
+        self.resolver
+            .start(hostname, port, channel_count, user_data)
+    }
+
+    /// Disconnects all peers and destroys this `Host`.
+    ///
+    /// Each peer is disconnected with `code` once its outgoing packets have
+    /// been sent. The host keeps being serviced until all disconnections are
+    /// complete or `deadline` passes, then the remaining peers are reset.
+    /// Peers that connect in the meantime are disconnected immediately with
+    /// `code`, and all events are discarded.
+    pub fn shutdown<C: DisconnectCode>(mut self, code: &C, deadline: Instant) -> ShutdownReport {
+        let code = code.to_code();
+        let mut pending = Vec::new();
+        let mut report = ShutdownReport::default();
+
+        for mut peer in self.peers() {
+            if peer.state() != PeerState::Disconnected {
+                peer.disconnect_later(code);
+
+                // peers that were still connecting are reset right away, and
+                // nothing confirms their disconnection
+                if peer.state() == PeerState::Disconnected {
+                    peer.free_data();
+                    report.reset.push(peer.id());
+                } else {
+                    pending.push(peer.id());
+                }
+            }
+        }
+
+        while !pending.is_empty() {
+            let remaining = millis_until(deadline);
+            if remaining == 0 {
+                break;
+            }
+
+            // the layers are bypassed, only ENet's own events tell which of
+            // the captured peers are gone, even if their slots are reused
+            let wait_ms = match self.conditioner.as_ref().and_then(|c| c.next_due()) {
+                Some(due) => remaining.min(millis_until(due)),
+                None => remaining,
+            };
+
+            let mut sys_event = MaybeUninit::uninit();
+            let sys_event = match self.raw_service(sys_event.as_mut_ptr(), wait_ms) {
+                r if r > 0 => unsafe { sys_event.assume_init() },
+                0 => continue,
+                _ => break,
+            };
+
+            match &Event::<T>::from_sys_event(&sys_event) {
+                Some(Event::Connect(peer)) => peer.clone().disconnect_now(code),
+                Some(Event::Disconnect(peer, _)) => {
+                    if let Some(index) = pending.iter().position(|id| *id == peer.id()) {
+                        report.disconnected.push(pending.remove(index));
+                    }
+                }
+                _ => (),
+            }
+        }
+
+        for id in pending {
+            let mut peer = self.raw_peer(id);
+            peer.free_data();
+            peer.reset();
+            report.reset.push(id);
+        }
+
+        report
+    }
+}
+
+/// Returns the peer `id` of `host`, which has to be in range.
+pub(crate) fn peer_at<'a, T>(host: *mut ENetHost, id: PeerId) -> Peer<'a, T> {
+    Peer::new(unsafe { (*host).peers.add(id.0) })
+}
+
+/// The intercept callback installed on hosts with a `NetworkConditioner` or a
+/// `Discovery`. Discovery probes are answered before conditioning, so they
+/// are never lost or delayed.
+unsafe extern "C" fn intercept(host: *mut ENetHost, _event: *mut ENetEvent) -> c_int {
+    match discovery::intercept(host) {
+        0 => conditioner::intercept(host),
+        res => res,
     }
 }
 
+pub(crate) fn millis_until(instant: Instant) -> u32 {
+    let remaining = instant.saturating_duration_since(Instant::now());
+    remaining
+        .as_micros()
+        .div_ceil(1000)
+        .try_into()
+        .unwrap_or(u32::MAX)
+}
+
 impl<T> Drop for Host<T> {
     /// Call the corresponding ENet cleanup-function(s).
     fn drop(&mut self) {
+        // the conditioner destroys the packets it holds back, which needs ENet
+        self.conditioner = None;
+
         unsafe {
             enet_host_destroy(self.inner);
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{Ipv4Addr, UdpSocket},
+        sync::mpsc,
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use crate::{
+        tests::{create_host, create_server},
+        Address, ConnectError, DisconnectReason, Event, Host,
+    };
+
+    #[test]
+    fn test_shutdown() {
+        let mut server: Host<()> = create_server(3);
+        let server_addr = server.address();
+
+        // this client keeps servicing, so it sees the disconnection
+        let (sender, receiver) = mpsc::channel();
+        let client_addr = server_addr.clone();
+        let client = thread::spawn(move || {
+            let mut client: Host<()> = create_host(None, 1);
+            client.connect(&client_addr, 1, 0).unwrap();
+
+            for _ in 0..400 {
+                match client.service(5).unwrap() {
+                    Some(Event::Connect(_)) => sender.send(()).unwrap(),
+                    Some(Event::Disconnect(_, reason)) => return Some(reason),
+                    _ => (),
+                }
+            }
+
+            None
+        });
+
+        // this client stops servicing after connecting
+        let mut silent: Host<()> = create_host(None, 1);
+        silent.connect(&server_addr, 1, 0).unwrap();
+
+        let mut connected = 0;
+        for _ in 0..100 {
+            if let Some(Event::Connect(_)) = server.service(5).unwrap() {
+                connected += 1;
+            }
+            silent.service(0).unwrap();
+
+            if connected == 2 && receiver.try_recv().is_ok() {
+                break;
+            }
+        }
+
+        // this client only connects once the server is shutting down
+        let late = thread::spawn(move || {
+            let mut late: Host<()> = create_host(None, 1);
+            late.connect(&server_addr, 1, 0).unwrap();
+
+            for _ in 0..400 {
+                if let Some(Event::Disconnect(_, reason)) = late.service(5).unwrap() {
+                    return Some(reason);
+                }
+            }
+
+            None
+        });
+
+        let report = server.shutdown(&7u32, Instant::now() + Duration::from_millis(500));
+
+        assert_eq!(report.disconnected.len(), 1);
+        assert_eq!(report.reset.len(), 1);
+        assert!(!report.is_clean());
+        assert_eq!(
+            client.join().unwrap(),
+            Some(DisconnectReason::RemoteRequested(7))
+        );
+        assert_eq!(
+            late.join().unwrap(),
+            Some(DisconnectReason::RemoteRequested(7))
+        );
+    }
+
+    #[test]
+    fn test_shutdown_while_connecting() {
+        // nobody answers on this socket, so the connection never completes
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let address = Address::new(Ipv4Addr::LOCALHOST, socket.local_addr().unwrap().port());
+
+        let mut client: Host<()> = create_host(None, 1);
+        let id = client.connect(&address, 1, 0).unwrap().id();
+        client.service(0).unwrap();
+
+        let report = client.shutdown(&7u32, Instant::now() + Duration::from_millis(100));
+
+        assert!(report.disconnected.is_empty());
+        assert_eq!(report.reset, vec![id]);
+        assert!(!report.is_clean());
+    }
+
+    #[test]
+    fn test_connect_errors() {
+        let mut host: Host<()> = create_host(None, 2);
+        let parse_error = "localhost:9000".parse::<Address>().unwrap_err();
+
+        match host.connect("localhost:9000", 1, 0) {
+            Err(ConnectError::InvalidAddress(err)) => assert_eq!(err, parse_error),
+            _ => panic!("expected an invalid address"),
+        }
+
+        host.connect("127.0.0.1:9000", 1, 0).unwrap();
+        host.connect("127.0.0.1:9001", 1, 0).unwrap();
+
+        assert!(matches!(
+            host.connect("127.0.0.1:9002", 1, 0),
+            Err(ConnectError::Enet(_))
+        ));
+    }
+}
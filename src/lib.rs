@@ -25,6 +25,14 @@
 //!
 //! Also check out the examples in the code, as well as the examples from the official ENet website and from the enet-sys crate. There are also an example server and client in the `examples` directory on [github](https://github.com/futile/enet-rs).
 //!
+//! # Features
+//! - `serde`: typed messages, see `Peer::send_message` and `Event::decode`.
+//!   Messages are encoded using bincode by default. Also enables
+//!   request/response calls, see `Rpc`.
+//! - `postcard`, `msgpack`: additional codecs for typed messages.
+//! - `crypto`: transparent encryption of all traffic, see
+//!   `Host::set_encryption`.
+//!
 //! # Thread-safety
 //! ENet claims to be "mostly" thread-safe as long as access to individual
 //! `Host`-instances is handled in a synchronized manner. This is kind of an
@@ -51,19 +59,72 @@ use std::{
 use enet_sys::{enet_deinitialize, enet_host_create, enet_initialize, enet_linked_version};
 
 mod address;
+mod admission;
+mod callbacks;
+mod channel;
+mod clock;
+mod conditioner;
+mod config;
+#[cfg(feature = "crypto")]
+mod crypto;
+mod disconnect;
+mod discovery;
+mod early;
 mod event;
+mod handshake;
 mod host;
+#[cfg(feature = "serde")]
+mod message;
 mod packet;
 mod peer;
+mod rate_limit;
+mod reconnect;
+mod resolve;
+mod rng;
+mod room;
+#[cfg(feature = "serde")]
+mod rpc;
+mod session;
+mod snapshot;
+pub mod socket;
+mod transfer;
 
 pub use enet_sys::ENetVersion as EnetVersion;
 
+#[cfg(feature = "crypto")]
+pub use crate::crypto::Encryption;
+#[cfg(feature = "msgpack")]
+pub use crate::message::MessagePack;
+#[cfg(feature = "postcard")]
+pub use crate::message::Postcard;
+#[cfg(feature = "serde")]
+pub use crate::message::{Bincode, Codec, CodecError, DefaultCodec, MessageError};
+#[cfg(feature = "serde")]
+pub use crate::rpc::{CallHandle, Request, Rpc, RpcError};
 pub use crate::{
-    address::Address,
+    address::{Address, AddressParseError, ToAddress},
+    admission::{Admission, AdmissionPolicy, Cidr, CidrParseError, ConnectRequest, IpFilter},
+    callbacks::{EnetCallbacks, FreeFn, MallocFn},
+    channel::ChannelLayout,
+    clock::{ClockEstimate, ClockSync},
+    conditioner::{NetworkConditioner, TrafficDirection},
+    config::{CreateHostError, HostConfig},
+    disconnect::{DisconnectCode, DisconnectReason},
+    discovery::{Discovery, DiscoveryInfoError},
     event::Event,
-    host::{BandwidthLimit, ChannelLimit, Host},
+    handshake::{Handshake, Hello, RejectReason},
+    host::{BandwidthLimit, ChannelLimit, ConnectError, Host, ShutdownReport},
     packet::{Packet, PacketMode},
-    peer::{Peer, PeerPacket, PeerState},
+    peer::{Peer, PeerId, PeerPacket, PeerState, SendError},
+    rate_limit::{Limit, RateLimitAction, RateLimitStats, RateLimiter},
+    reconnect::{Backoff, ClientEvent, ReconnectingClient},
+    resolve::ResolveId,
+    room::{RoomError, Rooms},
+    session::{SessionError, SessionId, Sessions},
+    snapshot::{SnapshotError, Snapshots},
+    transfer::{
+        SendTransferError, TransferError, TransferEvent, TransferId, TransferProgress, Transfers,
+    },
 };
 
 const ENET_UNINITIALIZED: usize = 1;
@@ -116,6 +177,16 @@ impl Enet {
     /// Initializes ENet and returns a handle to the top-level functionality, in
     /// the form of an `Enet`-instance.
     pub fn new() -> Result<Enet, InitializationError> {
+        Enet::initialize(None)
+    }
+
+    /// Like `Enet::new`, but initializes ENet with custom `EnetCallbacks`, for
+    /// example to route its allocations through Rust's global allocator.
+    pub fn with_callbacks(callbacks: EnetCallbacks) -> Result<Enet, InitializationError> {
+        Enet::initialize(Some(callbacks))
+    }
+
+    fn initialize(callbacks: Option<EnetCallbacks>) -> Result<Enet, InitializationError> {
         match ENET_STATUS.compare_exchange(
             ENET_UNINITIALIZED,
             ENET_INITIALIZED,
@@ -131,7 +202,10 @@ impl Enet {
             ),
         };
 
-        let r = unsafe { enet_initialize() };
+        let r = match callbacks {
+            Some(callbacks) => callbacks.initialize(),
+            None => unsafe { enet_initialize() },
+        };
 
         if r != 0 {
             return Err(InitializationError::Error(r));
@@ -149,11 +223,14 @@ impl Enet {
     /// choose `None`. `max_channel_count` will be set to its
     /// (ENet-specified) default value if `None`.
     ///
+    /// To listen on an address given as a string like `"0.0.0.0:9000"`, use
+    /// `HostConfig::with_address` and `Enet::create_host_with_config`.
+    ///
     /// The type `T` specifies the data associated with corresponding `Peer`s.
     pub fn create_host<T>(
         &self,
         address: Option<&Address>,
-        max_peer_count: enet_sys::size_t,
+        max_peer_count: usize,
         max_channel_count: ChannelLimit,
         incoming_bandwidth: BandwidthLimit,
         outgoing_bandwidth: BandwidthLimit,
@@ -177,6 +254,39 @@ impl Enet {
 
         Ok(Host::new(self.keep_alive.clone(), inner))
     }
+
+    /// Creates a `Host` from a `HostConfig`.
+    ///
+    /// The socket options of `config` are set before the socket is bound to
+    /// the address of `config`.
+    pub fn create_host_with_config<T>(
+        &self,
+        config: &HostConfig,
+    ) -> Result<Host<T>, CreateHostError> {
+        if let Some(Err(err)) = &config.address {
+            return Err(err.clone().into());
+        }
+
+        let inner = unsafe {
+            enet_host_create(
+                std::ptr::null(),
+                config.max_peer_count,
+                config.max_channel_count.to_enet_val(),
+                config.incoming_bandwidth.to_enet_u32(),
+                config.outgoing_bandwidth.to_enet_u32(),
+            )
+        };
+
+        if inner.is_null() {
+            return Err(CreateHostError::Create);
+        }
+
+        // the host is destroyed on drop if its socket can not be set up
+        let mut host = Host::new(self.keep_alive.clone(), inner);
+        host.configure_socket(config)?;
+
+        Ok(host)
+    }
 }
 
 /// Returns the version of the linked ENet library.
@@ -207,32 +317,85 @@ impl Drop for EnetKeepAlive {
 
 #[cfg(test)]
 mod tests {
-    use super::{BandwidthLimit, ChannelLimit, Enet};
+    use std::net::Ipv4Addr;
+
+    use super::{BandwidthLimit, ChannelLimit, Enet, EnetCallbacks};
+    use crate::{Address, Event, Host, PeerId};
 
     lazy_static! {
-        static ref ENET: Enet = Enet::new().unwrap();
+        // route all allocations of the tests through the global allocator
+        pub(crate) static ref ENET: Enet =
+            Enet::with_callbacks(EnetCallbacks::new().with_global_allocator()).unwrap();
+    }
+
+    /// Creates a host with room for `peer_count` peers, bound to `address` if
+    /// there is one.
+    pub(crate) fn create_host<T>(address: Option<&Address>, peer_count: usize) -> Host<T> {
+        ENET.create_host(
+            address,
+            peer_count,
+            ChannelLimit::Maximum,
+            BandwidthLimit::Unlimited,
+            BandwidthLimit::Unlimited,
+        )
+        .unwrap()
+    }
+
+    /// Creates a host with room for `peer_count` peers, bound to a free port on
+    /// localhost.
+    pub(crate) fn create_server<T>(peer_count: usize) -> Host<T> {
+        create_host(Some(&Address::new(Ipv4Addr::LOCALHOST, 0)), peer_count)
+    }
+
+    /// Connects `client` to `server` with `channel_count` channels, servicing
+    /// both until each side got its `Event::Connect`. Returns the server's id
+    /// of the client, and the client's id of the server.
+    pub(crate) fn connect_pair<T>(
+        server: &mut Host<T>,
+        client: &mut Host<T>,
+        channel_count: usize,
+    ) -> (PeerId, PeerId) {
+        client.connect(server.address(), channel_count, 0).unwrap();
+
+        let (mut server_id, mut client_id) = (None, None);
+
+        for _ in 0..100 {
+            if let Some(Event::Connect(ref peer)) = server.service(5).unwrap() {
+                server_id = Some(peer.id());
+            }
+            if let Some(Event::Connect(ref peer)) = client.service(5).unwrap() {
+                client_id = Some(peer.id());
+            }
+
+            if let (Some(server_id), Some(client_id)) = (server_id, client_id) {
+                return (server_id, client_id);
+            }
+        }
+
+        panic!("failed to connect");
     }
 
     #[test]
     fn test_enet_new() {
         let _ = *ENET; // make sure the lazy_static is initialized
         assert!(Enet::new().is_err());
+        assert!(Enet::with_callbacks(EnetCallbacks::new()).is_err());
     }
 
     #[test]
     fn test_host_create_localhost() {
-        use std::net::Ipv4Addr;
-
-        use crate::Address;
-
         let enet = &ENET;
-        enet.create_host::<()>(
-            Some(&Address::new(Ipv4Addr::LOCALHOST, 12345)),
-            1,
-            ChannelLimit::Maximum,
-            BandwidthLimit::Unlimited,
-            BandwidthLimit::Unlimited,
-        )
-        .unwrap();
+        let host = enet
+            .create_host::<()>(
+                Some(&Address::new(Ipv4Addr::LOCALHOST, 0)),
+                1,
+                ChannelLimit::Maximum,
+                BandwidthLimit::Unlimited,
+                BandwidthLimit::Unlimited,
+            )
+            .unwrap();
+
+        assert_eq!(host.address().ip(), &Ipv4Addr::LOCALHOST);
+        assert_ne!(host.address().port(), 0);
     }
 }
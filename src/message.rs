@@ -0,0 +1,246 @@
+//! Typed messages on top of `Packet`s, available with the `serde` feature.
+//!
+//! Messages are serialized using a [`Codec`]. The methods without a `_with`
+//! suffix use the [`DefaultCodec`] (bincode), the `_with` variants accept any
+//! other codec, such as [`Postcard`] or [`MessagePack`] when the corresponding
+//! features are enabled. Both sides of a connection have to agree on the codec.
+
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{ChannelLayout, Error, Event, Packet, PacketMode, Peer, PeerPacket, SendError};
+
+/// The error type returned by `Codec`s.
+pub type CodecError = Box<dyn std::error::Error + Send + Sync>;
+
+/// The codec used by methods that don't take an explicit codec.
+pub type DefaultCodec = Bincode;
+
+/// A serialization format that can be used to encode and decode messages.
+pub trait Codec {
+    /// Serializes `message` into a byte buffer.
+    fn encode<M: Serialize + ?Sized>(&self, message: &M) -> Result<Vec<u8>, CodecError>;
+
+    /// Deserializes a message from `data`.
+    fn decode<M: DeserializeOwned>(&self, data: &[u8]) -> Result<M, CodecError>;
+}
+
+/// Encodes messages using [bincode](https://crates.io/crates/bincode).
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Bincode;
+
+impl Codec for Bincode {
+    fn encode<M: Serialize + ?Sized>(&self, message: &M) -> Result<Vec<u8>, CodecError> {
+        bincode::serialize(message).map_err(Into::into)
+    }
+
+    fn decode<M: DeserializeOwned>(&self, data: &[u8]) -> Result<M, CodecError> {
+        bincode::deserialize(data).map_err(Into::into)
+    }
+}
+
+/// Encodes messages using [postcard](https://crates.io/crates/postcard).
+#[cfg(feature = "postcard")]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Postcard;
+
+#[cfg(feature = "postcard")]
+impl Codec for Postcard {
+    fn encode<M: Serialize + ?Sized>(&self, message: &M) -> Result<Vec<u8>, CodecError> {
+        postcard::to_allocvec(message).map_err(Into::into)
+    }
+
+    fn decode<M: DeserializeOwned>(&self, data: &[u8]) -> Result<M, CodecError> {
+        postcard::from_bytes(data).map_err(Into::into)
+    }
+}
+
+/// Encodes messages as [MessagePack](https://crates.io/crates/rmp-serde).
+#[cfg(feature = "msgpack")]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct MessagePack;
+
+#[cfg(feature = "msgpack")]
+impl Codec for MessagePack {
+    fn encode<M: Serialize + ?Sized>(&self, message: &M) -> Result<Vec<u8>, CodecError> {
+        rmp_serde::to_vec(message).map_err(Into::into)
+    }
+
+    fn decode<M: DeserializeOwned>(&self, data: &[u8]) -> Result<M, CodecError> {
+        rmp_serde::from_slice(data).map_err(Into::into)
+    }
+}
+
+/// An error that can occur when sending or receiving typed messages.
+#[derive(thiserror::Error, Debug)]
+pub enum MessageError {
+    /// The message could not be serialized.
+    #[error("failed to encode message")]
+    Encode(#[source] CodecError),
+    /// The received data could not be deserialized into the requested type.
+    #[error("failed to decode message")]
+    Decode(#[source] CodecError),
+    /// The event that should be decoded did not carry a packet.
+    #[error("event does not contain a packet")]
+    NoPacket,
+    /// ENet failed to create the packet.
+    #[error(transparent)]
+    Enet(#[from] Error),
+    /// The packet could not be queued.
+    #[error(transparent)]
+    Send(#[from] SendError),
+}
+
+impl Packet {
+    /// Creates a new `Packet` containing `message`, encoded with the
+    /// `DefaultCodec`.
+    pub fn from_message<M: Serialize + ?Sized>(
+        message: &M,
+        mode: PacketMode,
+    ) -> Result<Packet, MessageError> {
+        Packet::from_message_with(&DefaultCodec::default(), message, mode)
+    }
+
+    /// Creates a new `Packet` containing `message`, encoded with `codec`.
+    pub fn from_message_with<C: Codec, M: Serialize + ?Sized>(
+        codec: &C,
+        message: &M,
+        mode: PacketMode,
+    ) -> Result<Packet, MessageError> {
+        let data = codec.encode(message).map_err(MessageError::Encode)?;
+        Ok(Packet::new(&data, mode)?)
+    }
+
+    /// Decodes the contents of this packet using the `DefaultCodec`.
+    pub fn decode<M: DeserializeOwned>(&self) -> Result<M, MessageError> {
+        self.decode_with(&DefaultCodec::default())
+    }
+
+    /// Decodes the contents of this packet using `codec`.
+    pub fn decode_with<C: Codec, M: DeserializeOwned>(&self, codec: &C) -> Result<M, MessageError> {
+        codec.decode(self.data()).map_err(MessageError::Decode)
+    }
+}
+
+impl<'a, T> Peer<'a, T> {
+    /// Encodes `message` with the `DefaultCodec` and queues it to be sent on
+    /// channel `channel_id`.
+    pub fn send_message<M: Serialize + ?Sized>(
+        &mut self,
+        message: &M,
+        channel_id: u8,
+        mode: PacketMode,
+    ) -> Result<(), MessageError> {
+        self.send_message_with(&DefaultCodec::default(), message, channel_id, mode)
+    }
+
+    /// Encodes `message` with `codec` and queues it to be sent on channel
+    /// `channel_id`.
+    pub fn send_message_with<C: Codec, M: Serialize + ?Sized>(
+        &mut self,
+        codec: &C,
+        message: &M,
+        channel_id: u8,
+        mode: PacketMode,
+    ) -> Result<(), MessageError> {
+        let packet = Packet::from_message_with(codec, message, mode)?;
+        Ok(self.try_send_packet(packet, channel_id)?)
+    }
+
+    /// Encodes `message` with the `DefaultCodec` and queues it to be sent on
+    /// `channel`, using the channel's `PacketMode`.
+    pub fn send_message_on<L: ChannelLayout, M: Serialize + ?Sized>(
+        &mut self,
+        channel: L,
+        message: &M,
+    ) -> Result<(), MessageError> {
+        self.send_message(message, channel.channel_id(), channel.mode())
+    }
+}
+
+impl<'b, 'a, T> PeerPacket<'b, 'a, T> {
+    /// Decodes the received packet using the `DefaultCodec`.
+    pub fn decode<M: DeserializeOwned>(&self) -> Result<M, MessageError> {
+        self.packet.decode()
+    }
+
+    /// Decodes the received packet using `codec`.
+    pub fn decode_with<C: Codec, M: DeserializeOwned>(&self, codec: &C) -> Result<M, MessageError> {
+        self.packet.decode_with(codec)
+    }
+}
+
+impl<'a, T> Event<'a, T> {
+    /// Decodes the packet of an `Event::Receive` using the `DefaultCodec`.
+    ///
+    /// Returns `MessageError::NoPacket` for all other events.
+    pub fn decode<M: DeserializeOwned>(&self) -> Result<M, MessageError> {
+        self.decode_with(&DefaultCodec::default())
+    }
+
+    /// Decodes the packet of an `Event::Receive` using `codec`.
+    ///
+    /// Returns `MessageError::NoPacket` for all other events.
+    pub fn decode_with<C: Codec, M: DeserializeOwned>(&self, codec: &C) -> Result<M, MessageError> {
+        match self {
+            Event::Receive { packet, .. } => packet.decode_with(codec),
+            _ => Err(MessageError::NoPacket),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::{Deserialize, Serialize};
+
+    use super::{Codec, MessageError};
+    use crate::{Packet, PacketMode};
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    enum Chat {
+        Join { name: String },
+        Say(String),
+        Leave,
+    }
+
+    fn roundtrip<C: Codec>(codec: &C) {
+        let messages = vec![
+            Chat::Join {
+                name: "felix".to_string(),
+            },
+            Chat::Say("harro".to_string()),
+            Chat::Leave,
+        ];
+
+        for message in messages {
+            let packet =
+                Packet::from_message_with(codec, &message, PacketMode::ReliableSequenced).unwrap();
+            assert_eq!(packet.decode_with::<_, Chat>(codec).unwrap(), message);
+        }
+    }
+
+    #[test]
+    fn test_bincode_roundtrip() {
+        roundtrip(&super::Bincode);
+    }
+
+    #[cfg(feature = "postcard")]
+    #[test]
+    fn test_postcard_roundtrip() {
+        roundtrip(&super::Postcard);
+    }
+
+    #[cfg(feature = "msgpack")]
+    #[test]
+    fn test_msgpack_roundtrip() {
+        roundtrip(&super::MessagePack);
+    }
+
+    #[test]
+    fn test_decode_garbage() {
+        let packet = Packet::new(&[0xff; 3], PacketMode::ReliableSequenced).unwrap();
+        assert!(matches!(
+            packet.decode::<Chat>(),
+            Err(MessageError::Decode(_))
+        ));
+    }
+}
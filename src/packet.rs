@@ -44,6 +44,8 @@ impl PacketMode {
         }
     }
 
+    // The flag constants are `u32` on most platforms, but not on all of them.
+    #[allow(clippy::unnecessary_cast)]
     fn to_sys_flags(self) -> u32 {
         match self {
             PacketMode::UnreliableSequenced => 0,
@@ -58,14 +60,9 @@ impl PacketMode {
 impl Packet {
     /// Creates a new Packet with optional reliability settings.
     pub fn new(data: &[u8], mode: PacketMode) -> Result<Packet, Error> {
-        let data_len: enet_sys::size_t = data
-            .len()
-            .try_into()
-            // this can only happen on 64-bit systems if `size_t` is 32-bit wide (windows?)
-            .expect("packet data too long for ENet (`size_t`)");
-
-        let res =
-            unsafe { enet_packet_create(data.as_ptr() as *const _, data_len, mode.to_sys_flags()) };
+        let res = unsafe {
+            enet_packet_create(data.as_ptr() as *const _, data.len(), mode.to_sys_flags())
+        };
 
         if res.is_null() {
             return Err(Error(0));
@@ -85,19 +82,33 @@ impl Packet {
         res
     }
 
+    pub(crate) fn as_ptr(&self) -> *mut ENetPacket {
+        self.inner
+    }
+
+    /// Returns whether ENet still holds references to this packet, in which
+    /// case it must not be destroyed.
+    pub(crate) fn is_referenced(&self) -> bool {
+        unsafe { (*self.inner).referenceCount > 0 }
+    }
+
+    /// Returns the `PacketMode` this packet was created with.
+    pub fn mode(&self) -> PacketMode {
+        let flags = unsafe { (*self.inner).flags };
+
+        #[allow(clippy::unnecessary_cast)]
+        if flags & _ENetPacketFlag_ENET_PACKET_FLAG_RELIABLE as u32 != 0 {
+            PacketMode::ReliableSequenced
+        } else if flags & _ENetPacketFlag_ENET_PACKET_FLAG_UNSEQUENCED as u32 != 0 {
+            PacketMode::UnreliableUnsequenced
+        } else {
+            PacketMode::UnreliableSequenced
+        }
+    }
+
     /// Returns a reference to the bytes inside this packet.
     pub fn data(&self) -> &[u8] {
-        unsafe {
-            std::slice::from_raw_parts(
-                (*self.inner).data,
-                (*self.inner)
-                    .dataLength
-                    .try_into()
-                    // this can only happen when a too long packet is received on a 32-bit system I
-                    // think
-                    .expect("packet data too long for an `usize`"),
-            )
-        }
+        unsafe { std::slice::from_raw_parts((*self.inner).data, (*self.inner).dataLength) }
     }
 }
 
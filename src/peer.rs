@@ -1,8 +1,10 @@
+#[cfg(feature = "serde")]
+use std::{cell::RefCell, rc::Rc};
 use std::{marker::PhantomData, time::Duration};
 
 use enet_sys::{
     enet_peer_disconnect, enet_peer_disconnect_later, enet_peer_disconnect_now, enet_peer_receive,
-    enet_peer_reset, enet_peer_send, ENetPeer, _ENetPeerState,
+    enet_peer_reset, enet_peer_send, enet_peer_timeout, ENetPeer, _ENetPeerState,
     _ENetPeerState_ENET_PEER_STATE_ACKNOWLEDGING_CONNECT,
     _ENetPeerState_ENET_PEER_STATE_ACKNOWLEDGING_DISCONNECT,
     _ENetPeerState_ENET_PEER_STATE_CONNECTED, _ENetPeerState_ENET_PEER_STATE_CONNECTING,
@@ -12,7 +14,34 @@ use enet_sys::{
     _ENetPeerState_ENET_PEER_STATE_DISCONNECT_LATER, _ENetPeerState_ENET_PEER_STATE_ZOMBIE,
 };
 
-use crate::{Address, Error, Packet};
+#[cfg(feature = "crypto")]
+use crate::crypto::PeerCrypto;
+#[cfg(feature = "serde")]
+use crate::rpc::Calls;
+use crate::{
+    conditioner, rate_limit::PeerRateState, Address, ChannelLayout, DisconnectCode, Error, Packet,
+    RateLimitStats, SessionId,
+};
+
+/// An error that can occur when queueing a packet to be sent to a `Peer`.
+#[derive(thiserror::Error, Debug)]
+pub enum SendError {
+    /// The host encrypts its traffic, and the key exchange with this peer has
+    /// not completed yet, see `Encryption`. The packet was not sent, so it is
+    /// never sent in plaintext.
+    #[error("key exchange with the peer is pending")]
+    KeyExchangePending,
+    /// All nonces of the connection's key have been used up, so no further
+    /// packet can be encrypted for this peer.
+    #[error("the nonces of the connection's key are exhausted")]
+    NonceExhausted,
+    /// The packet could not be encrypted.
+    #[error("failed to encrypt the packet")]
+    Encrypt,
+    /// ENet failed to create or queue the packet.
+    #[error(transparent)]
+    Enet(#[from] Error),
+}
 
 /// This struct represents an endpoint in an ENet-connection.
 ///
@@ -22,13 +51,41 @@ use crate::{Address, Error, Packet};
 ///
 /// ENet allows the association of arbitrary data with each peer.
 /// The type of this associated data is chosen through `T`.
-#[derive(Clone, Debug)]
+#[derive(Debug)]
 pub struct Peer<'a, T: 'a> {
     inner: *mut ENetPeer,
 
     _data: PhantomData<&'a mut T>,
 }
 
+// Implemented manually, as deriving would require `T: Clone`.
+impl<'a, T> Clone for Peer<'a, T> {
+    fn clone(&self) -> Peer<'a, T> {
+        Peer::new(self.inner)
+    }
+}
+
+/// The data stored in the `data` field of an `ENetPeer`.
+///
+/// Besides the user data, this holds the state of optional layers that has to
+/// be available through a `Peer`, e.g. when sending packets.
+pub(crate) struct PeerData<T> {
+    pub(crate) user: Option<T>,
+    pub(crate) rate: Option<PeerRateState>,
+    #[cfg(feature = "crypto")]
+    pub(crate) crypto: Option<PeerCrypto>,
+    pub(crate) session: Option<SessionId>,
+    #[cfg(feature = "serde")]
+    pub(crate) rpc: Option<Rc<RefCell<Calls>>>,
+}
+
+/// Identifies a `Peer` slot of a `Host`.
+///
+/// ENet reuses peer slots, so after a peer disconnected, its id may be handed
+/// out to a new connection.
+#[derive(Debug, Copy, Clone, Hash, PartialEq, Eq, PartialOrd, Ord)]
+pub struct PeerId(pub(crate) usize);
+
 /// A packet received directly from a `Peer`.
 ///
 /// Contains the received packet as well as the channel on which it was
@@ -91,60 +148,112 @@ impl<'a, T> Peer<'a, T> {
         }
     }
 
+    /// Returns the id of this `Peer` within its `Host`.
+    pub fn id(&self) -> PeerId {
+        PeerId(usize::from(unsafe { (*self.inner).incomingPeerID }))
+    }
+
     /// Returns the address of this `Peer`.
     pub fn address(&self) -> Address {
         Address::from_enet_address(&unsafe { (*self.inner).address })
     }
 
     /// Returns the amout of channels allocated for this `Peer`.
-    pub fn channel_count(&self) -> enet_sys::size_t {
+    pub fn channel_count(&self) -> usize {
         unsafe { (*self.inner).channelCount }
     }
 
     /// Returns a reference to the data associated with this `Peer`, if set.
     pub fn data(&self) -> Option<&T> {
-        unsafe {
-            let raw_data = (*self.inner).data as *const T;
-
-            if raw_data.is_null() {
-                None
-            } else {
-                Some(&(*raw_data))
-            }
-        }
+        unsafe { self.raw_data().as_ref() }.and_then(|data| data.user.as_ref())
     }
 
     /// Returns a mutable reference to the data associated with this `Peer`, if
     /// set.
     pub fn data_mut(&mut self) -> Option<&mut T> {
-        unsafe {
-            let raw_data = (*self.inner).data as *mut T;
-
-            if raw_data.is_null() {
-                None
-            } else {
-                Some(&mut (*raw_data))
-            }
-        }
+        unsafe { self.raw_data().as_mut() }.and_then(|data| data.user.as_mut())
     }
 
     /// Sets or clears the data associated with this `Peer`, replacing existing
     /// data.
     pub fn set_data(&mut self, data: Option<T>) {
-        unsafe {
-            let raw_data = (*self.inner).data as *mut T;
+        if data.is_none() && self.raw_data().is_null() {
+            return;
+        }
 
-            if !raw_data.is_null() {
-                // free old data
-                let _: Box<T> = Box::from_raw(raw_data);
-            }
+        self.peer_data_mut().user = data;
+    }
+
+    /// Returns the counters of the traffic from this `Peer` that was dropped
+    /// by the `RateLimiter` of its `Host`.
+    pub fn rate_limit_stats(&self) -> RateLimitStats {
+        unsafe { self.raw_data().as_ref() }
+            .and_then(|data| data.rate.as_ref())
+            .map(|rate| rate.stats)
+            .unwrap_or_default()
+    }
 
-            let new_data = match data {
-                Some(data) => Box::into_raw(Box::new(data)) as *mut _,
-                None => std::ptr::null_mut(),
+    /// Returns the session of this `Peer`, if its `Host` uses `Sessions` as
+    /// a server.
+    pub fn session(&self) -> Option<SessionId> {
+        unsafe { self.raw_data().as_ref() }.and_then(|data| data.session)
+    }
+
+    fn raw_data(&self) -> *mut PeerData<T> {
+        unsafe { (*self.inner).data as *mut PeerData<T> }
+    }
+
+    /// Returns the `PeerData` of this `Peer`, allocating it if necessary.
+    pub(crate) fn peer_data_mut(&mut self) -> &mut PeerData<T> {
+        if self.raw_data().is_null() {
+            let data: PeerData<T> = PeerData {
+                user: None,
+                rate: None,
+                #[cfg(feature = "crypto")]
+                crypto: None,
+                session: None,
+                #[cfg(feature = "serde")]
+                rpc: None,
             };
 
-            (*self.inner).data = new_data;
+            unsafe {
+                (*self.inner).data = Box::into_raw(Box::new(data)) as *mut _;
+            }
+        }
+
+        unsafe { &mut *self.raw_data() }
+    }
+
+    /// Takes the user data out of this `Peer`.
+    pub(crate) fn take_data(&mut self) -> Option<T> {
+        unsafe { self.raw_data().as_mut() }.and_then(|data| data.user.take())
+    }
+
+    /// Frees the `PeerData` of this `Peer`, including the user data.
+    pub(crate) fn free_data(&mut self) {
+        let raw_data = self.raw_data();
+
+        if !raw_data.is_null() {
+            unsafe {
+                let _: Box<PeerData<T>> = Box::from_raw(raw_data);
+                (*self.inner).data = std::ptr::null_mut();
+            }
+        }
+    }
+
+    #[cfg(feature = "crypto")]
+    pub(crate) fn crypto_mut(&mut self) -> Option<&mut PeerCrypto> {
+        unsafe { self.raw_data().as_mut() }.and_then(|data| data.crypto.as_mut())
+    }
+
+    /// Fails the pending calls to this `Peer`, for disconnections that do not
+    /// emit `Event::Disconnect`.
+    #[cfg(feature = "serde")]
+    fn fail_calls(&self) {
+        let calls = unsafe { self.raw_data().as_ref() }.and_then(|data| data.rpc.clone());
+
+        if let Some(calls) = calls {
+            calls.borrow_mut().fail_peer(self.id());
         }
     }
 
@@ -164,11 +273,28 @@ impl<'a, T> Peer<'a, T> {
         Duration::from_millis(unsafe { (*self.inner).roundTripTime } as u64)
     }
 
+    /// Configures when this `Peer` is considered timed out.
+    ///
+    /// A peer times out once a reliable packet stayed unacknowledged for
+    /// `maximum`, or for `minimum` if its retries reached `limit` (which
+    /// ENet counts in powers of two). Zero values select ENet's defaults.
+    pub fn set_timeout(&mut self, limit: u32, minimum: Duration, maximum: Duration) {
+        let millis = |d: Duration| d.as_millis().try_into().unwrap_or(u32::MAX);
+
+        unsafe {
+            enet_peer_timeout(self.inner, limit, millis(minimum), millis(maximum));
+        }
+    }
+
     /// Forcefully disconnects this `Peer`.
     ///
     /// The foreign host represented by the peer is not notified of the
     /// disconnection and will timeout on its connection to the local host.
+    /// Pending calls to the peer fail with `RpcError::Disconnected`.
     pub fn reset(self) {
+        #[cfg(feature = "serde")]
+        self.fail_calls();
+
         unsafe {
             enet_peer_reset(self.inner);
         }
@@ -181,8 +307,77 @@ impl<'a, T> Peer<'a, T> {
 
     /// Queues a packet to be sent.
     ///
-    /// Actual sending will happen during `Host::service`.
+    /// Actual sending will happen during `Host::service`. If encryption is
+    /// enabled, the packet is encrypted first, and sending fails until the key
+    /// exchange with this peer has completed. Use `try_send_packet` to find out
+    /// why sending failed.
     pub fn send_packet(&mut self, packet: Packet, channel_id: u8) -> Result<(), Error> {
+        match self.try_send_packet(packet, channel_id) {
+            Ok(()) => Ok(()),
+            Err(SendError::Enet(err)) => Err(err),
+            // ENet reports its own failures as `-1` as well
+            Err(_) => Err(Error(-1)),
+        }
+    }
+
+    /// Queues a packet to be sent, like `send_packet`, but reports why the
+    /// packet could not be sent.
+    ///
+    /// If encryption is enabled, sending fails with
+    /// `SendError::KeyExchangePending` until the key exchange with this peer
+    /// has completed.
+    pub fn try_send_packet(&mut self, packet: Packet, channel_id: u8) -> Result<(), SendError> {
+        #[cfg(feature = "crypto")]
+        let packet = match self.crypto_mut() {
+            Some(PeerCrypto::Established(session)) => session.seal(&packet, channel_id)?,
+            Some(PeerCrypto::Awaiting | PeerCrypto::Pending { .. }) => {
+                return Err(SendError::KeyExchangePending)
+            }
+            None => packet,
+        };
+
+        Ok(self.send_packet_raw(packet, channel_id)?)
+    }
+
+    /// Queues a packet that may also be queued for other peers, see
+    /// `Host::broadcast_room`. Encrypted peers get their own sealed copy.
+    pub(crate) fn send_shared(&mut self, packet: &Packet, channel_id: u8) -> Result<(), SendError> {
+        #[cfg(feature = "crypto")]
+        match self.crypto_mut() {
+            Some(PeerCrypto::Established(session)) => {
+                let sealed = session.seal(packet, channel_id)?;
+                return Ok(self.send_packet_raw(sealed, channel_id)?);
+            }
+            Some(PeerCrypto::Awaiting | PeerCrypto::Pending { .. }) => {
+                return Err(SendError::KeyExchangePending)
+            }
+            None => (),
+        }
+
+        // a held back packet is sent later, so it can not be shared
+        if unsafe { conditioner::conditions_outgoing(self.inner) } {
+            let packet = Packet::new(packet.data(), packet.mode())?;
+            return Ok(self.send_packet_raw(packet, channel_id)?);
+        }
+
+        let res = unsafe { enet_peer_send(self.inner, channel_id, packet.as_ptr()) };
+
+        match res {
+            0 => Ok(()),
+            r if r < 0 => Err(Error(r).into()),
+            r => panic!("unexpected res: {}", r),
+        }
+    }
+
+    /// Queues a packet to be sent, bypassing encryption.
+    pub(crate) fn send_packet_raw(&mut self, packet: Packet, channel_id: u8) -> Result<(), Error> {
+        // the host's `NetworkConditioner` may hold the packet back, or drop it
+        let packet = unsafe { conditioner::condition_outgoing(self.inner, channel_id, packet) };
+        let packet = match packet {
+            Some(packet) => packet,
+            None => return Ok(()),
+        };
+
         let res = unsafe { enet_peer_send(self.inner, channel_id, packet.into_inner()) };
 
         match res {
@@ -193,22 +388,41 @@ impl<'a, T> Peer<'a, T> {
         }
     }
 
+    /// Queues `data` to be sent on `channel`, using the channel's
+    /// `PacketMode`.
+    pub fn send_on<L: ChannelLayout>(&mut self, channel: L, data: &[u8]) -> Result<(), SendError> {
+        let packet = Packet::new(data, channel.mode())?;
+        self.try_send_packet(packet, channel.channel_id())
+    }
+
     /// Disconnects from this peer.
     ///
     /// A `Disconnect` event will be returned by `Host::service` once the
-    /// disconnection is complete.
+    /// disconnection is complete. The foreign host receives `user_data` as
+    /// `DisconnectReason::RemoteRequested`.
     pub fn disconnect(&mut self, user_data: u32) {
         unsafe {
             enet_peer_disconnect(self.inner, user_data);
         }
     }
 
+    /// Disconnects from this peer, sending an application-defined `code`.
+    ///
+    /// The foreign host can parse the code using `DisconnectReason::code`.
+    pub fn disconnect_with<C: DisconnectCode>(&mut self, code: &C) {
+        self.disconnect(code.to_code());
+    }
+
     /// Disconnects from this peer immediately.
     ///
     /// No `Disconnect` event will be created. No disconnect notification for
     /// the foreign peer is guaranteed, and this `Peer` is immediately reset on
-    /// return from this method.
+    /// return from this method. Pending calls to the peer fail with
+    /// `RpcError::Disconnected`.
     pub fn disconnect_now(self, user_data: u32) {
+        #[cfg(feature = "serde")]
+        self.fail_calls();
+
         unsafe {
             enet_peer_disconnect_now(self.inner, user_data);
         }
@@ -228,19 +442,37 @@ impl<'a, T> Peer<'a, T> {
     ///
     /// On success, returns the packet and the channel id of the receiving
     /// channel.
+    #[cfg_attr(not(feature = "crypto"), allow(clippy::never_loop))]
     pub fn receive<'b>(&'b mut self) -> Option<PeerPacket<'b, 'a, T>> {
-        let mut channel_id = 0u8;
+        loop {
+            let mut channel_id = 0u8;
 
-        let res = unsafe { enet_peer_receive(self.inner, &mut channel_id as *mut _) };
+            let res = unsafe { enet_peer_receive(self.inner, &mut channel_id as *mut _) };
 
-        if res.is_null() {
-            return None;
-        }
+            if res.is_null() {
+                return None;
+            }
 
-        Some(PeerPacket {
-            packet: Packet::from_sys_packet(res),
-            channel_id,
-            _priv_guard: PhantomData,
-        })
+            let packet = Packet::from_sys_packet(res);
+
+            // packets that fail to decrypt are dropped
+            #[cfg(feature = "crypto")]
+            let packet = match self.crypto_mut() {
+                Some(PeerCrypto::Established(session)) => {
+                    match session.open(packet.data(), packet.mode(), channel_id) {
+                        Some(packet) => packet,
+                        None => continue,
+                    }
+                }
+                Some(PeerCrypto::Awaiting | PeerCrypto::Pending { .. }) => continue,
+                None => packet,
+            };
+
+            return Some(PeerPacket {
+                packet,
+                channel_id,
+                _priv_guard: PhantomData,
+            });
+        }
     }
 }
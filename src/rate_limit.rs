@@ -0,0 +1,387 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use crate::{Event, RejectReason};
+
+/// A limit on the traffic received from a peer, enforced using token buckets.
+///
+/// Each bucket holds up to `burst` worth of traffic and is refilled at the
+/// configured rate. The burst has to be large enough for the largest packet
+/// that should be accepted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Limit {
+    bytes_per_second: Option<u32>,
+    packets_per_second: Option<u32>,
+    burst: Duration,
+}
+
+impl Default for Limit {
+    fn default() -> Limit {
+        Limit::new()
+    }
+}
+
+impl Limit {
+    /// Creates a new `Limit` that allows all traffic, with a burst of 1
+    /// second.
+    pub fn new() -> Limit {
+        Limit {
+            bytes_per_second: None,
+            packets_per_second: None,
+            burst: Duration::from_secs(1),
+        }
+    }
+
+    /// Limits the number of received bytes per second.
+    pub fn bytes_per_second(mut self, rate: u32) -> Limit {
+        self.bytes_per_second = Some(rate);
+        self
+    }
+
+    /// Limits the number of received packets per second.
+    pub fn packets_per_second(mut self, rate: u32) -> Limit {
+        self.packets_per_second = Some(rate);
+        self
+    }
+
+    /// Sets how much traffic may be received at once, in terms of the rate,
+    /// defaults to 1 second.
+    pub fn with_burst(mut self, burst: Duration) -> Limit {
+        self.burst = burst;
+        self
+    }
+}
+
+/// What happens to a peer that exceeds its rate limit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RateLimitAction {
+    /// Drop the unreliable packets exceeding the limit.
+    ///
+    /// Reliable packets have already been acknowledged by ENet, so the peer
+    /// never resends them, and dropping one would silently stall whatever
+    /// relies on it, e.g. a `Transfers` or `Rpc` layer. A peer exceeding the
+    /// limit with a reliable packet is therefore disconnected, as with
+    /// `Disconnect`.
+    Drop,
+    /// Drop the packet and disconnect the peer with
+    /// `RejectReason::RateLimited`.
+    Disconnect,
+}
+
+/// Limits the traffic received from each peer, once set on a `Host` using
+/// `Host::set_rate_limiter`.
+///
+/// Packets exceeding a limit never reach the application. A packet has to
+/// conform to both the limit of its peer and of its channel, if any. The
+/// traffic dropped for each peer is counted in `Peer::rate_limit_stats`.
+#[derive(Debug, Clone)]
+pub struct RateLimiter {
+    action: RateLimitAction,
+    peer_limit: Limit,
+    channel_limits: HashMap<u8, Limit>,
+}
+
+impl RateLimiter {
+    /// Creates a new `RateLimiter` without any limits, which applies `action`
+    /// to offending peers.
+    pub fn new(action: RateLimitAction) -> RateLimiter {
+        RateLimiter {
+            action,
+            peer_limit: Limit::new(),
+            channel_limits: HashMap::new(),
+        }
+    }
+
+    /// Sets the limit for all traffic received from a peer.
+    pub fn with_peer_limit(mut self, limit: Limit) -> RateLimiter {
+        self.peer_limit = limit;
+        self
+    }
+
+    /// Sets the limit for traffic received from a peer on `channel_id`.
+    pub fn with_channel_limit(mut self, channel_id: u8, limit: Limit) -> RateLimiter {
+        self.channel_limits.insert(channel_id, limit);
+        self
+    }
+
+    /// Runs the rate limiter for an event, returning the event that should be
+    /// handed on instead, if any.
+    pub(crate) fn process_event<'a, T>(&mut self, event: Event<'a, T>) -> Option<Event<'a, T>> {
+        match &event {
+            Event::Connect(peer) => {
+                // the peer slot might have been used by an earlier connection
+                peer.clone().peer_data_mut().rate = None;
+                Some(event)
+            }
+            Event::Receive {
+                sender,
+                channel_id,
+                packet,
+            } => {
+                let mut peer = sender.clone();
+                let now = Instant::now();
+                let size = packet.data().len();
+
+                let state = peer
+                    .peer_data_mut()
+                    .rate
+                    .get_or_insert_with(PeerRateState::new);
+
+                if state.disconnected {
+                    return None;
+                }
+
+                let mut channel = self.channel_limits.get(channel_id).map(|limit| {
+                    let buckets = state.channels.entry(*channel_id).or_default();
+                    (limit, buckets)
+                });
+
+                let conforms = state.peer.affords(&self.peer_limit, now, size)
+                    && match &mut channel {
+                        Some((limit, buckets)) => buckets.affords(limit, now, size),
+                        None => true,
+                    };
+
+                if conforms {
+                    state.peer.take(&self.peer_limit, size);
+                    if let Some((limit, buckets)) = channel {
+                        buckets.take(limit, size);
+                    }
+
+                    return Some(event);
+                }
+
+                state.stats.dropped_packets += 1;
+                state.stats.dropped_bytes += size as u64;
+
+                if self.action == RateLimitAction::Disconnect || packet.mode().is_reliable() {
+                    state.disconnected = true;
+                    state.stats.disconnects += 1;
+                    peer.disconnect(RejectReason::RateLimited.code());
+                }
+
+                None
+            }
+            Event::Disconnect(..)
+            | Event::Authenticated(_)
+            | Event::Resumed(_)
+            | Event::SessionExpired { .. }
+            | Event::Transfer { .. }
+            | Event::Resolved { .. }
+            | Event::ResolveFailed { .. }
+            | Event::ConnectFailed { .. } => Some(event),
+        }
+    }
+}
+
+/// Counters of the traffic dropped by the `RateLimiter` for a peer.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct RateLimitStats {
+    /// The number of packets that were dropped.
+    pub dropped_packets: u64,
+    /// The number of bytes that were dropped.
+    pub dropped_bytes: u64,
+    /// The number of times the peer was disconnected for exceeding its limit.
+    pub disconnects: u64,
+}
+
+/// The rate limiting state of a single peer.
+pub(crate) struct PeerRateState {
+    peer: Buckets,
+    channels: HashMap<u8, Buckets>,
+    disconnected: bool,
+    pub(crate) stats: RateLimitStats,
+}
+
+impl PeerRateState {
+    fn new() -> PeerRateState {
+        PeerRateState {
+            peer: Buckets::default(),
+            channels: HashMap::new(),
+            disconnected: false,
+            stats: RateLimitStats::default(),
+        }
+    }
+}
+
+/// The buckets for both rates of a `Limit`.
+#[derive(Default)]
+struct Buckets {
+    bytes: Bucket,
+    packets: Bucket,
+}
+
+impl Buckets {
+    /// Refills the buckets and returns whether they can afford a packet of
+    /// `size` bytes.
+    fn affords(&mut self, limit: &Limit, now: Instant, size: usize) -> bool {
+        let bytes = match limit.bytes_per_second {
+            Some(rate) => self.bytes.refill(rate, limit.burst, now) >= size as f64,
+            None => true,
+        };
+
+        let packets = match limit.packets_per_second {
+            Some(rate) => self.packets.refill(rate, limit.burst, now) >= 1.0,
+            None => true,
+        };
+
+        bytes && packets
+    }
+
+    /// Takes a packet of `size` bytes from the buckets.
+    fn take(&mut self, limit: &Limit, size: usize) {
+        if limit.bytes_per_second.is_some() {
+            self.bytes.tokens -= size as f64;
+        }
+
+        if limit.packets_per_second.is_some() {
+            self.packets.tokens -= 1.0;
+        }
+    }
+}
+
+/// A token bucket, which starts out full.
+#[derive(Default)]
+struct Bucket {
+    tokens: f64,
+    last_refill: Option<Instant>,
+}
+
+impl Bucket {
+    /// Refills the bucket for the time since the last refill, returning the
+    /// available tokens.
+    fn refill(&mut self, rate: u32, burst: Duration, now: Instant) -> f64 {
+        let capacity = f64::from(rate) * burst.as_secs_f64();
+
+        self.tokens = match self.last_refill {
+            Some(last) => {
+                let elapsed = now.saturating_duration_since(last).as_secs_f64();
+                (self.tokens + f64::from(rate) * elapsed).min(capacity)
+            }
+            None => capacity,
+        };
+        self.last_refill = Some(now);
+
+        self.tokens
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::{Buckets, Limit};
+    use crate::{
+        tests::{create_host, create_server},
+        DisconnectReason, Event, Handshake, Hello, Host, Packet, PacketMode, Peer, RateLimitAction,
+        RateLimiter, RejectReason,
+    };
+
+    #[test]
+    fn test_buckets() {
+        let limit = Limit::new()
+            .bytes_per_second(100)
+            .packets_per_second(10)
+            .with_burst(Duration::from_millis(500));
+
+        let start = Instant::now();
+        let mut buckets = Buckets::default();
+
+        // the burst allows 5 packets, or 50 bytes
+        for _ in 0..5 {
+            assert!(buckets.affords(&limit, start, 1));
+            buckets.take(&limit, 1);
+        }
+        assert!(!buckets.affords(&limit, start, 1));
+
+        // after 100ms, there is room for another packet
+        let later = start + Duration::from_millis(100);
+        assert!(buckets.affords(&limit, later, 10));
+        assert!(!buckets.affords(&limit, later, 100));
+        buckets.take(&limit, 10);
+        assert!(!buckets.affords(&limit, later, 1));
+    }
+
+    #[test]
+    fn test_flood_is_dropped() {
+        let mut server: Host<()> = create_server(1);
+        let server_addr = server.address();
+        server.set_rate_limiter(
+            RateLimiter::new(RateLimitAction::Drop)
+                .with_peer_limit(Limit::new().packets_per_second(3)),
+        );
+
+        let mut client: Host<()> = create_host(None, 1);
+        client.connect(&server_addr, 1, 0).unwrap();
+
+        let mut received = 0;
+
+        for _ in 0..100 {
+            if let Some(Event::Connect(ref peer)) = client.service(5).unwrap() {
+                let mut peer = peer.clone();
+
+                for _ in 0..10 {
+                    let packet = Packet::new(b"spam", PacketMode::UnreliableUnsequenced).unwrap();
+                    peer.send_packet(packet, 0).unwrap();
+                }
+            }
+
+            if let Some(Event::Receive { .. }) = server.service(5).unwrap() {
+                received += 1;
+            }
+
+            let stats = server.peers().next().unwrap().rate_limit_stats();
+            if received + stats.dropped_packets >= 10 {
+                break;
+            }
+        }
+
+        let stats = server.peers().next().unwrap().rate_limit_stats();
+
+        assert!(received <= 4, "received {}", received);
+        assert_eq!(received + stats.dropped_packets, 10);
+        assert_eq!(stats.dropped_bytes, stats.dropped_packets * 4);
+    }
+
+    #[test]
+    fn test_reliable_overflow_disconnects() {
+        let mut server: Host<()> = create_server(1);
+        let server_addr = server.address();
+        // the hello of the handshake is reliable, and larger than the burst
+        server.set_rate_limiter(
+            RateLimiter::new(RateLimitAction::Drop)
+                .with_peer_limit(Limit::new().bytes_per_second(1)),
+        );
+        server.set_handshake(Handshake::server(1, |_: &mut Peer<'_, ()>, _: &Hello| {
+            Ok(())
+        }));
+
+        let mut client: Host<()> = create_host(None, 1);
+        client.set_handshake(Handshake::client(Hello::new(1, "token")));
+        client.connect(&server_addr, 1, 0).unwrap();
+
+        let mut reason = None;
+
+        for _ in 0..100 {
+            if let Some(Event::Authenticated(_)) = server.service(5).unwrap() {
+                panic!("the hello was not limited");
+            }
+
+            if let Some(Event::Disconnect(_, r)) = client.service(5).unwrap() {
+                reason = Some(r);
+                break;
+            }
+        }
+
+        // instead of stalling the handshake, the client is told why it failed
+        assert_eq!(
+            reason,
+            Some(DisconnectReason::Rejected(RejectReason::RateLimited))
+        );
+
+        let stats = server.peers().next().unwrap().rate_limit_stats();
+        assert_eq!(stats.disconnects, 1);
+    }
+}
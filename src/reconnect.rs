@@ -0,0 +1,546 @@
+use std::time::{Duration, Instant};
+
+use enet_sys::enet_host_random_seed;
+
+use crate::{
+    host::millis_until, rng::SplitMix64, Address, DisconnectReason, Error, Event, Host, Packet,
+    PacketMode, Peer, PeerId, SendError,
+};
+
+/// Configures the delays between the connection attempts of a
+/// `ReconnectingClient`.
+///
+/// The delay before attempt `n` is `initial * multiplier^(n - 1)`, capped at
+/// `max`. To keep clients from reconnecting in lockstep, the delay is then
+/// reduced by a random fraction of up to `jitter`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Backoff {
+    initial: Duration,
+    max: Duration,
+    multiplier: f64,
+    jitter: f64,
+    max_attempts: Option<u32>,
+}
+
+impl Default for Backoff {
+    /// Starts at 500ms, doubling up to 30s, with a jitter of `0.5` and no
+    /// limit on the number of attempts.
+    fn default() -> Backoff {
+        Backoff::new(Duration::from_millis(500), Duration::from_secs(30))
+    }
+}
+
+impl Backoff {
+    /// Creates a new `Backoff` starting at `initial` and doubling up to `max`,
+    /// with a jitter of `0.5` and no limit on the number of attempts.
+    pub fn new(initial: Duration, max: Duration) -> Backoff {
+        Backoff {
+            initial,
+            max,
+            multiplier: 2.0,
+            jitter: 0.5,
+            max_attempts: None,
+        }
+    }
+
+    /// Sets the factor the delay grows by with each attempt, which is at least
+    /// `1.0`. A `NaN` multiplier is treated as `1.0`.
+    pub fn with_multiplier(mut self, multiplier: f64) -> Backoff {
+        // `f64::max` returns the other operand if one of them is `NaN`
+        self.multiplier = multiplier.max(1.0);
+        self
+    }
+
+    /// Sets the maximum fraction, between `0.0` and `1.0`, by which delays are
+    /// randomly reduced.
+    pub fn with_jitter(mut self, jitter: f64) -> Backoff {
+        self.jitter = jitter.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Gives up after `max_attempts` failed reconnection attempts.
+    pub fn with_max_attempts(mut self, max_attempts: u32) -> Backoff {
+        self.max_attempts = Some(max_attempts);
+        self
+    }
+
+    fn allows(&self, attempt: u32) -> bool {
+        self.max_attempts.is_none_or(|max| attempt <= max)
+    }
+
+    /// Returns the delay before `attempt`, for a `random` number in `[0, 1)`.
+    fn delay(&self, attempt: u32, random: f64) -> Duration {
+        let exponent = attempt.saturating_sub(1).min(i32::MAX as u32) as i32;
+        let secs = (self.initial.as_secs_f64() * self.multiplier.powi(exponent))
+            .min(self.max.as_secs_f64());
+
+        // `secs` is not representable for huge or infinite maximums
+        Duration::try_from_secs_f64(secs * (1.0 - self.jitter * random)).unwrap_or(self.max)
+    }
+}
+
+/// An event of a `ReconnectingClient`.
+#[derive(Debug)]
+pub enum ClientEvent<'a, T> {
+    /// The client connected to the server for the first time.
+    Connected(Peer<'a, T>),
+    /// The connection was lost or could not be established, the client will
+    /// make connection attempt `attempt` after `delay`.
+    Reconnecting {
+        /// The number of the next attempt, starting at `1`.
+        attempt: u32,
+        /// The time until the next attempt.
+        delay: Duration,
+        /// Why the connection was lost.
+        reason: DisconnectReason,
+    },
+    /// Connection attempt `attempt - 1` could not be initiated, e.g. because
+    /// no peer of the `Host` was available, the client will make connection
+    /// attempt `attempt` after `delay`.
+    ConnectFailed {
+        /// The number of the next attempt.
+        attempt: u32,
+        /// The time until the next attempt.
+        delay: Duration,
+        /// Why the connection could not be initiated.
+        error: Error,
+    },
+    /// The client connected to the server again.
+    Reconnected {
+        /// The `Peer` of the server.
+        peer: Peer<'a, T>,
+        /// The result of queueing the resume payload, which is `Ok` if there
+        /// is none. The client is connected either way.
+        resume: Result<(), SendError>,
+    },
+    /// The connection was closed, and the client will not reconnect.
+    Disconnected(DisconnectReason),
+    /// Any other event of the underlying `Host`, e.g. `Event::Receive`.
+    Event(Event<'a, T>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Connecting { id: PeerId, attempt: u32 },
+    Connected(PeerId),
+    Waiting { attempt: u32, until: Instant },
+    Closing(PeerId),
+    Closed,
+}
+
+/// A failed connection attempt that is reported once the `Host` has no other
+/// event.
+#[derive(Debug)]
+struct FailedAttempt {
+    /// The next attempt and the time until it, `None` if the client gave up.
+    next: Option<(u32, Duration)>,
+    error: Error,
+}
+
+impl State {
+    fn peer_id(&self) -> Option<PeerId> {
+        match *self {
+            State::Connecting { id, .. } | State::Connected(id) | State::Closing(id) => Some(id),
+            State::Waiting { .. } | State::Closed => None,
+        }
+    }
+}
+
+/// A client `Host` that automatically reconnects to its server when the
+/// connection times out.
+///
+/// Only timeouts, including failed connection attempts, cause reconnects. If
+/// the server disconnects the client, or the client disconnects using
+/// `ReconnectingClient::disconnect`, `ClientEvent::Disconnected` is emitted
+/// instead.
+///
+/// If the `Host` uses a `Handshake`, the client counts as connected once it
+/// is authenticated. If it uses `Sessions`, reconnects resume the previous
+/// session where possible.
+pub struct ReconnectingClient<T> {
+    host: Host<T>,
+    address: Address,
+    channel_count: usize,
+    user_data: u32,
+
+    backoff: Backoff,
+    rng: SplitMix64,
+    resume: Option<(u8, Vec<u8>)>,
+    state: State,
+    connected_once: bool,
+    failed: Option<FailedAttempt>,
+}
+
+impl<T> ReconnectingClient<T> {
+    /// Wraps `host` and starts connecting to `address`.
+    ///
+    /// `channel_count` and `user_data` are used for every connection attempt,
+    /// see `Host::connect`.
+    pub fn new(
+        mut host: Host<T>,
+        address: Address,
+        channel_count: usize,
+        user_data: u32,
+    ) -> Result<ReconnectingClient<T>, Error> {
+        let id = host
+            .connect_address(&address, channel_count, user_data)?
+            .id();
+
+        Ok(ReconnectingClient {
+            host,
+            address,
+            channel_count,
+            user_data,
+            backoff: Backoff::default(),
+            rng: SplitMix64::new(u64::from(unsafe { enet_host_random_seed() })),
+            resume: None,
+            state: State::Connecting { id, attempt: 0 },
+            connected_once: false,
+            failed: None,
+        })
+    }
+
+    /// Sets the `Backoff` used between connection attempts.
+    pub fn with_backoff(mut self, backoff: Backoff) -> ReconnectingClient<T> {
+        self.backoff = backoff;
+        self
+    }
+
+    /// Sets the payload that is sent reliably on `channel_id` after each
+    /// reconnect, e.g. a login or session token. `None` clears it.
+    pub fn set_resume_payload(&mut self, channel_id: u8, payload: Option<Vec<u8>>) {
+        self.resume = payload.map(|payload| (channel_id, payload));
+    }
+
+    /// Returns the wrapped `Host`.
+    pub fn host(&self) -> &Host<T> {
+        &self.host
+    }
+
+    /// Returns the wrapped `Host` mutably.
+    pub fn host_mut(&mut self) -> &mut Host<T> {
+        &mut self.host
+    }
+
+    /// Stops reconnecting and returns the wrapped `Host`.
+    pub fn into_host(self) -> Host<T> {
+        self.host
+    }
+
+    /// Returns whether the client is currently connected to the server.
+    pub fn is_connected(&self) -> bool {
+        matches!(self.state, State::Connected(_))
+    }
+
+    /// Returns the `Peer` of the server, while connected.
+    pub fn peer(&mut self) -> Option<Peer<'_, T>> {
+        match self.state {
+            State::Connected(id) => self.host.peer(id),
+            _ => None,
+        }
+    }
+
+    /// Disconnects from the server and stops reconnecting.
+    ///
+    /// `ClientEvent::Disconnected` is emitted once the disconnection is
+    /// complete. If the client is waiting to reconnect, it simply stops.
+    pub fn disconnect(&mut self, user_data: u32) {
+        match self.state.peer_id() {
+            Some(id) => {
+                if let Some(mut peer) = self.host.peer(id) {
+                    peer.disconnect(user_data);
+                }
+                self.state = State::Closing(id);
+            }
+            None => self.state = State::Closed,
+        }
+    }
+
+    /// Maintains the connection and delivers an event if available, see
+    /// `Host::service`.
+    ///
+    /// A connection attempt that can not be initiated is retried after the
+    /// next delay of the `Backoff`, and reported as
+    /// `ClientEvent::ConnectFailed`, not as an error.
+    pub fn service(&mut self, timeout_ms: u32) -> Result<Option<ClientEvent<'_, T>>, Error> {
+        let mut timeout_ms = timeout_ms;
+
+        if let State::Waiting { attempt, until } = self.state {
+            if Instant::now() >= until {
+                match self
+                    .host
+                    .connect_address(&self.address, self.channel_count, self.user_data)
+                {
+                    Ok(peer) => {
+                        self.state = State::Connecting {
+                            id: peer.id(),
+                            attempt,
+                        };
+                    }
+                    // e.g. the previous peer is still a zombie, so this only
+                    // counts as a failed attempt
+                    Err(error) => {
+                        let attempt = attempt + 1;
+                        let next = if self.backoff.allows(attempt) {
+                            let delay = self.backoff.delay(attempt, self.rng.next_f64());
+                            self.state = State::Waiting {
+                                attempt,
+                                until: Instant::now() + delay,
+                            };
+                            Some((attempt, delay))
+                        } else {
+                            self.state = State::Closed;
+                            None
+                        };
+
+                        self.failed = Some(FailedAttempt { next, error });
+                    }
+                }
+            } else {
+                timeout_ms = timeout_ms.min(millis_until(until));
+            }
+        }
+
+        if self.failed.is_some() {
+            timeout_ms = 0;
+        }
+
+        let event = match self.host.service(timeout_ms)? {
+            Some(event) => event,
+            None => {
+                return Ok(self.failed.take().map(|failed| match failed.next {
+                    Some((attempt, delay)) => ClientEvent::ConnectFailed {
+                        attempt,
+                        delay,
+                        error: failed.error,
+                    },
+                    None => ClientEvent::Disconnected(DisconnectReason::Timeout),
+                }))
+            }
+        };
+
+        let connecting = match self.state {
+            State::Connecting { id, .. } => Some(id),
+            _ => None,
+        };
+
+        let client_event = match event {
+            Event::Connect(ref peer)
+            | Event::Authenticated(ref peer)
+            | Event::Resumed(ref peer)
+                if connecting == Some(peer.id()) =>
+            {
+                let mut peer = peer.clone();
+                self.state = State::Connected(peer.id());
+
+                if !std::mem::replace(&mut self.connected_once, true) {
+                    return Ok(Some(ClientEvent::Connected(peer)));
+                }
+
+                let resume = match &self.resume {
+                    Some((channel_id, payload)) => {
+                        Packet::new(payload, PacketMode::ReliableSequenced)
+                            .map_err(SendError::from)
+                            .and_then(|packet| peer.try_send_packet(packet, *channel_id))
+                    }
+                    None => Ok(()),
+                };
+
+                ClientEvent::Reconnected { peer, resume }
+            }
+            Event::Disconnect(ref peer, reason) if self.state.peer_id() == Some(peer.id()) => {
+                let attempt = match self.state {
+                    State::Connecting { attempt, .. } => attempt + 1,
+                    State::Connected(_) => 1,
+                    _ => {
+                        self.state = State::Closed;
+                        return Ok(Some(ClientEvent::Disconnected(reason)));
+                    }
+                };
+
+                if !reason.is_timeout() || !self.backoff.allows(attempt) {
+                    self.state = State::Closed;
+                    return Ok(Some(ClientEvent::Disconnected(reason)));
+                }
+
+                let delay = self.backoff.delay(attempt, self.rng.next_f64());
+                self.state = State::Waiting {
+                    attempt,
+                    until: Instant::now() + delay,
+                };
+
+                ClientEvent::Reconnecting {
+                    attempt,
+                    delay,
+                    reason,
+                }
+            }
+            _ => ClientEvent::Event(event),
+        };
+
+        Ok(Some(client_event))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{Ipv4Addr, UdpSocket},
+        time::Duration,
+    };
+
+    use super::{Backoff, ClientEvent, ReconnectingClient};
+    use crate::{
+        tests::{create_host, create_server},
+        Address, Event, Host, Packet, PacketMode,
+    };
+
+    #[test]
+    fn test_backoff_delays() {
+        let backoff =
+            Backoff::new(Duration::from_millis(100), Duration::from_secs(1)).with_max_attempts(3);
+
+        assert_eq!(backoff.delay(1, 0.0), Duration::from_millis(100));
+        assert_eq!(backoff.delay(2, 0.0), Duration::from_millis(200));
+        assert_eq!(backoff.delay(5, 0.0), Duration::from_secs(1));
+        assert_eq!(backoff.delay(u32::MAX, 0.0), Duration::from_secs(1));
+        assert_eq!(backoff.delay(1, 0.5), Duration::from_millis(75));
+
+        assert!(backoff.allows(3));
+        assert!(!backoff.allows(4));
+    }
+
+    #[test]
+    fn test_backoff_extreme_values() {
+        let backoff = Backoff::new(Duration::from_millis(100), Duration::from_secs(1));
+
+        for multiplier in [-2.0, 0.0, 0.5, f64::NAN] {
+            let backoff = backoff.with_multiplier(multiplier);
+
+            for attempt in 1..5 {
+                assert_eq!(backoff.delay(attempt, 0.0), Duration::from_millis(100));
+            }
+        }
+
+        let backoff = backoff.with_multiplier(f64::INFINITY);
+        assert_eq!(backoff.delay(2, 0.0), Duration::from_secs(1));
+
+        let backoff = Backoff::new(Duration::from_secs(1), Duration::MAX);
+        assert_eq!(backoff.delay(u32::MAX, 0.0), Duration::MAX);
+        assert!(backoff.delay(u32::MAX, 0.5) > Duration::from_secs(u32::MAX.into()));
+    }
+
+    #[test]
+    fn test_reconnect_after_timeout() {
+        let mut server: Host<()> = create_server(2);
+        let server_addr = server.address();
+        let mut client = ReconnectingClient::new(create_host::<()>(None, 2), server_addr, 1, 0)
+            .unwrap()
+            .with_backoff(Backoff::new(
+                Duration::from_millis(10),
+                Duration::from_millis(10),
+            ));
+        client.set_resume_payload(0, Some(b"resume".to_vec()));
+
+        let mut reconnecting = None;
+        let mut reconnected = false;
+        let mut resumed = false;
+
+        for _ in 0..500 {
+            match client.service(2).unwrap() {
+                Some(ClientEvent::Connected(ref peer)) => {
+                    let mut peer = peer.clone();
+                    peer.set_timeout(1, Duration::from_millis(50), Duration::from_millis(100));
+
+                    // make the server forget the client, so it times out
+                    server.peers().for_each(|peer| peer.reset());
+
+                    let packet = Packet::new(b"lost", PacketMode::ReliableSequenced).unwrap();
+                    peer.send_packet(packet, 0).unwrap();
+                }
+                Some(ClientEvent::Reconnecting {
+                    attempt, reason, ..
+                }) => {
+                    assert!(reason.is_timeout());
+                    reconnecting = Some(attempt);
+                }
+                Some(ClientEvent::Reconnected { resume, .. }) => {
+                    resume.unwrap();
+                    reconnected = true;
+                }
+                Some(ClientEvent::Disconnected(reason)) => panic!("disconnected: {:?}", reason),
+                _ => (),
+            }
+
+            if let Some(Event::Receive { ref packet, .. }) = server.service(2).unwrap() {
+                assert_eq!(packet.data(), b"resume");
+                resumed = true;
+                break;
+            }
+        }
+
+        assert_eq!(reconnecting, Some(1));
+        assert!(reconnected && resumed && client.is_connected());
+    }
+
+    #[test]
+    fn test_connect_failed() {
+        let mut server: Host<()> = create_server(2);
+        let server_addr = server.address();
+        // nobody answers on this socket
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let silent_addr = Address::new(Ipv4Addr::LOCALHOST, socket.local_addr().unwrap().port());
+
+        // the host has a single peer, so attempts fail while it is in use
+        let mut client = ReconnectingClient::new(create_host::<()>(None, 1), server_addr, 1, 0)
+            .unwrap()
+            .with_backoff(Backoff::new(
+                Duration::from_millis(10),
+                Duration::from_millis(10),
+            ));
+
+        let mut blocker = None;
+        let mut failed = None;
+        let mut reconnected = false;
+
+        for _ in 0..500 {
+            let mut block = false;
+
+            match client.service(2).unwrap() {
+                Some(ClientEvent::Connected(ref peer)) => {
+                    let mut peer = peer.clone();
+                    peer.set_timeout(1, Duration::from_millis(50), Duration::from_millis(100));
+
+                    // make the server forget the client, so it times out
+                    server.peers().for_each(|peer| peer.reset());
+
+                    let packet = Packet::new(b"lost", PacketMode::ReliableSequenced).unwrap();
+                    peer.send_packet(packet, 0).unwrap();
+                }
+                Some(ClientEvent::Reconnecting { .. }) => block = true,
+                Some(ClientEvent::ConnectFailed { attempt, .. }) => failed = Some(attempt),
+                Some(ClientEvent::Reconnected { .. }) => {
+                    reconnected = true;
+                    break;
+                }
+                Some(ClientEvent::Disconnected(reason)) => panic!("disconnected: {:?}", reason),
+                _ => (),
+            }
+
+            if block {
+                let peer = client.host_mut().connect(&silent_addr, 1, 0).unwrap();
+                blocker = Some(peer.id());
+            } else if failed.is_some() {
+                // free the peer again, so the next attempt succeeds
+                if let Some(id) = blocker.take() {
+                    client.host_mut().peer(id).unwrap().reset();
+                }
+            }
+
+            server.service(2).unwrap();
+        }
+
+        assert_eq!(failed, Some(2));
+        assert!(reconnected && client.is_connected());
+    }
+}
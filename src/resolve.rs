@@ -0,0 +1,170 @@
+use std::{
+    ffi::CString,
+    sync::mpsc::{self, Receiver, TryRecvError},
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::{Address, Error};
+
+/// How often a `Host` that waits for a resolution checks whether it
+/// completed.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Identifies a hostname resolution started by `Host::connect_hostname`.
+#[derive(Debug, Copy, Clone, Hash, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ResolveId(u32);
+
+/// A connection that waits for its hostname to be resolved.
+pub(crate) struct PendingConnect {
+    pub(crate) id: ResolveId,
+    pub(crate) hostname: String,
+    pub(crate) channel_count: usize,
+    pub(crate) user_data: u32,
+    result: Option<Receiver<Result<Address, Error>>>,
+}
+
+/// Resolves hostnames on worker threads, so servicing a `Host` never blocks
+/// on the system resolver.
+#[derive(Default)]
+pub(crate) struct Resolver {
+    next_id: u32,
+    pending: Vec<PendingConnect>,
+}
+
+impl Resolver {
+    /// Starts resolving `hostname` on a worker thread.
+    pub(crate) fn start(
+        &mut self,
+        hostname: &str,
+        port: u16,
+        channel_count: usize,
+        user_data: u32,
+    ) -> ResolveId {
+        let id = ResolveId(self.next_id);
+        self.next_id = self.next_id.wrapping_add(1);
+
+        // a hostname containing a nul byte fails without a lookup
+        let result = CString::new(hostname).ok().map(|hostname| {
+            let (sender, receiver) = mpsc::channel();
+
+            thread::spawn(move || {
+                // the host may have been dropped in the meantime
+                let _ = sender.send(Address::from_hostname(&hostname, port));
+            });
+
+            receiver
+        });
+
+        self.pending.push(PendingConnect {
+            id,
+            hostname: hostname.to_string(),
+            channel_count,
+            user_data,
+            result,
+        });
+
+        id
+    }
+
+    /// Returns when to check for completed resolutions next, if any are
+    /// pending.
+    pub(crate) fn next_due(&self) -> Option<Instant> {
+        (!self.pending.is_empty()).then(|| Instant::now() + POLL_INTERVAL)
+    }
+
+    /// Returns a completed resolution and its result, if any.
+    pub(crate) fn pop_completed(&mut self) -> Option<(PendingConnect, Option<Address>)> {
+        let (index, address) = self.pending.iter().enumerate().find_map(|(i, pending)| {
+            match pending.result.as_ref().map(Receiver::try_recv) {
+                Some(Ok(result)) => Some((i, result.ok())),
+                Some(Err(TryRecvError::Empty)) => None,
+                Some(Err(TryRecvError::Disconnected)) | None => Some((i, None)),
+            }
+        })?;
+
+        Some((self.pending.remove(index), address))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use crate::{
+        tests::{create_host, create_server},
+        Address, Event,
+    };
+
+    #[test]
+    fn test_connect_hostname() {
+        let mut server = create_server::<()>(1);
+        let port = server.address().port();
+
+        let mut client = create_host::<()>(None, 1);
+        let resolving = client.connect_hostname("localhost", port, 1, 0);
+        let failing = client.connect_hostname("", port, 1, 0);
+        assert_ne!(resolving, failing);
+
+        let (mut resolved, mut failed, mut connected) = (None, None, false);
+
+        for _ in 0..200 {
+            server.service(1).unwrap();
+
+            match client.service(5).unwrap() {
+                Some(Event::Resolved { resolve, ref peer }) => {
+                    resolved = Some((resolve, peer.id()))
+                }
+                Some(Event::ResolveFailed {
+                    resolve,
+                    ref hostname,
+                }) => failed = Some((resolve, hostname.clone())),
+                Some(Event::Connect(ref peer)) => {
+                    assert_eq!(resolved.map(|(_, id)| id), Some(peer.id()));
+                    connected = true;
+                }
+                _ => (),
+            }
+
+            if connected && failed.is_some() {
+                break;
+            }
+        }
+
+        assert!(connected, "failed to connect");
+        assert_eq!(resolved.map(|(resolve, _)| resolve), Some(resolving));
+        assert_eq!(failed, Some((failing, String::new())));
+    }
+
+    #[test]
+    fn test_connect_failed() {
+        let server = create_server::<()>(1);
+        let address = server.address();
+
+        // the only peer of the client is taken, so the resolved connection
+        // can not be initiated
+        let mut client = create_host::<()>(None, 1);
+        client.connect(&address, 1, 0).unwrap();
+        let resolving = client.connect_hostname("127.0.0.1", address.port(), 1, 0);
+
+        let mut failed = None;
+
+        for _ in 0..200 {
+            match client.service(5).unwrap() {
+                Some(Event::ConnectFailed {
+                    resolve,
+                    ref hostname,
+                    ref address,
+                }) => {
+                    failed = Some((resolve, hostname.clone(), address.clone()));
+                    break;
+                }
+                Some(Event::ResolveFailed { .. }) => panic!("resolving failed"),
+                _ => (),
+            }
+        }
+
+        let expected = Address::new(Ipv4Addr::LOCALHOST, address.port());
+        assert_eq!(failed, Some((resolving, "127.0.0.1".to_owned(), expected)));
+    }
+}
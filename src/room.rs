@@ -0,0 +1,227 @@
+use std::{
+    any::Any,
+    collections::{HashMap, HashSet},
+    hash::Hash,
+};
+
+use crate::PeerId;
+
+/// An error that can occur when broadcasting to a room using
+/// `Host::broadcast_room`.
+#[derive(thiserror::Error, Debug)]
+pub enum RoomError {
+    /// The `Host` has no `Rooms` registry with rooms of the requested type.
+    #[error("rooms of this type are not enabled for this host")]
+    NotEnabled,
+}
+
+/// A registry of rooms that peers can join, for broadcasting packets only to
+/// the peers in the same room, once set on a `Host` using `Host::set_rooms`.
+///
+/// Rooms are identified by any hashable key `R`, such as a zone id. A peer
+/// can be in any number of rooms, and a room exists as long as it has
+/// members. Peers are removed from all their rooms once they disconnect,
+/// including when a `Sessions` layer keeps their session, so clients that
+/// resume a session have to join their rooms again.
+///
+/// The registry of a `Host` can be accessed using `Host::rooms` and
+/// `Host::rooms_mut`, and packets are sent to a room using
+/// `Host::broadcast_room`.
+#[derive(Debug, Clone)]
+pub struct Rooms<R> {
+    members: HashMap<R, HashSet<PeerId>>,
+    joined: HashMap<PeerId, HashSet<R>>,
+}
+
+impl<R: Eq + Hash + Clone> Rooms<R> {
+    /// Creates a new registry without any rooms.
+    pub fn new() -> Rooms<R> {
+        Rooms {
+            members: HashMap::new(),
+            joined: HashMap::new(),
+        }
+    }
+
+    /// Adds `peer` to `room`. Returns `false` if it already was a member.
+    pub fn join(&mut self, peer: PeerId, room: R) -> bool {
+        self.joined.entry(peer).or_default().insert(room.clone());
+        self.members.entry(room).or_default().insert(peer)
+    }
+
+    /// Removes `peer` from `room`. Returns `false` if it was not a member.
+    pub fn leave(&mut self, peer: PeerId, room: &R) -> bool {
+        let Some(members) = self.members.get_mut(room) else {
+            return false;
+        };
+
+        if !members.remove(&peer) {
+            return false;
+        }
+
+        if members.is_empty() {
+            self.members.remove(room);
+        }
+
+        if let Some(joined) = self.joined.get_mut(&peer) {
+            joined.remove(room);
+
+            if joined.is_empty() {
+                self.joined.remove(&peer);
+            }
+        }
+
+        true
+    }
+
+    /// Removes `peer` from all rooms it joined.
+    pub fn leave_all(&mut self, peer: PeerId) {
+        for room in self.joined.remove(&peer).into_iter().flatten() {
+            if let Some(members) = self.members.get_mut(&room) {
+                members.remove(&peer);
+
+                if members.is_empty() {
+                    self.members.remove(&room);
+                }
+            }
+        }
+    }
+
+    /// Returns whether `peer` is a member of `room`.
+    pub fn contains(&self, room: &R, peer: PeerId) -> bool {
+        self.members
+            .get(room)
+            .is_some_and(|members| members.contains(&peer))
+    }
+
+    /// Returns the members of `room`, in no particular order.
+    pub fn members(&self, room: &R) -> impl Iterator<Item = PeerId> + '_ {
+        self.members.get(room).into_iter().flatten().copied()
+    }
+
+    /// Returns the rooms `peer` joined, in no particular order.
+    pub fn rooms_of(&self, peer: PeerId) -> impl Iterator<Item = &R> + '_ {
+        self.joined.get(&peer).into_iter().flatten()
+    }
+
+    /// Returns all rooms that have members, in no particular order.
+    pub fn rooms(&self) -> impl Iterator<Item = &R> + '_ {
+        self.members.keys()
+    }
+}
+
+impl<R: Eq + Hash + Clone> Default for Rooms<R> {
+    fn default() -> Rooms<R> {
+        Rooms::new()
+    }
+}
+
+/// `Rooms` with an erased room type, so a `Host` can hold it.
+pub(crate) trait RoomRegistry {
+    fn remove_peer(&mut self, peer: PeerId);
+
+    fn as_any(&self) -> &dyn Any;
+
+    fn as_any_mut(&mut self) -> &mut dyn Any;
+}
+
+impl<R: Eq + Hash + Clone + 'static> RoomRegistry for Rooms<R> {
+    fn remove_peer(&mut self, peer: PeerId) {
+        self.leave_all(peer);
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{RoomError, Rooms};
+    use crate::{
+        tests::{connect_pair, create_host, create_server},
+        Event, Host, Packet, PacketMode, PeerId,
+    };
+
+    #[test]
+    fn test_rooms() {
+        let mut rooms = Rooms::new();
+
+        assert!(rooms.join(PeerId(0), "town"));
+        assert!(!rooms.join(PeerId(0), "town"));
+        assert!(rooms.join(PeerId(0), "forest"));
+        assert!(rooms.join(PeerId(1), "town"));
+
+        let mut members: Vec<_> = rooms.members(&"town").collect();
+        members.sort();
+        assert_eq!(members, [PeerId(0), PeerId(1)]);
+
+        assert!(rooms.leave(PeerId(1), &"town"));
+        assert!(!rooms.leave(PeerId(1), &"town"));
+        assert_eq!(rooms.members(&"town").collect::<Vec<_>>(), [PeerId(0)]);
+
+        rooms.leave_all(PeerId(0));
+        assert_eq!(rooms.rooms().count(), 0);
+        assert_eq!(rooms.rooms_of(PeerId(0)).count(), 0);
+    }
+
+    #[test]
+    fn test_broadcast_room() {
+        let mut server = create_server(3);
+        server.set_rooms(Rooms::<u32>::new());
+
+        let mut clients: Vec<Host<()>> = (0..3).map(|_| create_host(None, 1)).collect();
+        let ids: Vec<_> = clients
+            .iter_mut()
+            .map(|client| connect_pair(&mut server, client, 1).0)
+            .collect();
+
+        let rooms = server.rooms_mut::<u32>().unwrap();
+        rooms.join(ids[0], 1);
+        rooms.join(ids[1], 1);
+        rooms.join(ids[2], 2);
+
+        let packet = Packet::new(b"room 1", PacketMode::ReliableSequenced).unwrap();
+        assert_eq!(server.broadcast_room(&1u32, packet, 0).unwrap(), 2);
+
+        let packet = Packet::new(b"nobody", PacketMode::ReliableSequenced).unwrap();
+        assert_eq!(server.broadcast_room(&3u32, packet, 0).unwrap(), 0);
+
+        let packet = Packet::new(b"wrong type", PacketMode::ReliableSequenced).unwrap();
+        assert!(matches!(
+            server.broadcast_room(&1u64, packet, 0),
+            Err(RoomError::NotEnabled)
+        ));
+
+        let mut received = vec![Vec::new(); 3];
+
+        for _ in 0..50 {
+            server.service(1).unwrap();
+
+            for (client, received) in clients.iter_mut().zip(&mut received) {
+                if let Some(Event::Receive { ref packet, .. }) = client.service(1).unwrap() {
+                    received.push(packet.data().to_vec());
+                }
+            }
+        }
+
+        assert_eq!(received[0], [b"room 1"]);
+        assert_eq!(received[1], [b"room 1"]);
+        assert!(received[2].is_empty());
+
+        let mut client = clients.remove(0);
+        client.peers().for_each(|peer| peer.disconnect_now(0));
+
+        for _ in 0..50 {
+            if let Some(Event::Disconnect(..)) = server.service(5).unwrap() {
+                break;
+            }
+        }
+
+        let rooms = server.rooms::<u32>().unwrap();
+        assert_eq!(rooms.members(&1).collect::<Vec<_>>(), [ids[1]]);
+    }
+}
@@ -0,0 +1,493 @@
+//! Request/response calls on top of typed messages, available with the
+//! `serde` feature.
+
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    future::Future,
+    marker::PhantomData,
+    pin::Pin,
+    rc::Rc,
+    task::{Context, Poll, Waker},
+    time::{Duration, Instant},
+};
+
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{
+    Codec, CodecError, DefaultCodec, Event, MessageError, Packet, PacketMode, Peer, PeerId,
+    SendError,
+};
+
+const MSG_REQUEST: u8 = 1;
+const MSG_RESPONSE: u8 = 2;
+
+const STATUS_OK: u8 = 0;
+const STATUS_UNKNOWN_METHOD: u8 = 1;
+const STATUS_BAD_REQUEST: u8 = 2;
+const STATUS_INTERNAL_ERROR: u8 = 3;
+
+/// A request that can be sent using `Peer::call`.
+///
+/// Each request type is identified by its `METHOD`, which has to be unique
+/// among the requests handled by an `Rpc` layer.
+pub trait Request: Serialize + DeserializeOwned {
+    /// The method id of this request.
+    const METHOD: u16;
+
+    /// The response to this request.
+    type Response: Serialize + DeserializeOwned;
+}
+
+/// An error that can occur when calling a remote method.
+#[derive(thiserror::Error, Debug)]
+pub enum RpcError {
+    /// No response arrived in time.
+    #[error("call timed out")]
+    Timeout,
+    /// The peer disconnected before responding.
+    #[error("peer disconnected")]
+    Disconnected,
+    /// The peer has no handler for the method.
+    #[error("unknown method")]
+    UnknownMethod,
+    /// The peer could not decode the request.
+    #[error("peer could not decode the request")]
+    BadRequest,
+    /// The peer handled the request, but could not encode the response.
+    #[error("peer could not encode the response")]
+    Internal,
+    /// The response could not be decoded.
+    #[error("failed to decode response")]
+    Decode(#[source] CodecError),
+    /// The request could not be encoded or sent.
+    #[error("failed to send request")]
+    Send(#[source] MessageError),
+    /// The `Host` of the peer has no `Rpc` layer.
+    #[error("rpc is not enabled for this peer")]
+    NotEnabled,
+}
+
+type Handler<T> = Box<dyn FnMut(&mut Peer<'_, T>, &[u8]) -> Result<Vec<u8>, u8>>;
+
+/// The state of a call, shared between the `Rpc` layer and its `CallHandle`.
+struct CallSlot {
+    deadline: Instant,
+    result: Option<Result<Vec<u8>, RpcError>>,
+    waker: Option<Waker>,
+}
+
+impl CallSlot {
+    fn resolve(&mut self, result: Result<Vec<u8>, RpcError>) {
+        self.result = Some(result);
+
+        if let Some(waker) = self.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+/// The pending calls of a `Host`, which its peers refer to.
+pub(crate) struct Calls {
+    channel_id: u8,
+    next_id: u32,
+    pending: HashMap<(PeerId, u32), Rc<RefCell<CallSlot>>>,
+}
+
+impl Calls {
+    /// Fails the pending calls to the peer `id`.
+    pub(crate) fn fail_peer(&mut self, id: PeerId) {
+        self.pending.retain(|(peer_id, _), slot| {
+            if *peer_id == id {
+                slot.borrow_mut().resolve(Err(RpcError::Disconnected));
+            }
+
+            *peer_id != id
+        });
+    }
+}
+
+/// Answers requests from peers and resolves the responses to calls, once set
+/// on a `Host` using `Host::set_rpc`.
+///
+/// Requests and responses are sent reliably on a dedicated channel, and
+/// encoded using the `DefaultCodec`. Packets on that channel are consumed by
+/// the layer, and never handed to the application. Calls are made using
+/// `Peer::call`, which returns a `CallHandle` for the response.
+///
+/// Pending calls fail with `RpcError::Timeout` once their timeout passes, and
+/// with `RpcError::Disconnected` once the peer disconnects. Both are detected
+/// while servicing the `Host`.
+pub struct Rpc<T> {
+    handlers: HashMap<u16, Handler<T>>,
+    calls: Rc<RefCell<Calls>>,
+}
+
+impl<T> Rpc<T> {
+    /// Creates a new `Rpc` layer without handlers, which uses `channel_id`
+    /// exclusively.
+    pub fn new(channel_id: u8) -> Rpc<T> {
+        Rpc {
+            handlers: HashMap::new(),
+            calls: Rc::new(RefCell::new(Calls {
+                channel_id,
+                next_id: 0,
+                pending: HashMap::new(),
+            })),
+        }
+    }
+
+    /// Registers `handler` for requests of type `R`, replacing any previous
+    /// handler for `R::METHOD`.
+    pub fn with_handler<R, F>(mut self, mut handler: F) -> Rpc<T>
+    where
+        R: Request,
+        F: FnMut(&mut Peer<'_, T>, R) -> R::Response + 'static,
+    {
+        let handler = move |peer: &mut Peer<'_, T>, data: &[u8]| {
+            let codec = DefaultCodec::default();
+            let request = codec.decode(data).map_err(|_| STATUS_BAD_REQUEST)?;
+
+            // a response that can not be encoded is the handler's fault, but
+            // the caller should still get an answer
+            codec
+                .encode(&handler(peer, request))
+                .map_err(|_| STATUS_INTERNAL_ERROR)
+        };
+
+        self.handlers.insert(R::METHOD, Box::new(handler));
+        self
+    }
+
+    /// Fails all calls whose timeout passed.
+    pub(crate) fn expire(&mut self, now: Instant) {
+        self.calls.borrow_mut().pending.retain(|_, slot| {
+            let mut slot = slot.borrow_mut();
+            let expired = slot.deadline <= now;
+
+            if expired {
+                slot.resolve(Err(RpcError::Timeout));
+            }
+
+            !expired
+        });
+    }
+
+    /// Fails the pending calls to the peer `id` with
+    /// `RpcError::Disconnected`, once its connection ended.
+    pub(crate) fn remove_peer(&mut self, id: PeerId) {
+        self.calls.borrow_mut().fail_peer(id);
+    }
+
+    /// Runs the rpc layer for an event, returning the event that should be
+    /// handed to the application instead, if any.
+    pub(crate) fn process_event<'a>(&mut self, event: Event<'a, T>) -> Option<Event<'a, T>> {
+        let channel_id = self.calls.borrow().channel_id;
+
+        match &event {
+            Event::Connect(peer) | Event::Authenticated(peer) | Event::Resumed(peer) => {
+                peer.clone().peer_data_mut().rpc = Some(self.calls.clone());
+                Some(event)
+            }
+            Event::Receive {
+                sender,
+                channel_id: packet_channel_id,
+                packet,
+            } if *packet_channel_id == channel_id => {
+                let mut peer = sender.clone();
+
+                match packet.data().split_first() {
+                    Some((&MSG_REQUEST, rest)) if rest.len() >= 6 => {
+                        let (header, data) = rest.split_at(6);
+                        let method = u16::from_be_bytes([header[4], header[5]]);
+
+                        let (status, payload) = match self.handlers.get_mut(&method) {
+                            Some(handler) => match handler(&mut peer, data) {
+                                Ok(payload) => (STATUS_OK, payload),
+                                Err(status) => (status, Vec::new()),
+                            },
+                            None => (STATUS_UNKNOWN_METHOD, Vec::new()),
+                        };
+
+                        let mut message = vec![MSG_RESPONSE];
+                        message.extend_from_slice(&header[..4]);
+                        message.push(status);
+                        message.extend_from_slice(&payload);
+
+                        // the caller times out if this fails
+                        let _ = Packet::new(&message, PacketMode::ReliableSequenced)
+                            .map_err(SendError::from)
+                            .and_then(|packet| peer.try_send_packet(packet, channel_id));
+                    }
+                    Some((&MSG_RESPONSE, rest)) if rest.len() >= 5 => {
+                        let call_id = u32::from_be_bytes(rest[..4].try_into().unwrap());
+                        let slot = self
+                            .calls
+                            .borrow_mut()
+                            .pending
+                            .remove(&(peer.id(), call_id));
+
+                        if let Some(slot) = slot {
+                            let result = match rest[4] {
+                                STATUS_OK => Ok(rest[5..].to_vec()),
+                                STATUS_UNKNOWN_METHOD => Err(RpcError::UnknownMethod),
+                                STATUS_BAD_REQUEST => Err(RpcError::BadRequest),
+                                _ => Err(RpcError::Internal),
+                            };
+
+                            slot.borrow_mut().resolve(result);
+                        }
+                    }
+                    _ => (),
+                }
+
+                None
+            }
+            _ => Some(event),
+        }
+    }
+}
+
+/// A call that was made using `Peer::call`, which resolves to the response.
+///
+/// The result can be polled using `CallHandle::try_take`, or awaited, as
+/// `CallHandle` implements `Future`. It is only resolved while the `Host` of
+/// the peer is serviced.
+pub struct CallHandle<R> {
+    slot: Rc<RefCell<CallSlot>>,
+    _response: PhantomData<fn() -> R>,
+}
+
+impl<R: DeserializeOwned> CallHandle<R> {
+    /// Returns whether the call has completed, successfully or not.
+    pub fn is_done(&self) -> bool {
+        self.slot.borrow().result.is_some()
+    }
+
+    /// Returns the result of the call if it has completed, decoding the
+    /// response. The result can only be taken once.
+    pub fn try_take(&mut self) -> Option<Result<R, RpcError>> {
+        let result = self.slot.borrow_mut().result.take()?;
+
+        Some(result.and_then(|data| {
+            DefaultCodec::default()
+                .decode(&data)
+                .map_err(RpcError::Decode)
+        }))
+    }
+}
+
+impl<R: DeserializeOwned> Future for CallHandle<R> {
+    type Output = Result<R, RpcError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        match self.try_take() {
+            Some(result) => Poll::Ready(result),
+            None => {
+                self.slot.borrow_mut().waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl<'a, T> Peer<'a, T> {
+    /// Sends `request` to this peer, returning a `CallHandle` that resolves to
+    /// the response, or fails after `timeout`.
+    ///
+    /// Requires an `Rpc` layer on the `Host` of this peer, see `Host::set_rpc`.
+    pub fn call<R: Request>(
+        &mut self,
+        request: &R,
+        timeout: Duration,
+    ) -> Result<CallHandle<R::Response>, RpcError> {
+        let calls = self
+            .peer_data_mut()
+            .rpc
+            .clone()
+            .ok_or(RpcError::NotEnabled)?;
+        let mut calls = calls.borrow_mut();
+
+        let call_id = calls.next_id;
+
+        let mut message = vec![MSG_REQUEST];
+        message.extend_from_slice(&call_id.to_be_bytes());
+        message.extend_from_slice(&R::METHOD.to_be_bytes());
+        message.extend(
+            DefaultCodec::default()
+                .encode(request)
+                .map_err(|err| RpcError::Send(MessageError::Encode(err)))?,
+        );
+
+        Packet::new(&message, PacketMode::ReliableSequenced)
+            .map_err(SendError::from)
+            .and_then(|packet| self.try_send_packet(packet, calls.channel_id))
+            .map_err(|err| RpcError::Send(err.into()))?;
+
+        let slot = Rc::new(RefCell::new(CallSlot {
+            deadline: Instant::now() + timeout,
+            result: None,
+            waker: None,
+        }));
+
+        calls.next_id = calls.next_id.wrapping_add(1);
+        calls.pending.insert((self.id(), call_id), slot.clone());
+
+        Ok(CallHandle {
+            slot,
+            _response: PhantomData,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use serde::{ser::Error, Deserialize, Serialize, Serializer};
+
+    use super::{Request, Rpc, RpcError};
+    use crate::{
+        tests::{connect_pair, create_host, create_server},
+        Sessions,
+    };
+
+    #[derive(Serialize, Deserialize)]
+    struct Add(u32, u32);
+
+    impl Request for Add {
+        const METHOD: u16 = 1;
+        type Response = u32;
+    }
+
+    #[derive(Serialize, Deserialize)]
+    struct Ping;
+
+    impl Request for Ping {
+        const METHOD: u16 = 2;
+        type Response = ();
+    }
+
+    #[derive(Deserialize)]
+    struct Unencodable;
+
+    impl Serialize for Unencodable {
+        fn serialize<S: Serializer>(&self, _: S) -> Result<S::Ok, S::Error> {
+            Err(S::Error::custom("unencodable"))
+        }
+    }
+
+    #[derive(Serialize, Deserialize)]
+    struct Fail;
+
+    impl Request for Fail {
+        const METHOD: u16 = 3;
+        type Response = Unencodable;
+    }
+
+    #[test]
+    fn test_calls() {
+        let mut server = create_server(1);
+        server.set_rpc(
+            Rpc::new(1)
+                .with_handler(|_, Add(a, b)| a + b)
+                .with_handler(|_, Fail| Unencodable),
+        );
+
+        let mut client = create_host::<()>(None, 1);
+        client.set_rpc(Rpc::new(1));
+        let (_, id) = connect_pair(&mut server, &mut client, 2);
+
+        let mut peer = client.peer(id).unwrap();
+        let mut add = peer.call(&Add(2, 3), Duration::from_secs(5)).unwrap();
+        let mut ping = peer.call(&Ping, Duration::from_secs(5)).unwrap();
+        let mut fail = peer.call(&Fail, Duration::from_secs(5)).unwrap();
+
+        for _ in 0..100 {
+            server.service(5).unwrap();
+            client.service(5).unwrap();
+
+            if add.is_done() && ping.is_done() && fail.is_done() {
+                break;
+            }
+        }
+
+        assert_eq!(add.try_take().unwrap().unwrap(), 5);
+        assert!(add.try_take().is_none());
+        assert!(matches!(
+            ping.try_take(),
+            Some(Err(RpcError::UnknownMethod))
+        ));
+        assert!(matches!(fail.try_take(), Some(Err(RpcError::Internal))));
+    }
+
+    #[test]
+    fn test_timeout() {
+        // the server does not answer requests
+        let mut server = create_server(1);
+
+        let mut client = create_host::<()>(None, 1);
+        client.set_rpc(Rpc::new(0));
+        let (_, id) = connect_pair(&mut server, &mut client, 1);
+
+        let peer = client.peer(id).unwrap();
+        let mut call = peer.clone().call(&Ping, Duration::from_millis(50)).unwrap();
+
+        for _ in 0..100 {
+            server.service(5).unwrap();
+            client.service(5).unwrap();
+
+            if call.is_done() {
+                break;
+            }
+        }
+
+        assert!(matches!(call.try_take(), Some(Err(RpcError::Timeout))));
+    }
+
+    #[test]
+    fn test_disconnected() {
+        // the server keeps the session of the client, so no disconnect event
+        // is emitted once it times out
+        let mut server = create_server(1);
+        server.set_sessions(Sessions::server(Duration::from_secs(5)));
+        server.set_rpc(Rpc::new(1));
+
+        // the client does not answer requests
+        let mut client = create_host::<()>(None, 1);
+        client.set_sessions(Sessions::client());
+        let (id, _) = connect_pair(&mut server, &mut client, 2);
+
+        let mut peer = server.peer(id).unwrap();
+        let min = Duration::from_millis(50);
+        peer.set_timeout(1, min, min * 4);
+
+        let mut call = peer.call(&Ping, Duration::from_secs(5)).unwrap();
+        drop(client);
+
+        for _ in 0..200 {
+            assert!(server.service(5).unwrap().is_none());
+
+            if call.is_done() {
+                break;
+            }
+        }
+
+        assert!(matches!(call.try_take(), Some(Err(RpcError::Disconnected))));
+    }
+
+    #[test]
+    fn test_disconnect_now() {
+        let mut server = create_server(1);
+
+        let mut client = create_host::<()>(None, 1);
+        client.set_rpc(Rpc::new(0));
+        let (_, id) = connect_pair(&mut server, &mut client, 1);
+
+        let mut peer = client.peer(id).unwrap();
+        let mut call = peer.call(&Ping, Duration::from_secs(5)).unwrap();
+        peer.disconnect_now(0);
+
+        assert!(matches!(call.try_take(), Some(Err(RpcError::Disconnected))));
+    }
+}
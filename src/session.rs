@@ -0,0 +1,731 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    net::SocketAddrV4,
+    time::{Duration, Instant},
+};
+
+use enet_sys::ENetHost;
+use rand_core::{OsRng, RngCore};
+
+use crate::{
+    early::{EarlyPacket, EarlyPackets},
+    host::peer_at,
+    Event, Packet, PacketMode, Peer, PeerId, RejectReason, SendError,
+};
+
+const MAGIC: &[u8; 4] = b"ENRS";
+const MSG_HELLO: u8 = 1;
+const MSG_SESSION: u8 = 2;
+
+const TOKEN_LEN: usize = 16;
+
+type Token = [u8; TOKEN_LEN];
+
+/// Identifies a session on a server using `Sessions`.
+///
+/// Unlike a `PeerId`, a `SessionId` stays the same when a client resumes its
+/// session over a new connection.
+#[derive(Debug, Copy, Clone, Hash, PartialEq, Eq, PartialOrd, Ord)]
+pub struct SessionId(u64);
+
+/// An error that can occur when sending to a session using
+/// `Host::send_to_session`.
+#[derive(thiserror::Error, Debug)]
+pub enum SessionError {
+    /// The `Host` has no `Sessions` layer, or only its client side.
+    #[error("sessions are not enabled for this host")]
+    NotEnabled,
+    /// The session does not exist, or has expired.
+    #[error("unknown session")]
+    UnknownSession,
+    /// The client is away, and too many packets are already queued for it.
+    #[error("too many packets queued for the session")]
+    QueueFull,
+    /// The packet could not be queued for the connected client.
+    #[error("failed to send packet")]
+    Send(#[source] SendError),
+}
+
+enum Role<T> {
+    Client {
+        tokens: HashMap<SocketAddrV4, Token>,
+    },
+    Server {
+        grace: Duration,
+        max_queued: usize,
+        next_id: u64,
+        sessions: HashMap<SessionId, Session<T>>,
+        by_token: HashMap<Token, SessionId>,
+        by_peer: HashMap<PeerId, SessionId>,
+    },
+}
+
+struct Session<T> {
+    token: Token,
+    state: SessionState<T>,
+}
+
+enum SessionState<T> {
+    Connected(PeerId),
+    Away {
+        until: Instant,
+        data: Option<T>,
+        queued: Vec<(u8, Packet)>,
+    },
+}
+
+#[derive(Debug)]
+enum PeerSession {
+    /// Waiting for the session message, `authenticated` tells which event
+    /// was held back. `early` holds the packets that overtook the session
+    /// message on another channel.
+    Pending {
+        since: Instant,
+        authenticated: bool,
+        early: EarlyPackets,
+    },
+    Rejected,
+}
+
+/// An event that was queued by the session layer.
+enum Queued<T> {
+    Receive { id: PeerId, packet: EarlyPacket },
+    Expired { session: SessionId, data: Option<T> },
+}
+
+/// An opt-in session layer that lets clients resume their session after a
+/// brief loss of connection.
+///
+/// Once set on both sides using `Host::set_sessions`, the server issues an
+/// opaque resume token to each new client. If the connection of a client
+/// times out, the server keeps the data of its `Peer` for a grace period,
+/// instead of emitting `Event::Disconnect`. When the client connects to the
+/// same address again within that period, it presents its token, and both
+/// sides emit `Event::Resumed` instead of `Event::Connect`. The new `Peer` on
+/// the server has the data of the previous one.
+///
+/// While a client is away, reliable packets can still be sent to its session
+/// using `Host::send_to_session`. They are queued and delivered once the
+/// session is resumed. If the grace period ends first, the server emits
+/// `Event::SessionExpired` with the data of the session.
+///
+/// Sessions only survive timeouts. Disconnections that were requested by
+/// either side end the session immediately. A token presented while the
+/// session is still connected, e.g. because the server did not notice the
+/// timeout yet, starts a new session instead, so a live connection is never
+/// taken over.
+///
+/// Tokens are drawn from the operating system's random number generator,
+/// but sent in plaintext. Use `Encryption` to keep them from being observed.
+pub struct Sessions<T> {
+    role: Role<T>,
+    channel_id: u8,
+    timeout: Duration,
+    peers: HashMap<PeerId, PeerSession>,
+    queued: VecDeque<Queued<T>>,
+}
+
+impl<T> Sessions<T> {
+    /// Creates the client side of the session layer, which remembers the
+    /// token of each server address it connected to.
+    pub fn client() -> Sessions<T> {
+        Sessions::new(Role::Client {
+            tokens: HashMap::new(),
+        })
+    }
+
+    /// Creates the server side of the session layer, which keeps the
+    /// sessions of timed out clients for `grace`.
+    pub fn server(grace: Duration) -> Sessions<T> {
+        Sessions::new(Role::Server {
+            grace,
+            max_queued: 256,
+            next_id: 0,
+            sessions: HashMap::new(),
+            by_token: HashMap::new(),
+            by_peer: HashMap::new(),
+        })
+    }
+
+    fn new(role: Role<T>) -> Sessions<T> {
+        Sessions {
+            role,
+            channel_id: 0,
+            timeout: Duration::from_secs(5),
+            peers: HashMap::new(),
+            queued: VecDeque::new(),
+        }
+    }
+
+    /// Sets the channel session messages are sent on, defaults to `0`.
+    pub fn with_channel(mut self, channel_id: u8) -> Sessions<T> {
+        self.channel_id = channel_id;
+        self
+    }
+
+    /// Sets how long a new connection may take to set up its session,
+    /// defaults to 5 seconds.
+    pub fn with_timeout(mut self, timeout: Duration) -> Sessions<T> {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Sets how many packets are queued for a session while its client is
+    /// away, defaults to 256. Only used by servers.
+    pub fn with_max_queued(mut self, max_queued: usize) -> Sessions<T> {
+        if let Role::Server {
+            max_queued: max, ..
+        } = &mut self.role
+        {
+            *max = max_queued;
+        }
+        self
+    }
+
+    /// Returns the ids of all peers that did not set up their session in
+    /// time, and ends all sessions whose grace period is over.
+    pub(crate) fn expire(&mut self, now: Instant) -> Vec<PeerId> {
+        let mut expired = Vec::new();
+
+        for (id, state) in self.peers.iter_mut() {
+            if let PeerSession::Pending { since, .. } = state {
+                if now.duration_since(*since) >= self.timeout {
+                    *state = PeerSession::Rejected;
+                    expired.push(*id);
+                }
+            }
+        }
+
+        if let Role::Server {
+            sessions, by_token, ..
+        } = &mut self.role
+        {
+            let ended = sessions
+                .iter()
+                .filter(|(_, session)| {
+                    matches!(session.state, SessionState::Away { until, .. } if until <= now)
+                })
+                .map(|(id, _)| *id)
+                .collect::<Vec<_>>();
+
+            for id in ended {
+                let session = sessions.remove(&id).unwrap();
+                by_token.remove(&session.token);
+
+                if let SessionState::Away { data, .. } = session.state {
+                    self.queued.push_back(Queued::Expired { session: id, data });
+                }
+            }
+        }
+
+        expired
+    }
+
+    /// Returns the next event that was queued by the session layer, if any.
+    pub(crate) fn pop_event<'a>(&mut self, host: *mut ENetHost) -> Option<Event<'a, T>> {
+        loop {
+            match self.queued.pop_front()? {
+                Queued::Receive { id, packet: early } => {
+                    // an allocation failure loses the packet
+                    if let Ok(packet) = Packet::new(&early.data, early.mode) {
+                        return Some(Event::Receive {
+                            sender: peer_at(host, id),
+                            channel_id: early.channel_id,
+                            packet,
+                        });
+                    }
+                }
+                Queued::Expired { session, data } => {
+                    return Some(Event::SessionExpired { session, data });
+                }
+            }
+        }
+    }
+
+    /// Sends `packet` to the client of `session`, or queues it if the client
+    /// is away. Unreliable packets are dropped while the client is away.
+    pub(crate) fn send(
+        &mut self,
+        host: *mut ENetHost,
+        session: SessionId,
+        channel_id: u8,
+        packet: Packet,
+    ) -> Result<(), SessionError> {
+        let (sessions, max_queued) = match &mut self.role {
+            Role::Server {
+                sessions,
+                max_queued,
+                ..
+            } => (sessions, *max_queued),
+            Role::Client { .. } => return Err(SessionError::NotEnabled),
+        };
+
+        let session = sessions
+            .get_mut(&session)
+            .ok_or(SessionError::UnknownSession)?;
+
+        match &mut session.state {
+            SessionState::Connected(id) => peer_at::<T>(host, *id)
+                .try_send_packet(packet, channel_id)
+                .map_err(SessionError::Send),
+            SessionState::Away { .. } if !packet.mode().is_reliable() => Ok(()),
+            SessionState::Away { queued, .. } if queued.len() >= max_queued => {
+                Err(SessionError::QueueFull)
+            }
+            SessionState::Away { queued, .. } => {
+                queued.push((channel_id, packet));
+                Ok(())
+            }
+        }
+    }
+
+    /// Runs the session layer for an event, returning the event that should
+    /// be handed to the application instead, if any.
+    pub(crate) fn process_event<'a>(&mut self, event: Event<'a, T>) -> Option<Event<'a, T>> {
+        match &event {
+            Event::Connect(peer) | Event::Authenticated(peer) => {
+                let mut peer = peer.clone();
+
+                if let Role::Client { tokens } = &self.role {
+                    let token = tokens.get(&socket_addr(&peer));
+                    let sent = Packet::new(&hello_message(token), PacketMode::ReliableSequenced)
+                        .map_err(SendError::from)
+                        .and_then(|packet| peer.try_send_packet(packet, self.channel_id));
+
+                    if sent.is_err() {
+                        peer.disconnect(RejectReason::Malformed.code());
+                    }
+                }
+
+                let pending = PeerSession::Pending {
+                    since: Instant::now(),
+                    authenticated: matches!(event, Event::Authenticated(_)),
+                    early: EarlyPackets::default(),
+                };
+                self.peers.insert(peer.id(), pending);
+
+                None
+            }
+            Event::Disconnect(peer, reason) => {
+                let id = peer.id();
+                let state = self.peers.remove(&id);
+                self.queued
+                    .retain(|queued| !matches!(queued, Queued::Receive { id: i, .. } if *i == id));
+
+                match &mut self.role {
+                    Role::Client { tokens } => {
+                        // the server ends the session on anything but a timeout
+                        if !reason.is_timeout() {
+                            tokens.remove(&socket_addr(peer));
+                        }
+
+                        Some(event)
+                    }
+                    Role::Server { .. } if state.is_some() => None,
+                    Role::Server {
+                        grace,
+                        sessions,
+                        by_token,
+                        by_peer,
+                        ..
+                    } => {
+                        let session_id = match by_peer.remove(&id) {
+                            Some(session_id) => session_id,
+                            None => return Some(event),
+                        };
+
+                        if !reason.is_timeout() {
+                            if let Some(session) = sessions.remove(&session_id) {
+                                by_token.remove(&session.token);
+                            }
+
+                            return Some(event);
+                        }
+
+                        let data = peer.clone().take_data();
+
+                        if let Some(session) = sessions.get_mut(&session_id) {
+                            session.state = SessionState::Away {
+                                until: Instant::now() + *grace,
+                                data,
+                                queued: Vec::new(),
+                            };
+                        }
+
+                        None
+                    }
+                }
+            }
+            Event::Receive {
+                sender,
+                channel_id,
+                packet,
+            } => {
+                let id = sender.id();
+
+                let authenticated = match self.peers.get(&id) {
+                    Some(PeerSession::Pending { authenticated, .. }) => *authenticated,
+                    Some(PeerSession::Rejected) => return None,
+                    None => return Some(event),
+                };
+
+                let mut peer = sender.clone();
+
+                if let Role::Client { tokens } = &mut self.role {
+                    let (resumed, token) = match decode_session_message(packet.data()) {
+                        Some(session) if *channel_id == self.channel_id => session,
+                        _ => {
+                            // might have overtaken the session message on another channel
+                            if let Some(PeerSession::Pending { early, .. }) =
+                                self.peers.get_mut(&id)
+                            {
+                                if early.push(*channel_id, packet).is_err() {
+                                    self.peers.insert(id, PeerSession::Rejected);
+                                    peer.disconnect(RejectReason::RateLimited.code());
+                                }
+                            }
+
+                            return None;
+                        }
+                    };
+
+                    tokens.insert(socket_addr(&peer), token);
+
+                    if let Some(PeerSession::Pending { early, .. }) = self.peers.remove(&id) {
+                        self.queued.extend(
+                            early
+                                .into_packets()
+                                .map(|packet| Queued::Receive { id, packet }),
+                        );
+                    }
+
+                    return Some(connected_event(peer, resumed, authenticated));
+                }
+
+                let token = match decode_hello_message(packet.data()) {
+                    Some(token) if *channel_id == self.channel_id => token,
+                    _ => {
+                        self.peers.insert(id, PeerSession::Rejected);
+                        peer.disconnect(RejectReason::Malformed.code());
+                        return None;
+                    }
+                };
+
+                self.peers.remove(&id);
+
+                let resumed = match token {
+                    Some(token) => self.resume(&mut peer, &token),
+                    None => false,
+                };
+
+                if !resumed {
+                    self.create(&mut peer);
+                }
+
+                Some(connected_event(peer, resumed, authenticated))
+            }
+            Event::Resumed(_)
+            | Event::SessionExpired { .. }
+            | Event::Transfer { .. }
+            | Event::Resolved { .. }
+            | Event::ResolveFailed { .. }
+            | Event::ConnectFailed { .. } => Some(event),
+        }
+    }
+
+    /// Starts a new session for `peer` and sends it the token.
+    fn create(&mut self, peer: &mut Peer<'_, T>) {
+        let Role::Server {
+            next_id,
+            sessions,
+            by_token,
+            by_peer,
+            ..
+        } = &mut self.role
+        else {
+            return;
+        };
+
+        let session_id = SessionId(*next_id);
+        *next_id += 1;
+
+        let mut token = [0; TOKEN_LEN];
+        OsRng.fill_bytes(&mut token);
+
+        sessions.insert(
+            session_id,
+            Session {
+                token,
+                state: SessionState::Connected(peer.id()),
+            },
+        );
+        by_token.insert(token, session_id);
+        by_peer.insert(peer.id(), session_id);
+
+        peer.peer_data_mut().session = Some(session_id);
+        self.send_session_message(peer, false, &token);
+    }
+
+    /// Moves the session with `token` over to `peer`, if it exists and its
+    /// client is away. Returns whether the session was resumed.
+    fn resume(&mut self, peer: &mut Peer<'_, T>, token: &Token) -> bool {
+        let Role::Server {
+            sessions,
+            by_token,
+            by_peer,
+            ..
+        } = &mut self.role
+        else {
+            return false;
+        };
+
+        let session_id = match by_token.get(token) {
+            Some(session_id) => *session_id,
+            None => return false,
+        };
+        let session = sessions.get_mut(&session_id).unwrap();
+
+        let (data, queued) = match &mut session.state {
+            // a live connection is never handed to whoever presents its token
+            SessionState::Connected(_) => return false,
+            SessionState::Away { data, queued, .. } => (data.take(), std::mem::take(queued)),
+        };
+        session.state = SessionState::Connected(peer.id());
+
+        by_peer.insert(peer.id(), session_id);
+
+        let peer_data = peer.peer_data_mut();
+        peer_data.user = data;
+        peer_data.session = Some(session_id);
+
+        self.send_session_message(peer, true, token);
+
+        for (channel_id, packet) in queued {
+            // the client drops the connection if this fails, so this is not
+            // worse than losing the packets
+            let _ = peer.send_packet(packet, channel_id);
+        }
+
+        true
+    }
+
+    fn send_session_message(&self, peer: &mut Peer<'_, T>, resumed: bool, token: &Token) {
+        let sent = Packet::new(
+            &session_message(resumed, token),
+            PacketMode::ReliableSequenced,
+        )
+        .map_err(SendError::from)
+        .and_then(|packet| peer.try_send_packet(packet, self.channel_id));
+
+        if sent.is_err() {
+            peer.disconnect(RejectReason::Malformed.code());
+        }
+    }
+}
+
+fn connected_event<'a, T>(peer: Peer<'a, T>, resumed: bool, authenticated: bool) -> Event<'a, T> {
+    match (resumed, authenticated) {
+        (true, _) => Event::Resumed(peer),
+        (false, true) => Event::Authenticated(peer),
+        (false, false) => Event::Connect(peer),
+    }
+}
+
+fn socket_addr<T>(peer: &Peer<'_, T>) -> SocketAddrV4 {
+    let address = peer.address();
+    SocketAddrV4::new(*address.ip(), address.port())
+}
+
+fn hello_message(token: Option<&Token>) -> Vec<u8> {
+    let mut data = MAGIC.to_vec();
+    data.push(MSG_HELLO);
+    if let Some(token) = token {
+        data.extend_from_slice(token);
+    }
+    data
+}
+
+/// Decodes a hello message, which contains the token of the session to
+/// resume, if any.
+fn decode_hello_message(data: &[u8]) -> Option<Option<Token>> {
+    match data.strip_prefix(MAGIC)?.strip_prefix(&[MSG_HELLO])? {
+        [] => Some(None),
+        token => Some(Some(token.try_into().ok()?)),
+    }
+}
+
+fn session_message(resumed: bool, token: &Token) -> Vec<u8> {
+    let mut data = MAGIC.to_vec();
+    data.push(MSG_SESSION);
+    data.push(u8::from(resumed));
+    data.extend_from_slice(token);
+    data
+}
+
+fn decode_session_message(data: &[u8]) -> Option<(bool, Token)> {
+    let rest = data.strip_prefix(MAGIC)?.strip_prefix(&[MSG_SESSION])?;
+    let (&resumed, token) = rest.split_first()?;
+
+    Some((resumed != 0, token.try_into().ok()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{
+        decode_hello_message, decode_session_message, hello_message, session_message, Sessions,
+    };
+    use crate::{
+        tests::{create_host, create_server},
+        Event, Host, Packet, PacketMode, PeerState,
+    };
+
+    #[test]
+    fn test_messages() {
+        let token = [7; 16];
+
+        assert_eq!(decode_hello_message(&hello_message(None)), Some(None));
+        assert_eq!(
+            decode_hello_message(&hello_message(Some(&token))),
+            Some(Some(token))
+        );
+        assert_eq!(decode_hello_message(&hello_message(None)[..4]), None);
+        assert_eq!(decode_hello_message(&session_message(true, &token)), None);
+
+        assert_eq!(
+            decode_session_message(&session_message(true, &token)),
+            Some((true, token))
+        );
+        assert_eq!(
+            decode_session_message(&session_message(false, &token)[..20]),
+            None
+        );
+    }
+
+    #[test]
+    fn test_resume_after_timeout() {
+        let mut server: Host<u32> = create_server(2);
+        server.set_sessions(Sessions::server(Duration::from_secs(5)));
+        let server_addr = server.address();
+        let mut client: Host<u32> = create_host(None, 2);
+        client.set_sessions(Sessions::client());
+        client.connect(&server_addr, 1, 0).unwrap();
+
+        // connect, and remember the session of the client
+        let mut session = None;
+        let mut client_connected = false;
+
+        for _ in 0..100 {
+            if let Some(Event::Connect(ref peer)) = server.service(5).unwrap() {
+                let mut peer = peer.clone();
+                peer.set_data(Some(42));
+                peer.set_timeout(1, Duration::from_millis(50), Duration::from_millis(100));
+                session = peer.session();
+            }
+
+            if let Some(Event::Connect(_)) = client.service(5).unwrap() {
+                client_connected = true;
+            }
+
+            if session.is_some() && client_connected {
+                break;
+            }
+        }
+
+        let session = session.unwrap();
+
+        // the client loses its connection, so the server times out
+        client.peers().for_each(|peer| peer.reset());
+
+        let ping = Packet::new(b"ping", PacketMode::ReliableSequenced).unwrap();
+        server.send_to_session(session, 0, ping).unwrap();
+
+        for _ in 0..200 {
+            assert!(server.service(5).unwrap().is_none());
+
+            if server
+                .peers()
+                .all(|peer| peer.state() == PeerState::Disconnected)
+            {
+                break;
+            }
+        }
+
+        let queued = Packet::new(b"queued", PacketMode::ReliableSequenced).unwrap();
+        server.send_to_session(session, 0, queued).unwrap();
+
+        // resume the session with a new connection
+        client.connect(&server_addr, 1, 0).unwrap();
+
+        let (mut server_resumed, mut client_resumed, mut received) = (false, false, false);
+
+        for _ in 0..100 {
+            if let Some(Event::Resumed(ref peer)) = server.service(5).unwrap() {
+                assert_eq!(peer.data(), Some(&42));
+                assert_eq!(peer.session(), Some(session));
+                server_resumed = true;
+            }
+
+            match client.service(5).unwrap() {
+                Some(Event::Resumed(_)) => client_resumed = true,
+                Some(Event::Receive { ref packet, .. }) => {
+                    assert!(client_resumed);
+                    assert_eq!(packet.data(), b"queued");
+                    received = true;
+                }
+                _ => (),
+            }
+
+            if server_resumed && received {
+                break;
+            }
+        }
+
+        assert!(server_resumed && client_resumed && received);
+    }
+
+    #[test]
+    fn test_connected_session_not_taken_over() {
+        let mut server: Host<u32> = create_server(2);
+        server.set_sessions(Sessions::server(Duration::from_secs(5)));
+        let server_addr = server.address();
+        let mut client: Host<u32> = create_host(None, 2);
+        client.set_sessions(Sessions::client());
+
+        let mut sessions = Vec::new();
+
+        // the second connection presents the token of the first one, which is
+        // still connected
+        for connection in 1..=2 {
+            client.connect(&server_addr, 1, 0).unwrap();
+            let mut client_connected = false;
+
+            for _ in 0..100 {
+                match server.service(5).unwrap() {
+                    Some(Event::Connect(ref peer)) => sessions.push(peer.session().unwrap()),
+                    Some(Event::Resumed(_)) => panic!("a connected session was resumed"),
+                    _ => (),
+                }
+
+                if let Some(Event::Connect(_)) = client.service(5).unwrap() {
+                    client_connected = true;
+                }
+
+                if client_connected && sessions.len() == connection {
+                    break;
+                }
+            }
+        }
+
+        assert_eq!(sessions.len(), 2);
+        assert_ne!(sessions[0], sessions[1]);
+        assert_eq!(
+            server
+                .peers()
+                .filter(|peer| peer.state() == PeerState::Connected)
+                .count(),
+            2
+        );
+    }
+}
@@ -0,0 +1,533 @@
+use std::collections::{HashMap, VecDeque};
+
+use crate::{Event, Packet, PacketMode, Peer, PeerId, SendError};
+
+const MSG_SNAPSHOT: u8 = 1;
+const MSG_ACK: u8 = 2;
+const MSG_NACK: u8 = 3;
+
+/// The baseline of a snapshot that is sent in full.
+const NO_BASELINE: u32 = 0;
+
+/// Unchanged runs shorter than this are sent as part of the surrounding
+/// changes, since starting a new run costs at least two bytes.
+const MIN_GAP: usize = 4;
+
+#[derive(Default)]
+struct PeerSnapshots {
+    /// The last sequence number sent to the peer.
+    sent_seq: u32,
+    /// The snapshots sent to the peer that may still become a baseline.
+    sent: VecDeque<(u32, Vec<u8>)>,
+    /// The newest snapshot the peer acknowledged.
+    acked: Option<u32>,
+    /// The snapshots received from the peer that it may use as a baseline.
+    received: VecDeque<(u32, Vec<u8>)>,
+}
+
+/// An error that can occur when sending a snapshot using
+/// `Host::send_snapshot`.
+#[derive(thiserror::Error, Debug)]
+pub enum SnapshotError {
+    /// The `Host` has no `Snapshots` layer.
+    #[error("snapshots are not enabled for this host")]
+    NotEnabled,
+    /// The `PeerId` does not belong to the host.
+    #[error("unknown peer")]
+    UnknownPeer,
+    /// The snapshot could not be sent.
+    #[error("failed to send snapshot")]
+    Send(#[source] SendError),
+}
+
+/// Sends snapshots of a changing state, compressed against the last snapshot
+/// the receiver acknowledged, once set on a `Host` using
+/// `Host::set_snapshots`.
+///
+/// Each snapshot sent using `Host::send_snapshot` is encoded as a byte-level
+/// delta against the newest snapshot the peer acknowledged, or in full if
+/// there is no such baseline or it is older than the history. Snapshots are
+/// sent unreliably but sequenced, so lost snapshots are not resent, and the
+/// receiver acknowledges each one it decodes. If the receiver no longer has
+/// the baseline of a delta, e.g. because its `Snapshots` layer was replaced,
+/// it tells the sender, which sends the next snapshot in full.
+///
+/// The receiving `Host` decodes the snapshots, and hands them to the
+/// application as `Event::Receive` containing the full state, on the
+/// dedicated channel. Both sides of a connection have to use the same
+/// channel, and no other packets may be sent on it.
+pub struct Snapshots {
+    channel_id: u8,
+    history: usize,
+    max_size: usize,
+    max_retained: usize,
+    peers: HashMap<PeerId, PeerSnapshots>,
+}
+
+impl Snapshots {
+    /// Creates a new `Snapshots` layer, which uses `channel_id` exclusively.
+    pub fn new(channel_id: u8) -> Snapshots {
+        Snapshots {
+            channel_id,
+            history: 32,
+            max_size: 1 << 20,
+            max_retained: 2 << 20,
+            peers: HashMap::new(),
+        }
+    }
+
+    /// Sets how many snapshots are kept as possible baselines, defaults to 32.
+    /// Once a peer has not acknowledged any of them, snapshots are sent in
+    /// full.
+    pub fn with_history(mut self, history: usize) -> Snapshots {
+        self.history = history.max(1);
+        self
+    }
+
+    /// Sets the maximum size of a received snapshot in bytes, defaults to
+    /// 1 MiB. Larger snapshots, and deltas that claim to decode to larger
+    /// snapshots, are dropped, so a peer can not make the receiver allocate
+    /// arbitrary amounts of memory.
+    ///
+    /// The decoded snapshots kept as baselines are bounded separately, see
+    /// `with_max_retained`. Each peer can make the receiver keep at most the
+    /// larger of the two limits.
+    pub fn with_max_size(mut self, max_size: usize) -> Snapshots {
+        self.max_size = max_size;
+        self
+    }
+
+    /// Sets how many bytes of received snapshots are kept per peer as
+    /// possible baselines, defaults to 2 MiB. The oldest baselines are dropped
+    /// first, but the newest snapshot is always kept. Deltas against dropped
+    /// baselines are answered by requesting a full snapshot.
+    pub fn with_max_retained(mut self, max_retained: usize) -> Snapshots {
+        self.max_retained = max_retained;
+        self
+    }
+
+    /// Sends `state` to `peer`, as a delta if possible.
+    pub(crate) fn send<T>(
+        &mut self,
+        peer: &mut Peer<'_, T>,
+        state: &[u8],
+    ) -> Result<(), SnapshotError> {
+        let snapshots = self.peers.entry(peer.id()).or_default();
+
+        snapshots.sent_seq = snapshots.sent_seq.wrapping_add(1).max(1);
+        let seq = snapshots.sent_seq;
+
+        let baseline = snapshots.acked.and_then(|acked| {
+            snapshots
+                .sent
+                .iter()
+                .find(|(sent_seq, _)| *sent_seq == acked)
+        });
+
+        let mut message = vec![MSG_SNAPSHOT];
+        message.extend_from_slice(&seq.to_be_bytes());
+
+        match baseline.map(|(base_seq, base)| (base_seq, encode_delta(base, state))) {
+            Some((base_seq, delta)) if delta.len() < state.len() => {
+                message.extend_from_slice(&base_seq.to_be_bytes());
+                message.extend_from_slice(&delta);
+            }
+            _ => {
+                message.extend_from_slice(&NO_BASELINE.to_be_bytes());
+                message.extend_from_slice(state);
+            }
+        }
+
+        snapshots.sent.push_back((seq, state.to_vec()));
+        if snapshots.sent.len() > self.history {
+            snapshots.sent.pop_front();
+        }
+
+        Packet::new(&message, PacketMode::UnreliableSequenced)
+            .map_err(SendError::from)
+            .and_then(|packet| peer.try_send_packet(packet, self.channel_id))
+            .map_err(SnapshotError::Send)
+    }
+
+    /// Drops the baselines of the peer `id`, once its connection ended.
+    pub(crate) fn remove_peer(&mut self, id: PeerId) {
+        self.peers.remove(&id);
+    }
+
+    /// Runs the snapshot layer for an event, returning the event that should
+    /// be handed to the application instead, if any.
+    pub(crate) fn process_event<'a, T>(&mut self, event: Event<'a, T>) -> Option<Event<'a, T>> {
+        match &event {
+            Event::Receive {
+                sender,
+                channel_id,
+                packet,
+            } if *channel_id == self.channel_id => {
+                let mut peer = sender.clone();
+                let snapshots = self.peers.entry(peer.id()).or_default();
+                let data = packet.data();
+
+                match data.split_first() {
+                    Some((&MSG_SNAPSHOT, rest)) if rest.len() >= 8 => {
+                        let seq = u32::from_be_bytes(rest[..4].try_into().unwrap());
+                        let base_seq = u32::from_be_bytes(rest[4..8].try_into().unwrap());
+
+                        let state = if base_seq == NO_BASELINE {
+                            if rest.len() - 8 > self.max_size {
+                                return None;
+                            }
+
+                            rest[8..].to_vec()
+                        } else {
+                            let base = snapshots
+                                .received
+                                .iter()
+                                .find(|(received_seq, _)| *received_seq == base_seq);
+
+                            match base
+                                .and_then(|(_, base)| decode_delta(base, &rest[8..], self.max_size))
+                            {
+                                Some(state) => state,
+                                None => {
+                                    // the sender recovers with a full snapshot
+                                    send_control(&mut peer, self.channel_id, MSG_NACK, base_seq);
+                                    return None;
+                                }
+                            }
+                        };
+
+                        // older baselines are no longer used by the sender
+                        snapshots.received.retain(|(received_seq, _)| {
+                            seq.wrapping_sub(*received_seq) <= self.history as u32
+                                && *received_seq >= base_seq.min(seq)
+                        });
+                        snapshots.received.push_back((seq, state.clone()));
+
+                        let mut retained: usize = snapshots
+                            .received
+                            .iter()
+                            .map(|(_, state)| state.len())
+                            .sum();
+
+                        while retained > self.max_retained && snapshots.received.len() > 1 {
+                            let (_, oldest) = snapshots.received.pop_front().unwrap();
+                            retained -= oldest.len();
+                        }
+
+                        // the sender keeps using an older baseline if this fails
+                        send_control(&mut peer, self.channel_id, MSG_ACK, seq);
+
+                        let packet = Packet::new(&state, PacketMode::UnreliableSequenced).ok()?;
+
+                        Some(Event::Receive {
+                            sender: peer,
+                            channel_id: self.channel_id,
+                            packet,
+                        })
+                    }
+                    Some((&MSG_ACK, rest)) if rest.len() == 4 => {
+                        let seq = u32::from_be_bytes(rest.try_into().unwrap());
+
+                        if snapshots.acked.is_none_or(|acked| seq > acked) {
+                            snapshots.acked = Some(seq);
+                            snapshots.sent.retain(|(sent_seq, _)| *sent_seq >= seq);
+                        }
+
+                        None
+                    }
+                    Some((&MSG_NACK, rest)) if rest.len() == 4 => {
+                        let base_seq = u32::from_be_bytes(rest.try_into().unwrap());
+
+                        // newer baselines the peer acknowledged are still fine
+                        if snapshots.acked == Some(base_seq) {
+                            snapshots.acked = None;
+                            snapshots.sent.retain(|(sent_seq, _)| *sent_seq != base_seq);
+                        }
+
+                        None
+                    }
+                    _ => None,
+                }
+            }
+            _ => Some(event),
+        }
+    }
+}
+
+/// Sends an acknowledgement or negative acknowledgement of `seq` to `peer`,
+/// ignoring failures.
+fn send_control<T>(peer: &mut Peer<'_, T>, channel_id: u8, kind: u8, seq: u32) {
+    let mut message = vec![kind];
+    message.extend_from_slice(&seq.to_be_bytes());
+
+    let _ = Packet::new(&message, PacketMode::UnreliableSequenced)
+        .map_err(SendError::from)
+        .and_then(|packet| peer.try_send_packet(packet, channel_id));
+}
+
+/// Encodes `target` as a delta against `base`: the length of `target`,
+/// followed by runs of changed bytes, each prefixed by the number of
+/// unchanged bytes before it and its length.
+fn encode_delta(base: &[u8], target: &[u8]) -> Vec<u8> {
+    let base_at = |i: usize| base.get(i).copied().unwrap_or(0);
+
+    let mut delta = Vec::new();
+    write_varint(&mut delta, target.len());
+
+    let (mut pos, mut i) = (0, 0);
+
+    while i < target.len() {
+        if target[i] == base_at(i) {
+            i += 1;
+            continue;
+        }
+
+        let start = i;
+        let mut end = i + 1;
+
+        // extend the run over short gaps of unchanged bytes
+        while end < target.len() {
+            let gap = (end..target.len().min(end + MIN_GAP))
+                .take_while(|&j| target[j] == base_at(j))
+                .count();
+
+            match gap {
+                0 => end += 1,
+                gap if gap < MIN_GAP && end + gap < target.len() => end += gap,
+                _ => break,
+            }
+        }
+
+        write_varint(&mut delta, start - pos);
+        write_varint(&mut delta, end - start);
+        delta.extend_from_slice(&target[start..end]);
+
+        pos = end;
+        i = end;
+    }
+
+    delta
+}
+
+/// Decodes a delta created by `encode_delta`, returning `None` if it is
+/// malformed or decodes to more than `max_len` bytes.
+fn decode_delta(base: &[u8], mut delta: &[u8], max_len: usize) -> Option<Vec<u8>> {
+    let len = read_varint(&mut delta)?;
+
+    if len > max_len {
+        return None;
+    }
+
+    let mut target = base[..base.len().min(len)].to_vec();
+    target.resize(len, 0);
+
+    let mut pos = 0usize;
+
+    while !delta.is_empty() {
+        let start = pos.checked_add(read_varint(&mut delta)?)?;
+        let run = read_varint(&mut delta)?;
+        let end = start.checked_add(run)?;
+
+        if end > len || run > delta.len() {
+            return None;
+        }
+
+        let (bytes, rest) = delta.split_at(run);
+        target[start..end].copy_from_slice(bytes);
+
+        delta = rest;
+        pos = end;
+    }
+
+    Some(target)
+}
+
+fn write_varint(out: &mut Vec<u8>, mut value: usize) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+
+    out.push(value as u8);
+}
+
+fn read_varint(data: &mut &[u8]) -> Option<usize> {
+    let mut value = 0usize;
+
+    for shift in (0..usize::BITS).step_by(7) {
+        let (&byte, rest) = data.split_first()?;
+        *data = rest;
+
+        value |= ((byte & 0x7f) as usize).checked_shl(shift)?;
+
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode_delta, encode_delta, write_varint, Snapshots};
+    use crate::{
+        tests::{connect_pair, create_host, create_server},
+        Event, Host, NetworkConditioner,
+    };
+
+    const MAX: usize = 1 << 20;
+
+    #[test]
+    fn test_delta() {
+        let base: Vec<u8> = (0..200).collect();
+
+        let mut target = base.clone();
+        target[3] = 0xff;
+        target[5] = 0xff;
+        target[150..160].fill(0);
+        target.extend_from_slice(b"tail");
+
+        let delta = encode_delta(&base, &target);
+        assert!(delta.len() < 30, "delta is {} bytes", delta.len());
+        assert_eq!(decode_delta(&base, &delta, MAX).unwrap(), target);
+
+        let shorter = &base[..100];
+        assert_eq!(
+            decode_delta(&base, &encode_delta(&base, shorter), MAX).unwrap(),
+            shorter
+        );
+        assert_eq!(
+            decode_delta(&[], &encode_delta(&[], &base), MAX).unwrap(),
+            base
+        );
+        assert_eq!(decode_delta(&base, &[5, 10, 1], MAX), None);
+        assert_eq!(decode_delta(&base, &delta, target.len() - 1), None);
+    }
+
+    #[test]
+    fn test_oversized_delta() {
+        for len in [usize::MAX, 1 << 40, MAX + 1] {
+            let mut delta = Vec::new();
+            write_varint(&mut delta, len);
+            delta.extend_from_slice(&[0, 1, 0xff]);
+
+            assert_eq!(decode_delta(&[], &delta, MAX), None);
+        }
+    }
+
+    fn with_snapshots(mut host: Host<()>) -> Host<()> {
+        host.set_snapshots(Snapshots::new(1).with_history(8));
+        host
+    }
+
+    /// The state of a small world at `tick`, where a few entities move each
+    /// tick.
+    fn world(tick: u32) -> Vec<u8> {
+        let mut state = tick.to_be_bytes().to_vec();
+
+        for entity in 0..100u32 {
+            let moved = if entity % 10 == 0 { tick } else { 0 };
+            state.extend_from_slice(&(entity * 1000 + moved).to_be_bytes());
+        }
+
+        state
+    }
+
+    #[test]
+    fn test_snapshots_with_loss() {
+        let mut server = with_snapshots(create_server(1));
+        let mut client = with_snapshots(create_host(None, 1));
+        let (id, _) = connect_pair(&mut server, &mut client, 2);
+
+        client
+            .set_network_conditioner(NetworkConditioner::new(7).with_loss(0.3))
+            .unwrap();
+        server
+            .set_network_conditioner(NetworkConditioner::new(8).with_loss(0.3))
+            .unwrap();
+
+        let mut received = Vec::new();
+
+        for tick in 1..=100 {
+            server.send_snapshot(id, &world(tick)).unwrap();
+
+            for _ in 0..3 {
+                server.service(1).unwrap();
+
+                if let Some(Event::Receive { ref packet, .. }) = client.service(1).unwrap() {
+                    let tick = u32::from_be_bytes(packet.data()[..4].try_into().unwrap());
+                    assert_eq!(packet.data(), world(tick));
+                    received.push(tick);
+                }
+            }
+        }
+
+        assert!(received.len() > 30, "received {} snapshots", received.len());
+        assert!(received.windows(2).all(|w| w[0] < w[1]));
+    }
+
+    #[test]
+    fn test_lost_baseline() {
+        let mut server = create_server(1);
+        server.set_snapshots(Snapshots::new(1));
+        let mut client = create_host(None, 1);
+        client.set_snapshots(Snapshots::new(1));
+        let (id, _) = connect_pair(&mut server, &mut client, 2);
+
+        let run = |server: &mut Host<()>, client: &mut Host<()>, ticks| {
+            let mut received = Vec::new();
+
+            for tick in ticks {
+                server.send_snapshot(id, &world(tick)).unwrap();
+
+                for _ in 0..3 {
+                    server.service(1).unwrap();
+
+                    if let Some(Event::Receive { ref packet, .. }) = client.service(1).unwrap() {
+                        assert_eq!(packet.data(), world(tick));
+                        received.push(tick);
+                    }
+                }
+            }
+
+            received
+        };
+
+        assert_eq!(run(&mut server, &mut client, 1..=5), vec![1, 2, 3, 4, 5]);
+
+        // the client forgets all baselines, while the server keeps sending
+        // deltas against them
+        client.set_snapshots(Snapshots::new(1));
+
+        let received = run(&mut server, &mut client, 6..=10);
+        assert!(received.contains(&10), "received {:?}", received);
+    }
+
+    #[test]
+    fn test_max_retained() {
+        let mut server: Host<()> = create_server(1);
+        server.set_snapshots(Snapshots::new(1));
+        let mut client = create_host(None, 1);
+        // only the newest snapshot is kept as a baseline
+        client.set_snapshots(Snapshots::new(1).with_max_retained(0));
+        let (id, _) = connect_pair(&mut server, &mut client, 2);
+
+        let mut received = Vec::new();
+
+        for tick in 1..=20 {
+            server.send_snapshot(id, &world(tick)).unwrap();
+
+            for _ in 0..10 {
+                server.service(1).unwrap();
+
+                if let Some(Event::Receive { ref packet, .. }) = client.service(1).unwrap() {
+                    assert_eq!(packet.data(), world(tick));
+                    received.push(tick);
+                }
+            }
+        }
+
+        assert!(received.contains(&20), "received {:?}", received);
+    }
+}
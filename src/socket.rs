@@ -0,0 +1,848 @@
+//! Safe wrappers for ENet's socket API, for raw UDP and TCP traffic alongside
+//! ENet hosts.
+//!
+//! Sockets are created using `Enet::create_socket`, and use `Address` like
+//! the rest of this crate.
+
+use std::{io, net::Shutdown, os::raw::c_int, sync::Arc, time::Duration};
+
+use enet_sys::{
+    _ENetSocketOption_ENET_SOCKOPT_BROADCAST, _ENetSocketOption_ENET_SOCKOPT_ERROR,
+    _ENetSocketOption_ENET_SOCKOPT_NODELAY, _ENetSocketOption_ENET_SOCKOPT_NONBLOCK,
+    _ENetSocketOption_ENET_SOCKOPT_RCVBUF, _ENetSocketOption_ENET_SOCKOPT_RCVTIMEO,
+    _ENetSocketOption_ENET_SOCKOPT_REUSEADDR, _ENetSocketOption_ENET_SOCKOPT_SNDBUF,
+    _ENetSocketOption_ENET_SOCKOPT_SNDTIMEO, _ENetSocketOption_ENET_SOCKOPT_TTL,
+    _ENetSocketShutdown_ENET_SOCKET_SHUTDOWN_READ,
+    _ENetSocketShutdown_ENET_SOCKET_SHUTDOWN_READ_WRITE,
+    _ENetSocketShutdown_ENET_SOCKET_SHUTDOWN_WRITE, _ENetSocketType_ENET_SOCKET_TYPE_DATAGRAM,
+    _ENetSocketType_ENET_SOCKET_TYPE_STREAM, _ENetSocketWait_ENET_SOCKET_WAIT_RECEIVE,
+    _ENetSocketWait_ENET_SOCKET_WAIT_SEND, enet_socket_accept, enet_socket_bind,
+    enet_socket_connect, enet_socket_create, enet_socket_destroy, enet_socket_get_address,
+    enet_socket_get_option, enet_socket_listen, enet_socket_receive, enet_socket_send,
+    enet_socket_set_option, enet_socket_shutdown, enet_socket_wait, ENetAddress, ENetBuffer,
+    ENetSocket, ENetSocketOption, ENET_SOCKET_NULL,
+};
+
+use crate::{Address, Enet, EnetKeepAlive};
+
+/// The type of a `Socket`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SocketType {
+    /// A UDP socket.
+    Datagram,
+    /// A TCP socket.
+    Stream,
+}
+
+/// An option that can be set on a `Socket`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SocketOption {
+    /// Whether operations return immediately instead of blocking.
+    NonBlocking(bool),
+    /// Whether datagrams may be sent to broadcast addresses (`SO_BROADCAST`).
+    Broadcast(bool),
+    /// The size of the receive buffer in bytes (`SO_RCVBUF`).
+    ReceiveBuffer(u32),
+    /// The size of the send buffer in bytes (`SO_SNDBUF`).
+    SendBuffer(u32),
+    /// Whether the local address can be reused (`SO_REUSEADDR`).
+    ReuseAddress(bool),
+    /// How long receiving blocks at most (`SO_RCVTIMEO`).
+    ReceiveTimeout(Duration),
+    /// How long sending blocks at most (`SO_SNDTIMEO`).
+    SendTimeout(Duration),
+    /// Whether Nagle's algorithm is disabled (`TCP_NODELAY`).
+    NoDelay(bool),
+    /// The time-to-live of sent IP packets (`IP_TTL`).
+    Ttl(u32),
+    /// The type-of-service byte of sent IP packets, which contains the DSCP
+    /// in its upper six bits (`IP_TOS`). Only supported on unix platforms.
+    Tos(u8),
+}
+
+/// The kind of a `SocketOption`, used to read an option using
+/// `Socket::option`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[allow(missing_docs)]
+pub enum SocketOptionKind {
+    NonBlocking,
+    Broadcast,
+    ReceiveBuffer,
+    SendBuffer,
+    ReuseAddress,
+    ReceiveTimeout,
+    SendTimeout,
+    NoDelay,
+    Ttl,
+    Tos,
+}
+
+impl SocketOption {
+    /// Creates a `SocketOption::Tos` that marks sent packets with the
+    /// differentiated services code point `dscp`, between `0` and `63`.
+    pub fn dscp(dscp: u8) -> SocketOption {
+        SocketOption::Tos(dscp << 2)
+    }
+
+    /// Returns the kind of this option.
+    pub fn kind(&self) -> SocketOptionKind {
+        match self {
+            SocketOption::NonBlocking(_) => SocketOptionKind::NonBlocking,
+            SocketOption::Broadcast(_) => SocketOptionKind::Broadcast,
+            SocketOption::ReceiveBuffer(_) => SocketOptionKind::ReceiveBuffer,
+            SocketOption::SendBuffer(_) => SocketOptionKind::SendBuffer,
+            SocketOption::ReuseAddress(_) => SocketOptionKind::ReuseAddress,
+            SocketOption::ReceiveTimeout(_) => SocketOptionKind::ReceiveTimeout,
+            SocketOption::SendTimeout(_) => SocketOptionKind::SendTimeout,
+            SocketOption::NoDelay(_) => SocketOptionKind::NoDelay,
+            SocketOption::Ttl(_) => SocketOptionKind::Ttl,
+            SocketOption::Tos(_) => SocketOptionKind::Tos,
+        }
+    }
+
+    /// Returns the ENet option and value, if ENet can set this option.
+    fn to_sys(self) -> Option<(ENetSocketOption, c_int)> {
+        let millis = |duration: Duration| duration.as_millis().try_into().unwrap_or(c_int::MAX);
+        let int = |value: u32| value.try_into().unwrap_or(c_int::MAX);
+
+        let option = match self {
+            SocketOption::NonBlocking(value) => {
+                (_ENetSocketOption_ENET_SOCKOPT_NONBLOCK, value.into())
+            }
+            SocketOption::Broadcast(value) => {
+                (_ENetSocketOption_ENET_SOCKOPT_BROADCAST, value.into())
+            }
+            SocketOption::ReceiveBuffer(size) => (_ENetSocketOption_ENET_SOCKOPT_RCVBUF, int(size)),
+            SocketOption::SendBuffer(size) => (_ENetSocketOption_ENET_SOCKOPT_SNDBUF, int(size)),
+            SocketOption::ReuseAddress(value) => {
+                (_ENetSocketOption_ENET_SOCKOPT_REUSEADDR, value.into())
+            }
+            SocketOption::ReceiveTimeout(timeout) => {
+                (_ENetSocketOption_ENET_SOCKOPT_RCVTIMEO, millis(timeout))
+            }
+            SocketOption::SendTimeout(timeout) => {
+                (_ENetSocketOption_ENET_SOCKOPT_SNDTIMEO, millis(timeout))
+            }
+            SocketOption::NoDelay(value) => (_ENetSocketOption_ENET_SOCKOPT_NODELAY, value.into()),
+            SocketOption::Ttl(ttl) => (_ENetSocketOption_ENET_SOCKOPT_TTL, int(ttl)),
+            SocketOption::Tos(_) => return None,
+        };
+
+        Some(option)
+    }
+}
+
+/// The conditions `Socket::wait` waits for, and reports.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct Readiness {
+    /// The socket can send without blocking.
+    pub send: bool,
+    /// The socket has data to receive.
+    pub receive: bool,
+}
+
+/// An error that can occur when using a `Socket`.
+#[derive(thiserror::Error, Debug)]
+pub enum SocketError {
+    /// The socket could not be created.
+    #[error("failed to create socket")]
+    Create(#[source] io::Error),
+    /// The socket could not be bound to the address.
+    #[error("failed to bind socket")]
+    Bind(#[source] io::Error),
+    /// The local address of the socket could not be determined.
+    #[error("failed to get socket address")]
+    Address(#[source] io::Error),
+    /// The socket could not listen for connections.
+    #[error("failed to listen on socket")]
+    Listen(#[source] io::Error),
+    /// No connection could be accepted.
+    #[error("failed to accept connection")]
+    Accept(#[source] io::Error),
+    /// The socket could not connect to the address.
+    #[error("failed to connect socket")]
+    Connect(#[source] io::Error),
+    /// Data could not be sent.
+    #[error("failed to send on socket")]
+    Send(#[source] io::Error),
+    /// Data could not be received.
+    #[error("failed to receive on socket")]
+    Receive(#[source] io::Error),
+    /// A received datagram did not fit into the buffer, and was truncated.
+    #[error("received datagram was truncated")]
+    Truncated,
+    /// The option could not be set.
+    #[error("failed to set socket option {option:?}")]
+    SetOption {
+        /// The option that was set.
+        option: SocketOption,
+        /// The underlying error.
+        #[source]
+        source: io::Error,
+    },
+    /// An option could not be read.
+    #[error("failed to get socket option")]
+    GetOption(#[source] io::Error),
+    /// The socket could not be shut down.
+    #[error("failed to shut down socket")]
+    Shutdown(#[source] io::Error),
+    /// Waiting for the socket failed.
+    #[error("failed to wait for socket")]
+    Wait(#[source] io::Error),
+    /// The option is not supported on this platform.
+    #[error("socket option {0:?} is not supported")]
+    Unsupported(SocketOptionKind),
+    /// The socket can not be added to a `SocketSet`, because its descriptor
+    /// is too large.
+    #[error("socket can not be added to a socket set")]
+    NotSelectable,
+}
+
+/// A UDP or TCP socket, created using `Enet::create_socket`.
+///
+/// The socket is closed when it is dropped.
+#[derive(Debug)]
+pub struct Socket {
+    inner: ENetSocket,
+    _keep_alive: Arc<EnetKeepAlive>,
+}
+
+impl Enet {
+    /// Creates a new, unbound `Socket` of the given type.
+    pub fn create_socket(&self, socket_type: SocketType) -> Result<Socket, SocketError> {
+        let socket_type = match socket_type {
+            SocketType::Datagram => _ENetSocketType_ENET_SOCKET_TYPE_DATAGRAM,
+            SocketType::Stream => _ENetSocketType_ENET_SOCKET_TYPE_STREAM,
+        };
+
+        let inner = unsafe { enet_socket_create(socket_type) };
+
+        if inner == ENET_SOCKET_NULL {
+            return Err(SocketError::Create(io::Error::last_os_error()));
+        }
+
+        Ok(Socket {
+            inner,
+            _keep_alive: self.keep_alive.clone(),
+        })
+    }
+}
+
+/// Sets `option` on `socket`, which may also be owned by a `Host`.
+pub(crate) fn set_option(socket: ENetSocket, option: SocketOption) -> Result<(), SocketError> {
+    let res = match option.to_sys() {
+        Some((sys_option, value)) => unsafe { enet_socket_set_option(socket, sys_option, value) },
+        None => raw::set_option(socket, option)?,
+    };
+
+    if res < 0 {
+        return Err(SocketError::SetOption {
+            option,
+            source: io::Error::last_os_error(),
+        });
+    }
+
+    Ok(())
+}
+
+/// Reads the option of the given kind from `socket`, which may also be owned
+/// by a `Host`.
+pub(crate) fn get_option(
+    socket: ENetSocket,
+    kind: SocketOptionKind,
+) -> Result<SocketOption, SocketError> {
+    match kind {
+        SocketOptionKind::Ttl => {
+            let ttl = enet_option(socket, _ENetSocketOption_ENET_SOCKOPT_TTL)?;
+            Ok(SocketOption::Ttl(ttl as u32))
+        }
+        kind => raw::get_option(socket, kind),
+    }
+}
+
+fn enet_option(socket: ENetSocket, option: ENetSocketOption) -> Result<c_int, SocketError> {
+    let mut value = 0;
+    check(
+        unsafe { enet_socket_get_option(socket, option, &mut value) },
+        SocketError::GetOption,
+    )?;
+    Ok(value)
+}
+
+/// Binds `socket`, which may also be owned by a `Host`, to `address`.
+pub(crate) fn bind(socket: ENetSocket, address: &Address) -> Result<(), SocketError> {
+    let address = address.to_enet_address();
+    check(
+        unsafe { enet_socket_bind(socket, &address) },
+        SocketError::Bind,
+    )?;
+    Ok(())
+}
+
+/// Returns the address `socket`, which may also be owned by a `Host`, is
+/// bound to.
+pub(crate) fn local_address(socket: ENetSocket) -> Result<Address, SocketError> {
+    let mut address = ENetAddress { host: 0, port: 0 };
+    check(
+        unsafe { enet_socket_get_address(socket, &mut address) },
+        SocketError::Address,
+    )?;
+    Ok(Address::from_enet_address(&address))
+}
+
+/// Maps the result of an ENet socket function to `SocketError`.
+fn check(res: c_int, error: fn(io::Error) -> SocketError) -> Result<c_int, SocketError> {
+    match res {
+        r if r < 0 => Err(error(io::Error::last_os_error())),
+        r => Ok(r),
+    }
+}
+
+impl Socket {
+    /// Binds this socket to `address`. Port `0` binds an unused port, which
+    /// can be queried using `Socket::local_address`.
+    pub fn bind(&mut self, address: &Address) -> Result<(), SocketError> {
+        bind(self.inner, address)
+    }
+
+    /// Returns the address this socket is bound to.
+    pub fn local_address(&self) -> Result<Address, SocketError> {
+        local_address(self.inner)
+    }
+
+    /// Listens for incoming connections on a stream socket. A negative
+    /// `backlog` uses the system's maximum.
+    pub fn listen(&mut self, backlog: i32) -> Result<(), SocketError> {
+        check(
+            unsafe { enet_socket_listen(self.inner, backlog) },
+            SocketError::Listen,
+        )?;
+        Ok(())
+    }
+
+    /// Accepts an incoming connection on a listening stream socket, returning
+    /// the connected socket and the address of the remote end.
+    pub fn accept(&mut self) -> Result<(Socket, Address), SocketError> {
+        let mut address = ENetAddress { host: 0, port: 0 };
+        let inner = unsafe { enet_socket_accept(self.inner, &mut address) };
+
+        if inner == ENET_SOCKET_NULL {
+            return Err(SocketError::Accept(io::Error::last_os_error()));
+        }
+
+        let socket = Socket {
+            inner,
+            _keep_alive: self._keep_alive.clone(),
+        };
+
+        Ok((socket, Address::from_enet_address(&address)))
+    }
+
+    /// Connects a stream socket to `address`. Non-blocking sockets may still
+    /// be connecting when this returns.
+    pub fn connect(&mut self, address: &Address) -> Result<(), SocketError> {
+        let address = address.to_enet_address();
+        check(
+            unsafe { enet_socket_connect(self.inner, &address) },
+            SocketError::Connect,
+        )?;
+        Ok(())
+    }
+
+    /// Sends a datagram containing `data` to `address`.
+    ///
+    /// Returns the number of bytes sent, which is `0` if the socket is
+    /// non-blocking and sending would block.
+    pub fn send_to(&mut self, data: &[u8], address: &Address) -> Result<usize, SocketError> {
+        let address = address.to_enet_address();
+        self.raw_send(data, &address)
+    }
+
+    /// Sends `data` on a connected socket.
+    ///
+    /// Returns the number of bytes sent, which is `0` if the socket is
+    /// non-blocking and sending would block.
+    pub fn send(&mut self, data: &[u8]) -> Result<usize, SocketError> {
+        self.raw_send(data, std::ptr::null())
+    }
+
+    fn raw_send(&mut self, data: &[u8], address: *const ENetAddress) -> Result<usize, SocketError> {
+        let buffer = ENetBuffer {
+            data: data.as_ptr() as *mut _,
+            dataLength: data.len(),
+        };
+
+        let sent = check(
+            unsafe { enet_socket_send(self.inner, address, &buffer, 1) },
+            SocketError::Send,
+        )?;
+        Ok(sent as usize)
+    }
+
+    /// Receives data into `buffer`, returning the number of bytes received
+    /// and the address they were sent from.
+    ///
+    /// Returns `None` if the socket is non-blocking and no data is available,
+    /// or a stream socket was closed by the remote end. Fails with
+    /// `SocketError::Truncated` if a datagram did not fit into `buffer`.
+    pub fn receive(&mut self, buffer: &mut [u8]) -> Result<Option<(usize, Address)>, SocketError> {
+        let mut address = ENetAddress { host: 0, port: 0 };
+        let mut buffer = ENetBuffer {
+            data: buffer.as_mut_ptr() as *mut _,
+            dataLength: buffer.len(),
+        };
+
+        let res = unsafe { enet_socket_receive(self.inner, &mut address, &mut buffer, 1) };
+
+        match res {
+            -2 => Err(SocketError::Truncated),
+            0 => Ok(None),
+            r => {
+                let received = check(r, SocketError::Receive)?;
+                Ok(Some((
+                    received as usize,
+                    Address::from_enet_address(&address),
+                )))
+            }
+        }
+    }
+
+    /// Sets an option on this socket.
+    pub fn set_option(&mut self, option: SocketOption) -> Result<(), SocketError> {
+        set_option(self.inner, option)
+    }
+
+    /// Reads the current value of an option of this socket.
+    ///
+    /// The system may adjust the values that were set, for example Linux
+    /// doubles the buffer sizes. Only `SocketOptionKind::Ttl` is supported on
+    /// all platforms, the other options only on unix platforms.
+    pub fn option(&self, kind: SocketOptionKind) -> Result<SocketOption, SocketError> {
+        get_option(self.inner, kind)
+    }
+
+    /// Returns and clears the pending error of this socket (`SO_ERROR`), for
+    /// example the result of a non-blocking connect.
+    pub fn take_error(&self) -> Result<Option<io::Error>, SocketError> {
+        let error = enet_option(self.inner, _ENetSocketOption_ENET_SOCKOPT_ERROR)?;
+        Ok((error != 0).then(|| io::Error::from_raw_os_error(error)))
+    }
+
+    /// Waits until this socket is ready for any of the conditions in
+    /// `interest`, or `timeout_ms` milliseconds passed. Returns the
+    /// conditions that are ready, which are all `false` on timeout.
+    pub fn wait(&self, interest: Readiness, timeout_ms: u32) -> Result<Readiness, SocketError> {
+        let mut condition = 0;
+        if interest.send {
+            condition |= _ENetSocketWait_ENET_SOCKET_WAIT_SEND;
+        }
+        if interest.receive {
+            condition |= _ENetSocketWait_ENET_SOCKET_WAIT_RECEIVE;
+        }
+
+        check(
+            unsafe { enet_socket_wait(self.inner, &mut condition, timeout_ms) },
+            SocketError::Wait,
+        )?;
+
+        Ok(Readiness {
+            send: condition & _ENetSocketWait_ENET_SOCKET_WAIT_SEND != 0,
+            receive: condition & _ENetSocketWait_ENET_SOCKET_WAIT_RECEIVE != 0,
+        })
+    }
+
+    /// Shuts down the reading, writing or both halves of a stream socket.
+    pub fn shutdown(&mut self, how: Shutdown) -> Result<(), SocketError> {
+        let how = match how {
+            Shutdown::Read => _ENetSocketShutdown_ENET_SOCKET_SHUTDOWN_READ,
+            Shutdown::Write => _ENetSocketShutdown_ENET_SOCKET_SHUTDOWN_WRITE,
+            Shutdown::Both => _ENetSocketShutdown_ENET_SOCKET_SHUTDOWN_READ_WRITE,
+        };
+
+        check(
+            unsafe { enet_socket_shutdown(self.inner, how) },
+            SocketError::Shutdown,
+        )?;
+        Ok(())
+    }
+}
+
+#[cfg(unix)]
+impl std::os::fd::AsRawFd for Socket {
+    fn as_raw_fd(&self) -> std::os::fd::RawFd {
+        self.inner
+    }
+}
+
+impl Drop for Socket {
+    fn drop(&mut self) {
+        unsafe {
+            enet_socket_destroy(self.inner);
+        }
+    }
+}
+
+// `SocketSet` hands ENet's `fd_set` to the platform's `FD_*` macros
+#[cfg(unix)]
+const _: () =
+    assert!(std::mem::size_of::<enet_sys::ENetSocketSet>() == std::mem::size_of::<libc::fd_set>());
+
+/// A set of sockets, used to wait for any of them to become ready using
+/// `SocketSet::select`.
+///
+/// Only available on Unix platforms.
+#[cfg(unix)]
+#[derive(Clone, Copy)]
+pub struct SocketSet {
+    inner: enet_sys::ENetSocketSet,
+    max: Option<ENetSocket>,
+}
+
+#[cfg(unix)]
+impl SocketSet {
+    /// Creates an empty set.
+    pub fn new() -> SocketSet {
+        let mut set = SocketSet {
+            inner: unsafe { std::mem::zeroed() },
+            max: None,
+        };
+
+        unsafe { libc::FD_ZERO(set.as_raw()) };
+        set
+    }
+
+    fn as_raw(&mut self) -> *mut libc::fd_set {
+        (&mut self.inner as *mut enet_sys::ENetSocketSet).cast()
+    }
+
+    /// Returns whether `socket` fits into an `fd_set`, which the `FD_*`
+    /// macros do not check.
+    // the type of `FD_SETSIZE` differs between platforms
+    #[allow(clippy::unnecessary_cast)]
+    fn is_selectable(socket: &Socket) -> bool {
+        usize::try_from(socket.inner).is_ok_and(|fd| fd < libc::FD_SETSIZE as usize)
+    }
+
+    /// Adds `socket` to this set.
+    pub fn add(&mut self, socket: &Socket) -> Result<(), SocketError> {
+        if !SocketSet::is_selectable(socket) {
+            return Err(SocketError::NotSelectable);
+        }
+
+        unsafe { libc::FD_SET(socket.inner, self.as_raw()) };
+        self.max = self.max.max(Some(socket.inner));
+
+        Ok(())
+    }
+
+    /// Removes `socket` from this set.
+    pub fn remove(&mut self, socket: &Socket) {
+        if SocketSet::is_selectable(socket) {
+            unsafe { libc::FD_CLR(socket.inner, self.as_raw()) };
+        }
+    }
+
+    /// Returns whether `socket` is in this set. After `SocketSet::select`,
+    /// this tells whether `socket` is ready.
+    pub fn contains(&self, socket: &Socket) -> bool {
+        let set = (&self.inner as *const enet_sys::ENetSocketSet).cast();
+        SocketSet::is_selectable(socket) && unsafe { libc::FD_ISSET(socket.inner, set) }
+    }
+
+    /// Removes all sockets from this set.
+    pub fn clear(&mut self) {
+        *self = SocketSet::new();
+    }
+
+    /// Waits until any socket in `read` has data to receive, or any socket
+    /// in `write` can send, or `timeout_ms` milliseconds passed.
+    ///
+    /// Afterwards, the sets only contain the sockets that are ready. Returns
+    /// the number of ready sockets, which is `0` on timeout.
+    pub fn select(
+        mut read: Option<&mut SocketSet>,
+        mut write: Option<&mut SocketSet>,
+        timeout_ms: u32,
+    ) -> Result<usize, SocketError> {
+        let max = read
+            .as_ref()
+            .and_then(|set| set.max)
+            .max(write.as_ref().and_then(|set| set.max));
+
+        let as_ptr = |set: &mut Option<&mut SocketSet>| match set {
+            Some(set) => &mut set.inner as *mut enet_sys::ENetSocketSet,
+            None => std::ptr::null_mut(),
+        };
+
+        let ready = check(
+            unsafe {
+                enet_sys::enet_socketset_select(
+                    max.unwrap_or(-1),
+                    as_ptr(&mut read),
+                    as_ptr(&mut write),
+                    timeout_ms,
+                )
+            },
+            SocketError::Wait,
+        )?;
+        Ok(ready as usize)
+    }
+}
+
+#[cfg(unix)]
+impl Default for SocketSet {
+    fn default() -> SocketSet {
+        SocketSet::new()
+    }
+}
+
+#[cfg(unix)]
+impl std::fmt::Debug for SocketSet {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SocketSet").field("max", &self.max).finish()
+    }
+}
+
+/// Socket options that ENet does not support, set and read directly.
+#[cfg(unix)]
+mod raw {
+    use std::{io, mem, os::raw::c_int, time::Duration};
+
+    use enet_sys::ENetSocket;
+
+    use super::{SocketError, SocketOption, SocketOptionKind};
+
+    pub(super) fn set_option(
+        socket: ENetSocket,
+        option: SocketOption,
+    ) -> Result<c_int, SocketError> {
+        match option {
+            SocketOption::Tos(tos) => Ok(unsafe {
+                let value = c_int::from(tos);
+                libc::setsockopt(
+                    socket,
+                    libc::IPPROTO_IP,
+                    libc::IP_TOS,
+                    &value as *const c_int as *const _,
+                    mem::size_of::<c_int>() as libc::socklen_t,
+                )
+            }),
+            option => Err(SocketError::Unsupported(option.kind())),
+        }
+    }
+
+    fn get<V>(socket: ENetSocket, level: c_int, name: c_int) -> Result<V, SocketError> {
+        let mut value = mem::MaybeUninit::<V>::zeroed();
+        let mut len = mem::size_of::<V>() as libc::socklen_t;
+
+        let res = unsafe {
+            libc::getsockopt(socket, level, name, value.as_mut_ptr() as *mut _, &mut len)
+        };
+
+        if res < 0 {
+            return Err(SocketError::GetOption(io::Error::last_os_error()));
+        }
+
+        Ok(unsafe { value.assume_init() })
+    }
+
+    fn get_timeout(socket: ENetSocket, name: c_int) -> Result<Duration, SocketError> {
+        let time: libc::timeval = get(socket, libc::SOL_SOCKET, name)?;
+        Ok(Duration::from_secs(time.tv_sec as u64) + Duration::from_micros(time.tv_usec as u64))
+    }
+
+    pub(super) fn get_option(
+        socket: ENetSocket,
+        kind: SocketOptionKind,
+    ) -> Result<SocketOption, SocketError> {
+        let get_int = |level, name| get::<c_int>(socket, level, name);
+
+        let option = match kind {
+            SocketOptionKind::NonBlocking => {
+                let flags = unsafe { libc::fcntl(socket, libc::F_GETFL) };
+
+                if flags < 0 {
+                    return Err(SocketError::GetOption(io::Error::last_os_error()));
+                }
+
+                SocketOption::NonBlocking(flags & libc::O_NONBLOCK != 0)
+            }
+            SocketOptionKind::Broadcast => {
+                SocketOption::Broadcast(get_int(libc::SOL_SOCKET, libc::SO_BROADCAST)? != 0)
+            }
+            SocketOptionKind::ReceiveBuffer => {
+                SocketOption::ReceiveBuffer(get_int(libc::SOL_SOCKET, libc::SO_RCVBUF)? as u32)
+            }
+            SocketOptionKind::SendBuffer => {
+                SocketOption::SendBuffer(get_int(libc::SOL_SOCKET, libc::SO_SNDBUF)? as u32)
+            }
+            SocketOptionKind::ReuseAddress => {
+                SocketOption::ReuseAddress(get_int(libc::SOL_SOCKET, libc::SO_REUSEADDR)? != 0)
+            }
+            SocketOptionKind::ReceiveTimeout => {
+                SocketOption::ReceiveTimeout(get_timeout(socket, libc::SO_RCVTIMEO)?)
+            }
+            SocketOptionKind::SendTimeout => {
+                SocketOption::SendTimeout(get_timeout(socket, libc::SO_SNDTIMEO)?)
+            }
+            SocketOptionKind::NoDelay => {
+                SocketOption::NoDelay(get_int(libc::IPPROTO_TCP, libc::TCP_NODELAY)? != 0)
+            }
+            SocketOptionKind::Ttl => {
+                SocketOption::Ttl(get_int(libc::IPPROTO_IP, libc::IP_TTL)? as u32)
+            }
+            SocketOptionKind::Tos => {
+                SocketOption::Tos(get_int(libc::IPPROTO_IP, libc::IP_TOS)? as u8)
+            }
+        };
+
+        Ok(option)
+    }
+}
+
+#[cfg(not(unix))]
+mod raw {
+    use std::os::raw::c_int;
+
+    use enet_sys::ENetSocket;
+
+    use super::{SocketError, SocketOption, SocketOptionKind};
+
+    pub(super) fn set_option(_: ENetSocket, option: SocketOption) -> Result<c_int, SocketError> {
+        Err(SocketError::Unsupported(option.kind()))
+    }
+
+    pub(super) fn get_option(
+        _: ENetSocket,
+        kind: SocketOptionKind,
+    ) -> Result<SocketOption, SocketError> {
+        Err(SocketError::Unsupported(kind))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, Shutdown};
+
+    #[cfg(unix)]
+    use super::SocketSet;
+    use super::{Readiness, SocketError, SocketOption, SocketOptionKind, SocketType};
+    use crate::{tests::ENET, Address};
+
+    fn loopback() -> Address {
+        Address::new(Ipv4Addr::LOCALHOST, 0)
+    }
+
+    #[test]
+    fn test_datagram() {
+        let mut receiver = ENET.create_socket(SocketType::Datagram).unwrap();
+        receiver.bind(&loopback()).unwrap();
+        receiver
+            .set_option(SocketOption::NonBlocking(true))
+            .unwrap();
+
+        let address = receiver.local_address().unwrap();
+        assert_ne!(address.port(), 0);
+
+        let mut buffer = [0; 16];
+        assert!(receiver.receive(&mut buffer).unwrap().is_none());
+
+        let mut sender = ENET.create_socket(SocketType::Datagram).unwrap();
+        sender.bind(&loopback()).unwrap();
+        sender.set_option(SocketOption::Ttl(7)).unwrap();
+        assert_eq!(
+            sender.option(SocketOptionKind::Ttl).unwrap(),
+            SocketOption::Ttl(7)
+        );
+
+        assert_eq!(sender.send_to(b"hello", &address).unwrap(), 5);
+
+        #[cfg(unix)]
+        {
+            let mut set = SocketSet::new();
+            set.add(&receiver).unwrap();
+            assert_eq!(SocketSet::select(Some(&mut set), None, 1000).unwrap(), 1);
+            assert!(set.contains(&receiver));
+
+            set.remove(&receiver);
+            assert!(!set.contains(&receiver));
+        }
+
+        let (len, from) = receiver.receive(&mut buffer).unwrap().unwrap();
+        assert_eq!(&buffer[..len], b"hello");
+        assert_eq!(from, sender.local_address().unwrap());
+
+        sender.send_to(b"too long", &address).unwrap();
+        let ready = receiver
+            .wait(
+                Readiness {
+                    receive: true,
+                    ..Readiness::default()
+                },
+                1000,
+            )
+            .unwrap();
+        assert!(ready.receive);
+        assert!(matches!(
+            receiver.receive(&mut buffer[..4]),
+            Err(SocketError::Truncated)
+        ));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_options() {
+        let mut socket = ENET.create_socket(SocketType::Datagram).unwrap();
+
+        socket.set_option(SocketOption::ReuseAddress(true)).unwrap();
+        socket
+            .set_option(SocketOption::ReceiveBuffer(1 << 16))
+            .unwrap();
+        socket.set_option(SocketOption::dscp(46)).unwrap();
+
+        assert_eq!(
+            socket.option(SocketOptionKind::ReuseAddress).unwrap(),
+            SocketOption::ReuseAddress(true)
+        );
+        assert_eq!(
+            socket.option(SocketOptionKind::Tos).unwrap(),
+            SocketOption::Tos(46 << 2)
+        );
+        assert_eq!(
+            socket.option(SocketOptionKind::NonBlocking).unwrap(),
+            SocketOption::NonBlocking(false)
+        );
+
+        match socket.option(SocketOptionKind::ReceiveBuffer).unwrap() {
+            SocketOption::ReceiveBuffer(size) => assert!(size >= 1 << 16),
+            option => panic!("unexpected option: {:?}", option),
+        }
+    }
+
+    #[test]
+    fn test_stream() {
+        let mut listener = ENET.create_socket(SocketType::Stream).unwrap();
+        listener
+            .set_option(SocketOption::ReuseAddress(true))
+            .unwrap();
+        listener.bind(&loopback()).unwrap();
+        listener.listen(-1).unwrap();
+
+        let mut client = ENET.create_socket(SocketType::Stream).unwrap();
+        client.connect(&listener.local_address().unwrap()).unwrap();
+        client.set_option(SocketOption::NoDelay(true)).unwrap();
+
+        let (mut server, from) = listener.accept().unwrap();
+        assert_eq!(from, client.local_address().unwrap());
+        assert!(server.take_error().unwrap().is_none());
+
+        assert_eq!(client.send(b"ping").unwrap(), 4);
+
+        let mut buffer = [0; 16];
+        let (len, _) = server.receive(&mut buffer).unwrap().unwrap();
+        assert_eq!(&buffer[..len], b"ping");
+
+        client.shutdown(Shutdown::Both).unwrap();
+        assert!(server.receive(&mut buffer).unwrap().is_none());
+    }
+}
@@ -0,0 +1,795 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    io::{self, Read, Write},
+};
+
+use enet_sys::ENetHost;
+
+use crate::{host::peer_at, Event, Packet, PacketMode, Peer, PeerId, SendError};
+
+const MSG_OFFER: u8 = 1;
+const MSG_ACCEPT: u8 = 2;
+const MSG_CHUNK: u8 = 3;
+const MSG_ACK: u8 = 4;
+const MSG_END: u8 = 5;
+const MSG_DONE: u8 = 6;
+const MSG_CANCEL_SENDER: u8 = 7;
+const MSG_CANCEL_RECEIVER: u8 = 8;
+
+const REASON_CANCELLED: u8 = 0;
+const REASON_CHECKSUM: u8 = 1;
+const REASON_IO: u8 = 2;
+
+/// Identifies a transfer of a `Host`, see `Transfers`.
+#[derive(Debug, Copy, Clone, Hash, PartialEq, Eq, PartialOrd, Ord)]
+pub struct TransferId {
+    peer: PeerId,
+    id: u32,
+    outgoing: bool,
+}
+
+impl TransferId {
+    /// Returns the peer this transfer is sent to or received from.
+    pub fn peer(&self) -> PeerId {
+        self.peer
+    }
+
+    /// Returns whether this transfer is sent by the local `Host`.
+    pub fn is_outgoing(&self) -> bool {
+        self.outgoing
+    }
+}
+
+/// The progress of a transfer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct TransferProgress {
+    /// The number of bytes that were transferred so far. For outgoing
+    /// transfers, only bytes the receiver acknowledged are counted.
+    pub transferred: u64,
+    /// The total size of the transfer in bytes.
+    pub total: u64,
+}
+
+impl TransferProgress {
+    /// Returns the progress as a fraction between `0.0` and `1.0`.
+    pub fn fraction(&self) -> f64 {
+        if self.total == 0 {
+            1.0
+        } else {
+            self.transferred as f64 / self.total as f64
+        }
+    }
+}
+
+/// The reason a transfer failed.
+#[derive(thiserror::Error, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransferError {
+    /// The other side cancelled the transfer.
+    #[error("transfer was cancelled by the peer")]
+    Cancelled,
+    /// The received data did not match the checksum of the sent data.
+    #[error("transfer checksum mismatch")]
+    ChecksumMismatch,
+    /// Reading the source or writing the sink failed locally.
+    #[error("transfer I/O failed: {0}")]
+    Io(io::ErrorKind),
+    /// Reading the source or writing the sink failed on the other side.
+    #[error("transfer I/O failed on the peer")]
+    RemoteIo,
+    /// A packet of the transfer could not be sent.
+    #[error("failed to send transfer packet")]
+    Send,
+}
+
+/// An error that can occur when starting a transfer using
+/// `Host::send_transfer`.
+#[derive(thiserror::Error, Debug)]
+pub enum SendTransferError {
+    /// The `Host` has no `Transfers` layer.
+    #[error("transfers are not enabled for this host")]
+    NotEnabled,
+    /// The `PeerId` does not belong to the host.
+    #[error("unknown peer")]
+    UnknownPeer,
+    /// The offer could not be sent.
+    #[error("failed to send transfer offer")]
+    Send(#[source] SendError),
+}
+
+/// What happened to a transfer, contained in `Event::Transfer`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransferEvent {
+    /// A peer offers a transfer of `size` bytes, which can be accepted using
+    /// `Host::accept_transfer` or declined using `Host::cancel_transfer`.
+    Offered {
+        /// The total size of the transfer in bytes.
+        size: u64,
+    },
+    /// More data was transferred.
+    Progress(TransferProgress),
+    /// All data was transferred, and the checksum matched.
+    Completed,
+    /// The transfer failed, and was discarded.
+    Failed(TransferError),
+}
+
+struct Outgoing {
+    source: Box<dyn Read>,
+    size: u64,
+    sent: u64,
+    acked: u64,
+    accepted: bool,
+    ended: bool,
+    crc: Crc32,
+}
+
+struct Incoming {
+    sink: Option<Box<dyn Write>>,
+    size: u64,
+    received: u64,
+    crc: Crc32,
+}
+
+/// Streams large amounts of data over a dedicated channel, once set on a
+/// `Host` using `Host::set_transfers`.
+///
+/// A transfer is started using `Host::send_transfer`, which offers it to the
+/// receiving peer. The receiver gets a `TransferEvent::Offered` and can accept
+/// it with a sink to write the data to. The data is then read from the source
+/// and sent in chunks, with at most a window of chunks unacknowledged at a
+/// time, so other traffic is not starved. Both sides report
+/// `TransferEvent::Progress` as chunks arrive, and verify a CRC32 checksum of
+/// the whole data at the end.
+///
+/// Packets on the dedicated channel are consumed by the transfer layer, and
+/// never handed to the application. Transfers of a peer that disconnects are
+/// discarded without further events. Both sides of a connection have to use
+/// the same channel.
+///
+/// A peer can only have a limited number of incoming transfers at a time,
+/// see `Transfers::with_max_incoming`, and offers reusing the id of a
+/// transfer in progress are ignored.
+pub struct Transfers {
+    channel_id: u8,
+    chunk_size: usize,
+    window: usize,
+    max_incoming: usize,
+    next_id: u32,
+    outgoing: HashMap<(PeerId, u32), Outgoing>,
+    incoming: HashMap<(PeerId, u32), Incoming>,
+    events: VecDeque<(TransferId, TransferEvent)>,
+}
+
+impl Transfers {
+    /// Creates a new `Transfers` layer, which uses `channel_id` exclusively.
+    pub fn new(channel_id: u8) -> Transfers {
+        Transfers {
+            channel_id,
+            chunk_size: 16 * 1024,
+            window: 8,
+            max_incoming: 16,
+            next_id: 0,
+            outgoing: HashMap::new(),
+            incoming: HashMap::new(),
+            events: VecDeque::new(),
+        }
+    }
+
+    /// Sets the size of the chunks data is sent in, defaults to 16 KiB.
+    pub fn with_chunk_size(mut self, chunk_size: usize) -> Transfers {
+        self.chunk_size = chunk_size.max(1);
+        self
+    }
+
+    /// Sets how many chunks may be unacknowledged at a time, defaults to `8`.
+    pub fn with_window(mut self, window: usize) -> Transfers {
+        self.window = window.max(1);
+        self
+    }
+
+    /// Sets how many incoming transfers a single peer may have at a time,
+    /// defaults to `16`. Offers beyond that are declined without an event.
+    pub fn with_max_incoming(mut self, max_incoming: usize) -> Transfers {
+        self.max_incoming = max_incoming;
+        self
+    }
+
+    /// Offers a transfer of `size` bytes read from `source` to `peer`.
+    pub(crate) fn start<T>(
+        &mut self,
+        peer: &mut Peer<'_, T>,
+        source: Box<dyn Read>,
+        size: u64,
+    ) -> Result<TransferId, SendTransferError> {
+        let id = self.next_id;
+
+        let mut message = header(MSG_OFFER, id);
+        message.extend_from_slice(&size.to_be_bytes());
+        self.send(peer, &message).map_err(SendTransferError::Send)?;
+
+        self.next_id = self.next_id.wrapping_add(1);
+        self.outgoing.insert(
+            (peer.id(), id),
+            Outgoing {
+                source,
+                size,
+                sent: 0,
+                acked: 0,
+                accepted: false,
+                ended: false,
+                crc: Crc32::new(),
+            },
+        );
+
+        Ok(TransferId {
+            peer: peer.id(),
+            id,
+            outgoing: true,
+        })
+    }
+
+    /// Accepts an offered transfer, writing its data to `sink`.
+    pub(crate) fn accept<T>(
+        &mut self,
+        host: *mut ENetHost,
+        transfer: TransferId,
+        sink: Box<dyn Write>,
+    ) -> bool {
+        let incoming = match self.incoming.get_mut(&(transfer.peer, transfer.id)) {
+            Some(incoming) if incoming.sink.is_none() && !transfer.outgoing => incoming,
+            _ => return false,
+        };
+        incoming.sink = Some(sink);
+
+        let mut peer = peer_at::<T>(host, transfer.peer);
+        if self
+            .send(&mut peer, &header(MSG_ACCEPT, transfer.id))
+            .is_err()
+        {
+            self.fail(&mut peer, transfer, TransferError::Send);
+        }
+
+        true
+    }
+
+    /// Cancels a transfer, notifying the other side.
+    pub(crate) fn cancel<T>(&mut self, host: *mut ENetHost, transfer: TransferId) -> bool {
+        let key = (transfer.peer, transfer.id);
+        let removed = match transfer.outgoing {
+            true => self.outgoing.remove(&key).is_some(),
+            false => self.incoming.remove(&key).is_some(),
+        };
+
+        if removed {
+            let mut peer = peer_at::<T>(host, transfer.peer);
+            self.send_cancel(&mut peer, transfer, REASON_CANCELLED);
+        }
+
+        removed
+    }
+
+    /// Returns the progress of a transfer, if it exists.
+    pub(crate) fn progress(&self, transfer: TransferId) -> Option<TransferProgress> {
+        let key = (transfer.peer, transfer.id);
+
+        match transfer.outgoing {
+            true => self.outgoing.get(&key).map(|outgoing| TransferProgress {
+                transferred: outgoing.acked,
+                total: outgoing.size,
+            }),
+            false => self.incoming.get(&key).map(|incoming| TransferProgress {
+                transferred: incoming.received,
+                total: incoming.size,
+            }),
+        }
+    }
+
+    /// Returns the next event of a transfer, if any.
+    pub(crate) fn pop_event(&mut self) -> Option<(TransferId, TransferEvent)> {
+        self.events.pop_front()
+    }
+
+    /// Sends chunks of all accepted outgoing transfers, as far as their
+    /// windows allow.
+    pub(crate) fn pump<T>(&mut self, host: *mut ENetHost) {
+        let window = (self.window * self.chunk_size) as u64;
+        let mut failed = Vec::new();
+
+        for (&(peer_id, id), outgoing) in self.outgoing.iter_mut() {
+            if !outgoing.accepted || outgoing.ended {
+                continue;
+            }
+
+            let mut peer = peer_at::<T>(host, peer_id);
+            let transfer = TransferId {
+                peer: peer_id,
+                id,
+                outgoing: true,
+            };
+
+            while outgoing.sent < outgoing.size && outgoing.sent - outgoing.acked < window {
+                let len = (outgoing.size - outgoing.sent).min(self.chunk_size as u64) as usize;
+                let mut message = header(MSG_CHUNK, id);
+                let start = message.len();
+                message.resize(start + len, 0);
+
+                if let Err(err) = outgoing.source.read_exact(&mut message[start..]) {
+                    failed.push((transfer, TransferError::Io(err.kind())));
+                    break;
+                }
+
+                outgoing.crc.update(&message[start..]);
+
+                if send(&mut peer, self.channel_id, &message).is_err() {
+                    failed.push((transfer, TransferError::Send));
+                    break;
+                }
+
+                outgoing.sent += len as u64;
+            }
+
+            if outgoing.sent == outgoing.size && !failed.iter().any(|(t, _)| *t == transfer) {
+                let mut message = header(MSG_END, id);
+                message.extend_from_slice(&outgoing.crc.finish().to_be_bytes());
+
+                match send(&mut peer, self.channel_id, &message) {
+                    Ok(()) => outgoing.ended = true,
+                    Err(_) => failed.push((transfer, TransferError::Send)),
+                }
+            }
+        }
+
+        for (transfer, error) in failed {
+            let mut peer = peer_at::<T>(host, transfer.peer);
+            self.fail(&mut peer, transfer, error);
+        }
+    }
+
+    /// Drops all transfers with the peer `id`, once its connection ended.
+    pub(crate) fn remove_peer(&mut self, id: PeerId) {
+        self.outgoing.retain(|(peer_id, _), _| *peer_id != id);
+        self.incoming.retain(|(peer_id, _), _| *peer_id != id);
+        self.events.retain(|(transfer, _)| transfer.peer != id);
+    }
+
+    /// Runs the transfer layer for an event, returning the event that should
+    /// be handed to the application instead, if any.
+    pub(crate) fn process_event<'a, T>(&mut self, event: Event<'a, T>) -> Option<Event<'a, T>> {
+        match &event {
+            Event::Receive {
+                sender,
+                channel_id,
+                packet,
+            } if *channel_id == self.channel_id => {
+                let mut peer = sender.clone();
+
+                if let Some((kind, id, body)) = decode_header(packet.data()) {
+                    self.handle_message(&mut peer, kind, id, body);
+                }
+
+                None
+            }
+            _ => Some(event),
+        }
+    }
+
+    fn handle_message<T>(&mut self, peer: &mut Peer<'_, T>, kind: u8, id: u32, body: &[u8]) {
+        let key = (peer.id(), id);
+        let outgoing = TransferId {
+            peer: peer.id(),
+            id,
+            outgoing: true,
+        };
+        let incoming = TransferId {
+            outgoing: false,
+            ..outgoing
+        };
+
+        match kind {
+            MSG_OFFER => {
+                let size = match body.try_into() {
+                    Ok(size) => u64::from_be_bytes(size),
+                    Err(_) => return,
+                };
+
+                // an offer must not replace a transfer that is in progress
+                if self.incoming.contains_key(&key) {
+                    return;
+                }
+
+                let count = self
+                    .incoming
+                    .keys()
+                    .filter(|(peer_id, _)| *peer_id == peer.id())
+                    .count();
+
+                if count >= self.max_incoming {
+                    self.send_cancel(peer, incoming, REASON_CANCELLED);
+                    return;
+                }
+
+                self.incoming.insert(
+                    key,
+                    Incoming {
+                        sink: None,
+                        size,
+                        received: 0,
+                        crc: Crc32::new(),
+                    },
+                );
+                self.events
+                    .push_back((incoming, TransferEvent::Offered { size }));
+            }
+            MSG_ACCEPT => {
+                if let Some(transfer) = self.outgoing.get_mut(&key) {
+                    transfer.accepted = true;
+                }
+            }
+            MSG_ACK => {
+                let (transfer, acked) = match (self.outgoing.get_mut(&key), body.try_into()) {
+                    (Some(transfer), Ok(acked)) => (transfer, u64::from_be_bytes(acked)),
+                    _ => return,
+                };
+
+                transfer.acked = acked.min(transfer.sent);
+
+                let progress = TransferProgress {
+                    transferred: transfer.acked,
+                    total: transfer.size,
+                };
+                self.events
+                    .push_back((outgoing, TransferEvent::Progress(progress)));
+            }
+            MSG_DONE if self.outgoing.remove(&key).is_some() => {
+                self.events.push_back((outgoing, TransferEvent::Completed));
+            }
+            MSG_CHUNK => {
+                let transfer = match self.incoming.get_mut(&key) {
+                    Some(transfer) => transfer,
+                    None => return,
+                };
+
+                let result = match &mut transfer.sink {
+                    _ if transfer.received + body.len() as u64 > transfer.size => {
+                        Err(TransferError::Cancelled)
+                    }
+                    Some(sink) => sink
+                        .write_all(body)
+                        .map_err(|err| TransferError::Io(err.kind())),
+                    // chunks are only sent after accepting
+                    None => Err(TransferError::Cancelled),
+                };
+
+                if let Err(error) = result {
+                    self.fail(peer, incoming, error);
+                    return;
+                }
+
+                transfer.crc.update(body);
+                transfer.received += body.len() as u64;
+
+                let progress = TransferProgress {
+                    transferred: transfer.received,
+                    total: transfer.size,
+                };
+
+                let mut message = header(MSG_ACK, id);
+                message.extend_from_slice(&transfer.received.to_be_bytes());
+
+                if self.send(peer, &message).is_err() {
+                    self.fail(peer, incoming, TransferError::Send);
+                    return;
+                }
+
+                self.events
+                    .push_back((incoming, TransferEvent::Progress(progress)));
+            }
+            MSG_END => {
+                let transfer = match self.incoming.get_mut(&key) {
+                    Some(transfer) => transfer,
+                    None => return,
+                };
+
+                let flushed = match &mut transfer.sink {
+                    Some(sink) => sink.flush().map_err(|err| TransferError::Io(err.kind())),
+                    None => Err(TransferError::Cancelled),
+                };
+
+                let result = flushed.and_then(|()| {
+                    let matches =
+                        body.try_into().map(u32::from_be_bytes).ok() == Some(transfer.crc.finish());
+
+                    if matches && transfer.received == transfer.size {
+                        Ok(())
+                    } else {
+                        Err(TransferError::ChecksumMismatch)
+                    }
+                });
+
+                match result {
+                    Ok(()) => {
+                        self.incoming.remove(&key);
+                        // the sender finishes when it gets this, so failing
+                        // to send it is not worth failing the transfer over
+                        let _ = self.send(peer, &header(MSG_DONE, id));
+                        self.events.push_back((incoming, TransferEvent::Completed));
+                    }
+                    Err(error) => self.fail(peer, incoming, error),
+                }
+            }
+            MSG_CANCEL_SENDER | MSG_CANCEL_RECEIVER => {
+                let (transfer, removed) = match kind {
+                    MSG_CANCEL_SENDER => (incoming, self.incoming.remove(&key).is_some()),
+                    _ => (outgoing, self.outgoing.remove(&key).is_some()),
+                };
+
+                let error = match body {
+                    [REASON_CHECKSUM] => TransferError::ChecksumMismatch,
+                    [REASON_IO] => TransferError::RemoteIo,
+                    _ => TransferError::Cancelled,
+                };
+
+                if removed {
+                    self.events
+                        .push_back((transfer, TransferEvent::Failed(error)));
+                }
+            }
+            _ => (),
+        }
+    }
+
+    /// Discards a transfer after `error`, notifying both sides.
+    fn fail<T>(&mut self, peer: &mut Peer<'_, T>, transfer: TransferId, error: TransferError) {
+        let key = (transfer.peer, transfer.id);
+
+        match transfer.outgoing {
+            true => drop(self.outgoing.remove(&key)),
+            false => drop(self.incoming.remove(&key)),
+        }
+
+        let reason = match error {
+            TransferError::ChecksumMismatch => REASON_CHECKSUM,
+            TransferError::Io(_) => REASON_IO,
+            _ => REASON_CANCELLED,
+        };
+
+        self.send_cancel(peer, transfer, reason);
+        self.events
+            .push_back((transfer, TransferEvent::Failed(error)));
+    }
+
+    fn send_cancel<T>(&self, peer: &mut Peer<'_, T>, transfer: TransferId, reason: u8) {
+        let kind = match transfer.outgoing {
+            true => MSG_CANCEL_SENDER,
+            false => MSG_CANCEL_RECEIVER,
+        };
+
+        let mut message = header(kind, transfer.id);
+        message.push(reason);
+
+        // if this fails, the other side has to notice the missing progress
+        let _ = self.send(peer, &message);
+    }
+
+    fn send<T>(&self, peer: &mut Peer<'_, T>, message: &[u8]) -> Result<(), SendError> {
+        send(peer, self.channel_id, message)
+    }
+}
+
+fn send<T>(peer: &mut Peer<'_, T>, channel_id: u8, message: &[u8]) -> Result<(), SendError> {
+    let packet = Packet::new(message, PacketMode::ReliableSequenced)?;
+    peer.try_send_packet(packet, channel_id)
+}
+
+fn header(kind: u8, id: u32) -> Vec<u8> {
+    let mut message = Vec::with_capacity(5);
+    message.push(kind);
+    message.extend_from_slice(&id.to_be_bytes());
+    message
+}
+
+fn decode_header(data: &[u8]) -> Option<(u8, u32, &[u8])> {
+    let (&kind, rest) = data.split_first()?;
+
+    if rest.len() < 4 {
+        return None;
+    }
+
+    let (id, body) = rest.split_at(4);
+    Some((kind, u32::from_be_bytes(id.try_into().unwrap()), body))
+}
+
+/// An incremental CRC32 (IEEE), as used by zip and ethernet.
+struct Crc32(u32);
+
+impl Crc32 {
+    fn new() -> Crc32 {
+        Crc32(0xFFFF_FFFF)
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        for &byte in data {
+            self.0 ^= u32::from(byte);
+
+            for _ in 0..8 {
+                let mask = (self.0 & 1).wrapping_neg();
+                self.0 = (self.0 >> 1) ^ (0xEDB8_8320 & mask);
+            }
+        }
+    }
+
+    fn finish(&self) -> u32 {
+        !self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, io::Write, rc::Rc};
+
+    use super::{header, Crc32, TransferError, TransferEvent, Transfers, MSG_OFFER};
+    use crate::{
+        tests::{connect_pair, create_host, create_server},
+        Event, Packet, PacketMode, TransferId,
+    };
+
+    #[derive(Clone, Default)]
+    struct SharedSink(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for SharedSink {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_crc32() {
+        let mut crc = Crc32::new();
+        crc.update(b"1234");
+        crc.update(b"56789");
+
+        assert_eq!(crc.finish(), 0xCBF4_3926);
+        assert_eq!(Crc32::new().finish(), 0);
+    }
+
+    fn transfers() -> Transfers {
+        Transfers::new(1).with_chunk_size(1000).with_window(4)
+    }
+
+    fn transfer_event(event: Option<Event<'_, ()>>) -> Option<(TransferId, TransferEvent)> {
+        match event? {
+            Event::Transfer {
+                transfer, event, ..
+            } => Some((transfer, event)),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_transfer() {
+        let mut server = create_server(1);
+        server.set_transfers(transfers());
+        let mut client = create_host(None, 1);
+        client.set_transfers(transfers());
+        let (id, _) = connect_pair(&mut server, &mut client, 2);
+
+        let data = (0..50_000u32).map(|i| (i * 7) as u8).collect::<Vec<_>>();
+        let transfer = server
+            .send_transfer(id, std::io::Cursor::new(data.clone()), data.len() as u64)
+            .unwrap();
+
+        let sink = SharedSink::default();
+        let (mut client_progress, mut server_progress) = (Vec::new(), Vec::new());
+        let (mut client_done, mut server_done) = (false, false);
+
+        for _ in 0..1000 {
+            if let Some((t, event)) = transfer_event(server.service(1).unwrap()) {
+                assert_eq!(t, transfer);
+                match event {
+                    TransferEvent::Progress(progress) => server_progress.push(progress),
+                    TransferEvent::Completed => server_done = true,
+                    event => panic!("unexpected event: {:?}", event),
+                }
+            }
+
+            if let Some((t, event)) = transfer_event(client.service(1).unwrap()) {
+                assert!(!t.is_outgoing());
+                match event {
+                    TransferEvent::Offered { size } => {
+                        assert_eq!(size, data.len() as u64);
+                        assert!(client.accept_transfer(t, sink.clone()));
+                    }
+                    TransferEvent::Progress(progress) => client_progress.push(progress),
+                    TransferEvent::Completed => client_done = true,
+                    TransferEvent::Failed(error) => panic!("failed: {}", error),
+                }
+            }
+
+            if client_done && server_done {
+                break;
+            }
+        }
+
+        assert!(client_done && server_done);
+        assert_eq!(*sink.0.borrow(), data);
+        assert_eq!(client_progress.len(), 50);
+        assert_eq!(client_progress.last().unwrap().fraction(), 1.0);
+        assert!(server_progress
+            .windows(2)
+            .all(|w| w[0].transferred < w[1].transferred));
+        assert!(server.transfer_progress(transfer).is_none());
+    }
+
+    #[test]
+    fn test_declined_transfer() {
+        let mut server = create_server(1);
+        server.set_transfers(transfers());
+        let mut client = create_host(None, 1);
+        client.set_transfers(transfers());
+        let (id, _) = connect_pair(&mut server, &mut client, 2);
+
+        let transfer = server.send_transfer(id, std::io::empty(), 10_000).unwrap();
+
+        let mut failed = None;
+
+        for _ in 0..100 {
+            if let Some((_, event)) = transfer_event(server.service(5).unwrap()) {
+                failed = Some(event);
+                break;
+            }
+
+            if let Some((t, _)) = transfer_event(client.service(5).unwrap()) {
+                assert!(client.cancel_transfer(t));
+                assert!(!client.accept_transfer(t, SharedSink::default()));
+            }
+        }
+
+        assert_eq!(
+            failed,
+            Some(TransferEvent::Failed(TransferError::Cancelled))
+        );
+        assert!(server.transfer_progress(transfer).is_none());
+    }
+
+    #[test]
+    fn test_incoming_limits() {
+        let mut server = create_server(1);
+        server.set_transfers(transfers());
+        let mut client = create_host(None, 1);
+        client.set_transfers(Transfers::new(1).with_max_incoming(1));
+        let (id, _) = connect_pair(&mut server, &mut client, 2);
+
+        let first = server.send_transfer(id, std::io::empty(), 10).unwrap();
+        let second = server.send_transfer(id, std::io::empty(), 20).unwrap();
+
+        // a second offer of the first transfer must not replace it
+        let mut message = header(MSG_OFFER, 0);
+        message.extend_from_slice(&30u64.to_be_bytes());
+        let packet = Packet::new(&message, PacketMode::ReliableSequenced).unwrap();
+        server.peer(id).unwrap().send_packet(packet, 1).unwrap();
+
+        let (mut offered, mut failed) = (Vec::new(), None);
+
+        for _ in 0..100 {
+            if let Some((t, event)) = transfer_event(server.service(5).unwrap()) {
+                failed = Some((t, event));
+            }
+
+            if let Some((_, event)) = transfer_event(client.service(5).unwrap()) {
+                offered.push(event);
+            }
+        }
+
+        assert_eq!(offered, [TransferEvent::Offered { size: 10 }]);
+        assert_eq!(
+            failed,
+            Some((second, TransferEvent::Failed(TransferError::Cancelled)))
+        );
+        assert!(server.transfer_progress(first).is_some());
+    }
+}